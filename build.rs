@@ -0,0 +1,16 @@
+use std::path::Path;
+
+/// Compiles the SQL grammar, vendored because the published `tree-sitter-sql` crate depends on
+/// another version of tree-sitter, see vendor/tree-sitter-sql/README.md
+fn main() {
+    let dir = Path::new("vendor/tree-sitter-sql");
+    let parser = dir.join("parser.c");
+    cc::Build::new()
+        .include(dir)
+        .file(&parser)
+        .flag_if_supported("-Wno-unused-parameter")
+        .flag_if_supported("-Wno-unused-but-set-variable")
+        .flag_if_supported("-Wno-trigraphs")
+        .compile("tree-sitter-sql");
+    println!("cargo:rerun-if-changed={}", parser.display());
+}
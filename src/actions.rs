@@ -0,0 +1,176 @@
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use tree_sitter::Node;
+
+use crate::code_cleaning::dedent;
+use crate::lang::{ProgItem, PythonProgItem};
+use crate::search::parse_code;
+
+/// Actions with a dedicated prompt and a structural check of the model's answer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BuiltinAction {
+    /// Convert synchronous Python functions to `async def` and await the I/O calls in their
+    /// bodies. Only the item itself is changed: callers in other places are NOT updated,
+    /// the list of converted functions is printed at the end so they can be fixed by hand
+    ToAsync,
+}
+
+/// Name and parameters of a Python function, used to compare the code before and after a change
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PythonFunctionSignature {
+    pub is_async: bool,
+    pub name: String,
+    pub parameters: String,
+}
+
+impl BuiltinAction {
+    pub fn task(&self) -> &'static str {
+        match self {
+            BuiltinAction::ToAsync => {
+                "convert this function to an `async def` function. Add `await` to the calls inside \
+                 the body that perform I/O and have async equivalents, replacing blocking calls \
+                 with their async counterparts where needed. Keep the function name and the \
+                 parameters exactly the same"
+            }
+        }
+    }
+
+    pub fn default_item(&self) -> ProgItem {
+        match self {
+            BuiltinAction::ToAsync => ProgItem::Python(PythonProgItem::Function),
+        }
+    }
+
+    pub fn supports(&self, item: &ProgItem) -> bool {
+        match self {
+            BuiltinAction::ToAsync => matches!(
+                item,
+                ProgItem::Python(PythonProgItem::Function | PythonProgItem::Method)
+            ),
+        }
+    }
+
+    /// Returns true when the item should be sent to the model at all
+    pub fn applies_to(&self, code: &str) -> bool {
+        match self {
+            BuiltinAction::ToAsync => python_function_signature(code)
+                .map(|signature| !signature.is_async)
+                .unwrap_or(false),
+        }
+    }
+
+    /// Checks that the new code is the expected structural change of the old code
+    pub fn validate(&self, old_code: &str, new_code: &str) -> Result<()> {
+        match self {
+            BuiltinAction::ToAsync => {
+                let before = python_function_signature(old_code)?;
+                let after = python_function_signature(new_code)?;
+                if !after.is_async {
+                    bail!("The function {} was not converted to async def", after.name);
+                }
+                if before.name != after.name {
+                    bail!(
+                        "The function was renamed from {} to {}",
+                        before.name,
+                        after.name
+                    );
+                }
+                if before.parameters != after.parameters {
+                    bail!(
+                        "The parameters of {} changed from {} to {}",
+                        before.name,
+                        before.parameters,
+                        after.parameters
+                    );
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+fn find_node<'a>(node: Node<'a>, kind: &str) -> Option<Node<'a>> {
+    if node.kind() == kind {
+        return Some(node);
+    }
+    let mut cursor = node.walk();
+    let children: Vec<Node<'a>> = node.named_children(&mut cursor).collect();
+    children
+        .into_iter()
+        .find_map(|child| find_node(child, kind))
+}
+
+/// Extracts the signature of the first function defined in the code
+pub fn python_function_signature(code: &str) -> Result<PythonFunctionSignature> {
+    let code = dedent(code);
+    let item = ProgItem::Python(PythonProgItem::Function);
+    let (_, tree) = parse_code(&code, &item)?;
+    let function = find_node(tree.root_node(), "function_definition")
+        .context("Cannot find a function definition")?;
+
+    let text = |node: Node| node.utf8_text(code.as_bytes()).map(|t| t.to_string());
+    let name = function
+        .child_by_field_name("name")
+        .context("Function without a name")?;
+    let parameters = function
+        .child_by_field_name("parameters")
+        .context("Function without parameters")?;
+    let is_async = function
+        .child(0)
+        .map(|first| first.kind() == "async")
+        .unwrap_or(false);
+
+    Ok(PythonFunctionSignature {
+        is_async,
+        name: text(name)?,
+        parameters: text(parameters)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_python_function_signature() {
+        let signature =
+            python_function_signature("    async def fetch(self, url):\n        pass\n").unwrap();
+        assert_eq!(
+            signature,
+            PythonFunctionSignature {
+                is_async: true,
+                name: "fetch".to_string(),
+                parameters: "(self, url)".to_string(),
+            }
+        );
+        assert!(
+            !python_function_signature("def fetch(url):\n    pass\n")
+                .unwrap()
+                .is_async
+        );
+    }
+
+    #[test]
+    fn test_to_async_validation() {
+        let old_code = "def fetch(url):\n    return requests.get(url)\n";
+        let action = BuiltinAction::ToAsync;
+
+        assert!(action.applies_to(old_code));
+        assert!(action
+            .validate(
+                old_code,
+                "async def fetch(url):\n    return await client.get(url)\n"
+            )
+            .is_ok());
+        // still synchronous
+        assert!(action.validate(old_code, old_code).is_err());
+        // renamed
+        assert!(action
+            .validate(old_code, "async def fetch_async(url):\n    pass\n")
+            .is_err());
+        // parameters changed
+        assert!(action
+            .validate(old_code, "async def fetch(url, session):\n    pass\n")
+            .is_err());
+    }
+}
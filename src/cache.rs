@@ -0,0 +1,229 @@
+use std::fs;
+use std::future::Future;
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use openai::chat::ChatCompletionMessage;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::llm::{Completion, CompletionOptions};
+
+/// Directory of the response cache, relative to the working directory
+pub const DEFAULT_CACHE_DIR: &str = ".mechatyper_cache";
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CachedAnswer {
+    content: String,
+}
+
+/// Model responses stored on disk, so that rerunning an interrupted run doesn't pay for the same
+/// completions again
+#[derive(Clone, Debug)]
+pub struct ResponseCache {
+    dir: PathBuf,
+}
+
+impl ResponseCache {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        ResponseCache { dir: dir.into() }
+    }
+
+    /// sha256 of the model, the messages, the sampling options and the definition of the item
+    /// they are about. The definition is hashed on its own so that an edited item is a cache miss
+    /// even when the prompt doesn't contain all of its code
+    pub fn key(
+        model: &str,
+        messages: &[ChatCompletionMessage],
+        options: &CompletionOptions,
+        definition: &str,
+    ) -> String {
+        let messages = serde_json::to_string(messages).unwrap_or_default();
+        let options = format!("{:?} {:?}", options.temperature, options.max_tokens);
+        let mut hasher = Sha256::new();
+        for part in [model, messages.as_str(), options.as_str(), definition] {
+            hasher.update(part.len().to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+
+    /// The cached answer, without token usage since a cache hit costs nothing
+    pub fn get(&self, key: &str) -> Option<Completion> {
+        let contents = fs::read_to_string(self.path(key)).ok()?;
+        let answer: CachedAnswer = serde_json::from_str(&contents).ok()?;
+        Some(Completion {
+            content: Some(answer.content),
+            usage: None,
+        })
+    }
+
+    pub fn put(&self, key: &str, content: &str) -> Result<()> {
+        fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Cannot create the cache directory {:?}", self.dir))?;
+        // keeps the cache out of `git status`
+        let gitignore = self.dir.join(".gitignore");
+        if !gitignore.exists() {
+            fs::write(gitignore, "*\n")?;
+        }
+
+        let answer = CachedAnswer {
+            content: content.to_string(),
+        };
+        fs::write(self.path(key), serde_json::to_string_pretty(&answer)?)?;
+        Ok(())
+    }
+
+    /// The cached response for `key`, otherwise the result of `request`. Only responses with an
+    /// answer are cached
+    pub async fn get_or_create(
+        &self,
+        key: &str,
+        request: impl Future<Output = Result<Completion>>,
+    ) -> Result<Completion> {
+        if let Some(completion) = self.get(key) {
+            return Ok(completion);
+        }
+        let completion = request.await?;
+        if let Some(content) = &completion.content {
+            if let Err(err) = self.put(key, content) {
+                warn!("Cannot cache the response: {:#}", err);
+            }
+        }
+        Ok(completion)
+    }
+
+    pub fn clear(&self) -> Result<()> {
+        if self.dir.exists() {
+            fs::remove_dir_all(&self.dir)
+                .with_context(|| format!("Cannot remove the cache directory {:?}", self.dir))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use openai::chat::ChatCompletionMessageRole;
+    use openai::Usage;
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn completion(content: &str) -> Completion {
+        Completion {
+            content: Some(content.to_string()),
+            usage: Some(Usage {
+                prompt_tokens: 10,
+                completion_tokens: 5,
+                total_tokens: 15,
+            }),
+        }
+    }
+
+    fn messages(prompt: &str) -> Vec<ChatCompletionMessage> {
+        vec![ChatCompletionMessage {
+            role: ChatCompletionMessageRole::User,
+            content: Some(prompt.to_string()),
+            name: None,
+            function_call: None,
+        }]
+    }
+
+    #[test]
+    fn test_cache_hit_skips_the_request() {
+        let dir = tempdir().unwrap();
+        let cache = ResponseCache::new(dir.path().join("cache"));
+        let requests = Cell::new(0);
+        let request = || async {
+            requests.set(requests.get() + 1);
+            Ok(completion("def a():\n    return 2"))
+        };
+        let options = CompletionOptions::default();
+        let key = ResponseCache::key(
+            "gpt-4o",
+            &messages("add docstrings"),
+            &options,
+            "def a(): ...",
+        );
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let first = rt.block_on(cache.get_or_create(&key, request())).unwrap();
+        let second = rt.block_on(cache.get_or_create(&key, request())).unwrap();
+        assert_eq!(requests.get(), 1);
+        assert_eq!(second.content, first.content);
+        assert!(second.usage.is_none());
+
+        cache.clear().unwrap();
+        rt.block_on(cache.get_or_create(&key, request())).unwrap();
+        assert_eq!(requests.get(), 2);
+    }
+
+    #[test]
+    fn test_edited_item_is_a_cache_miss() {
+        let dir = tempdir().unwrap();
+        let cache = ResponseCache::new(dir.path());
+        let requests = Cell::new(0);
+        let request = || async {
+            requests.set(requests.get() + 1);
+            Ok(completion("def a():\n    return 2"))
+        };
+        // same task and prompt, but the item was edited between the runs
+        let prompt = messages("add docstrings to the function");
+        let options = CompletionOptions::default();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let before = ResponseCache::key("gpt-4o", &prompt, &options, "def a():\n    return 1");
+        rt.block_on(cache.get_or_create(&before, request()))
+            .unwrap();
+        let after = ResponseCache::key("gpt-4o", &prompt, &options, "def a():\n    return 10");
+        rt.block_on(cache.get_or_create(&after, request())).unwrap();
+        assert_eq!(requests.get(), 2);
+
+        assert_ne!(
+            ResponseCache::key("gpt-4o", &prompt, &options, ""),
+            ResponseCache::key("gpt-4", &prompt, &options, "")
+        );
+    }
+
+    #[test]
+    fn test_options_are_part_of_the_key() {
+        let prompt = messages("add docstrings");
+        let key = |options: CompletionOptions| ResponseCache::key("gpt-4o", &prompt, &options, "");
+        let default = key(CompletionOptions::default());
+        let cold = key(CompletionOptions {
+            temperature: Some(0.0),
+            ..Default::default()
+        });
+        let short = key(CompletionOptions {
+            max_tokens: Some(100),
+            ..Default::default()
+        });
+        assert_ne!(default, cold);
+        assert_ne!(default, short);
+        assert_ne!(cold, short);
+        // streaming doesn't change the answer
+        let streamed = key(CompletionOptions {
+            stream: true,
+            ..Default::default()
+        });
+        assert_eq!(default, streamed);
+    }
+
+    #[test]
+    fn test_empty_response_is_not_cached() {
+        let dir = tempdir().unwrap();
+        let cache = ResponseCache::new(dir.path());
+        let empty = Completion::default();
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(cache.get_or_create("key", async { Ok(empty) }))
+            .unwrap();
+        assert!(cache.get("key").is_none());
+    }
+}
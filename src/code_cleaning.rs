@@ -1,35 +1,59 @@
+use anyhow::{bail, Result};
+
+fn leading_whitespace(line: &str) -> &str {
+    &line[..line.len() - line.trim_start().len()]
+}
+
+/// Indentation of the first non-blank line, and the smallest indentation of the non-blank lines
+/// after it. An item that follows other code on its first line starts without that line's
+/// indentation, so the first line says nothing about the body
+fn base_indentation(lines: &[&str]) -> (String, Option<String>) {
+    let mut non_blank = lines.iter().filter(|line| !line.trim().is_empty());
+    let first = non_blank.next().map(|line| leading_whitespace(line));
+    let body = non_blank
+        .map(|line| leading_whitespace(line))
+        .min_by_key(|indentation| indentation.chars().count());
+    (
+        first.unwrap_or_default().to_string(),
+        body.map(str::to_string),
+    )
+}
+
+/// Re-indents `new_code` to the nesting of `old_code`. The first line gets the indentation of
+/// the old first line, and the other lines are shifted by the difference between the old and
+/// new body indentation, so the structure of the new code is kept whatever its indent width
 pub fn apply_indentation(old_code: &str, new_code: &str) -> String {
     let old_code_lines: Vec<&str> = old_code.lines().collect();
     let new_code_lines: Vec<&str> = new_code.lines().collect();
 
-    // Detect the indentation in old code
-    let old_indentation = old_code_lines
-        .iter()
-        .filter(|line| !line.trim().is_empty())
-        .map(|line| {
-            line.chars()
-                .take_while(|c| c.is_whitespace())
-                .collect::<String>()
-        })
-        .next()
-        .unwrap_or_default();
+    let (old_first, old_body) = base_indentation(&old_code_lines);
+    let (new_first, new_body) = base_indentation(&new_code_lines);
+    // a one-line item has no body to align with, keep the body relative to the first line
+    let (old_body, new_body) = match (old_body, new_body) {
+        (Some(old_body), Some(new_body)) => (old_body, new_body),
+        _ => (old_first.clone(), new_first.clone()),
+    };
 
-    // Detect the number of leading whitespace characters in new code
-    let new_indentation_count = new_code_lines
-        .iter()
-        .filter(|line| !line.trim().is_empty())
-        .map(|line| line.chars().take_while(|c| c.is_whitespace()).count())
-        .min()
-        .unwrap_or(0);
+    let reindent = |line: &str, old_base: &str, new_base: &str| {
+        let indentation = leading_whitespace(line);
+        let kept = indentation
+            .char_indices()
+            .nth(new_base.chars().count())
+            .map_or("", |(index, _)| &indentation[index..]);
+        format!("{}{}{}", old_base, kept, line.trim_start())
+    };
 
-    // Apply indentation to new code
+    let mut seen_first = false;
     let indented_code = new_code_lines
         .into_iter()
         .map(|line| {
             if line.trim().is_empty() {
                 line.to_string()
+            } else if !seen_first {
+                seen_first = true;
+                reindent(line, &old_first, &new_first)
             } else {
-                old_indentation.clone() + &line[new_indentation_count..]
+                reindent(line, &old_body, &new_body)
             }
         })
         .collect::<Vec<String>>()
@@ -43,30 +67,282 @@ pub fn apply_indentation(old_code: &str, new_code: &str) -> String {
     }
 }
 
-pub fn extract_python_code(input: &str) -> Option<String> {
-    let mut lines = input.lines();
-    let mut python_code = String::new();
-    let mut in_python_code_block = false;
+/// How the lines of a piece of code are indented
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum IndentationStyle {
+    Tabs,
+    Spaces(usize),
+}
 
-    while let Some(line) = lines.next() {
-        if line.trim_start().starts_with("```python") {
-            in_python_code_block = true;
-        } else if line.trim_start().starts_with("```") {
-            if in_python_code_block {
-                // Reached the end of the Python code block
-                break;
+fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 {
+        a
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+/// Tabs if any line is indented with a tab, otherwise the greatest common divisor of the space
+/// indentations. None when no line is indented
+fn indentation_style(code: &str) -> Option<IndentationStyle> {
+    let indentations: Vec<&str> = code
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(leading_whitespace)
+        .filter(|indentation| !indentation.is_empty())
+        .collect();
+    if indentations
+        .iter()
+        .any(|indentation| indentation.contains('\t'))
+    {
+        return Some(IndentationStyle::Tabs);
+    }
+    let width = indentations
+        .iter()
+        .map(|indentation| indentation.len())
+        .fold(0, gcd);
+    (width > 0).then_some(IndentationStyle::Spaces(width))
+}
+
+/// Converts the leading whitespace of `new_code` to the indentation style (tabs or spaces) of
+/// `old_code`, mixing them makes Python fail with a `TabError`
+pub fn normalize_indentation_style(old_code: &str, new_code: &str) -> String {
+    let (Some(old_style), Some(new_style)) =
+        (indentation_style(old_code), indentation_style(new_code))
+    else {
+        return new_code.to_string();
+    };
+    let convert = |line: &str| -> String {
+        let indentation = leading_whitespace(line);
+        let rest = &line[indentation.len()..];
+        match (old_style, new_style) {
+            (IndentationStyle::Tabs, IndentationStyle::Spaces(width)) => {
+                let columns: usize = indentation
+                    .chars()
+                    .map(|c| if c == '\t' { width } else { 1 })
+                    .sum();
+                format!(
+                    "{}{}{}",
+                    "\t".repeat(columns / width),
+                    " ".repeat(columns % width),
+                    rest
+                )
             }
-        } else if in_python_code_block {
-            python_code.push_str(line);
-            python_code.push('\n');
+            (IndentationStyle::Spaces(width), IndentationStyle::Tabs) => {
+                format!("{}{}", indentation.replace('\t', &" ".repeat(width)), rest)
+            }
+            _ => line.to_string(),
         }
+    };
+
+    let mut normalized = new_code
+        .lines()
+        .map(convert)
+        .collect::<Vec<String>>()
+        .join("\n");
+    if new_code.ends_with('\n') {
+        normalized.push('\n');
     }
+    normalized
+}
+
+/// Removes the common leading whitespace of all non-blank lines
+pub fn dedent(code: &str) -> String {
+    let indentation = code
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.chars().take_while(|c| c.is_whitespace()).count())
+        .min()
+        .unwrap_or(0);
 
-    if !python_code.is_empty() {
-        Some(python_code)
+    code.lines()
+        .map(|line| line.chars().skip(indentation).collect::<String>())
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Removes the `///` or `//!` doc comment marker (and the space after it) from every line
+pub fn strip_doc_comment(code: &str) -> String {
+    code.lines()
+        .map(|line| {
+            let line = line.trim_start();
+            let line = line
+                .strip_prefix("///")
+                .or_else(|| line.strip_prefix("//!"))
+                .unwrap_or(line);
+            line.strip_prefix(' ').unwrap_or(line)
+        })
+        .collect::<Vec<&str>>()
+        .join("\n")
+}
+
+/// Turns code into doc comment lines, using the indentation and marker of the first line of
+/// `template`
+pub fn to_doc_comment(code: &str, template: &str) -> String {
+    let first_line = template.lines().next().unwrap_or_default();
+    let indentation_length = first_line.len() - first_line.trim_start().len();
+    let marker = if first_line.trim_start().starts_with("//!") {
+        "//!"
     } else {
-        None
+        "///"
+    };
+    let prefix = format!("{}{}", &first_line[..indentation_length], marker);
+
+    code.lines()
+        .map(|line| {
+            if line.trim().is_empty() {
+                prefix.clone()
+            } else {
+                format!("{} {}", prefix, line)
+            }
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// `# type:` comments of Python code as (code before the comment, comment) pairs
+pub fn type_comments(code: &str) -> Vec<(String, String)> {
+    code.lines()
+        .filter_map(|line| {
+            let index = line.find("# type:")?;
+            Some((
+                line[..index].trim().to_string(),
+                line[index..].trim().to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Puts back the `# type:` comments of `old_code` the model dropped from `new_code`. An inline
+/// comment is reattached to the unchanged line it was on, a comment on its own line is inserted
+/// after the unchanged line preceding it. Fails with the comments that cannot be placed
+pub fn preserve_type_comments(old_code: &str, new_code: &str) -> Result<String> {
+    let old_lines: Vec<&str> = old_code.lines().collect();
+    let mut new_lines: Vec<String> = new_code.lines().map(str::to_string).collect();
+    let mut lost = vec![];
+
+    for (index, old_line) in old_lines.iter().enumerate() {
+        let Some(comment_index) = old_line.find("# type:") else {
+            continue;
+        };
+        let comment = old_line[comment_index..].trim();
+        if new_lines.iter().any(|line| line.contains(comment)) {
+            continue;
+        }
+
+        let code = old_line[..comment_index].trim();
+        if !code.is_empty() {
+            match new_lines
+                .iter_mut()
+                .find(|line| line.trim() == code && !line.contains('#'))
+            {
+                Some(line) => {
+                    line.push_str("  ");
+                    line.push_str(comment);
+                }
+                None => lost.push(comment.to_string()),
+            }
+            continue;
+        }
+
+        let anchor = old_lines[..index]
+            .iter()
+            .rev()
+            .map(|line| line.trim())
+            .find(|line| !line.is_empty());
+        match anchor.and_then(|anchor| new_lines.iter().position(|line| line.trim() == anchor)) {
+            Some(position) => new_lines.insert(
+                position + 1,
+                format!("{}{}", &old_line[..comment_index], comment),
+            ),
+            None => lost.push(comment.to_string()),
+        }
+    }
+
+    if !lost.is_empty() {
+        bail!("The type comments {:?} were dropped", lost);
+    }
+    let mut code = new_lines.join("\n");
+    if new_code.ends_with('\n') {
+        code.push('\n');
+    }
+    Ok(code)
+}
+
+/// The JSON object in a model's answer. Some models wrap it in a code block or add a sentence
+/// before or after it despite being asked not to
+pub fn extract_json_object(answer: &str) -> &str {
+    let answer = answer.trim();
+    match (answer.find('{'), answer.rfind('}')) {
+        (Some(start), Some(end)) if start < end => &answer[start..=end],
+        _ => answer,
+    }
+}
+
+/// The language tag and the code of every fenced (```` ``` ````) block, in order. A block left
+/// open runs to the end of the input
+fn fenced_blocks(input: &str) -> Vec<(&str, String)> {
+    let mut blocks = vec![];
+    let mut current: Option<(&str, String)> = None;
+    for line in input.lines() {
+        match (line.trim_start().strip_prefix("```"), current.take()) {
+            (Some(tag), None) => current = Some((tag.trim(), String::new())),
+            (Some(_), Some(block)) => blocks.push(block),
+            (None, Some((tag, mut code))) => {
+                code.push_str(line);
+                code.push('\n');
+                current = Some((tag, code));
+            }
+            (None, None) => {}
+        }
+    }
+    blocks.extend(current);
+    blocks
+}
+
+/// The code of the first fenced block in a model's answer. With `lang_hint` only a block tagged
+/// with that language (in any case) or an untagged one is taken
+pub fn extract_fenced_code(input: &str, lang_hint: Option<&str>) -> Option<String> {
+    fenced_blocks(input)
+        .into_iter()
+        .find(|(tag, _)| match lang_hint {
+            Some(hint) => tag.is_empty() || tag.eq_ignore_ascii_case(hint),
+            None => true,
+        })
+        .map(|(_, code)| code)
+        .filter(|code| !code.is_empty())
+}
+
+/// The code of all the fenced blocks in a model's answer, in order
+pub fn extract_all_fenced_code(input: &str) -> Vec<String> {
+    fenced_blocks(input)
+        .into_iter()
+        .map(|(_, code)| code)
+        .filter(|code| !code.is_empty())
+        .collect()
+}
+
+/// The code in a model's answer wrapped in code blocks: the first block in the `lang_hint`
+/// language (or any block), or all the blocks joined in order when only the joined code is
+/// valid, e.g. the imports and the function split into two blocks. `None` without any block
+pub fn extract_answer_code(
+    answer: &str,
+    lang_hint: &str,
+    is_valid: impl Fn(&str) -> bool,
+) -> Option<String> {
+    let single = extract_fenced_code(answer, Some(lang_hint))
+        .or_else(|| extract_fenced_code(answer, None))?;
+    if is_valid(&single) {
+        return Some(single);
     }
+    let blocks = extract_all_fenced_code(answer);
+    if blocks.len() > 1 {
+        let joined = blocks.join("\n");
+        if is_valid(&joined) {
+            return Some(joined);
+        }
+    }
+    Some(single)
 }
 
 #[cfg(test)]
@@ -97,9 +373,115 @@ mod tests {
         assert_eq!(indented_new_code, expected_indented_new_code);
     }
 
+    #[test]
+    fn test_apply_indentation_shifts_nested_blocks() {
+        let old_code = "    def old_function(items):\n        for item in items:\n            if item:\n                print(item)\n\n        return items\n";
+        // two space indents, the inner blocks have to move by the same amount as the body
+        let new_code = "def new_function(items):\n  for item in items:\n    if item:\n      print(item)\n\n  return None\n";
+
+        assert_eq!(
+            apply_indentation(old_code, new_code),
+            "    def new_function(items):\n        for item in items:\n          if item:\n            print(item)\n\n        return None\n"
+        );
+
+        // an item after other code on its first line starts without the indentation of that line
+        let old_code =
+            "def method(self):\n        if self.a:\n            return 1\n        return 2";
+        let new_code = "def method(self):\n    if self.a:\n        return 10\n    return 20\n";
+        assert_eq!(
+            apply_indentation(old_code, new_code),
+            "def method(self):\n        if self.a:\n            return 10\n        return 20\n"
+        );
+
+        // the new code may come back more indented than the old one
+        let old_code = "fn a() {\n    1\n}";
+        let new_code = "        fn a() {\n            2\n        }";
+        assert_eq!(
+            apply_indentation(old_code, new_code),
+            "fn a() {\n    2\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_normalize_indentation_style() {
+        // space indented answer for a tab indented file
+        let old_code = "def a():\n\tif x:\n\t\treturn 1\n";
+        let new_code = "def a():\n    if x:\n        return 2\n";
+        assert_eq!(
+            normalize_indentation_style(old_code, new_code),
+            "def a():\n\tif x:\n\t\treturn 2\n"
+        );
+
+        // tab indented answer for a file indented with 2 spaces
+        let old_code = "def a():\n  if x:\n    return 1";
+        let new_code = "def a():\n\tif x:\n\t\treturn 2";
+        assert_eq!(
+            normalize_indentation_style(old_code, new_code),
+            "def a():\n  if x:\n    return 2"
+        );
+
+        // already matching
+        let new_code = "def a():\n    if x:\n        return 2\n";
+        assert_eq!(
+            normalize_indentation_style("def a():\n    return 1\n", new_code),
+            new_code
+        );
+        assert_eq!(normalize_indentation_style("x = 1", new_code), new_code);
+
+        // normalized before the indentation is applied
+        let old_code = "\tdef a(self):\n\t\treturn 1\n";
+        let new_code = "def a(self):\n    return 2\n";
+        assert_eq!(
+            apply_indentation(old_code, &normalize_indentation_style(old_code, new_code)),
+            "\tdef a(self):\n\t\treturn 2\n"
+        );
+    }
+
+    #[test]
+    fn test_doc_comment_round_trip() {
+        let doc_code = "    /// let x = 1;\n    ///\n    /// assert_eq!(x, 1);";
+        let code = strip_doc_comment(doc_code);
+        assert_eq!(code, "let x = 1;\n\nassert_eq!(x, 1);");
+        assert_eq!(to_doc_comment(&code, doc_code), doc_code);
+        assert_eq!(
+            to_doc_comment("let y = 2;", "//! let x = 1;"),
+            "//! let y = 2;"
+        );
+    }
+
+    #[test]
+    fn test_preserve_type_comments() {
+        let old_code = "def send(to, body):\n    # type: (str, str) -> bool\n    retries = 3  # type: int\n    return deliver(to, body, retries)\n";
+        assert_eq!(
+            type_comments(old_code),
+            vec![
+                ("".to_string(), "# type: (str, str) -> bool".to_string()),
+                ("retries = 3".to_string(), "# type: int".to_string()),
+            ]
+        );
+
+        // kept by the model
+        assert_eq!(
+            preserve_type_comments(old_code, old_code).unwrap(),
+            old_code
+        );
+        // dropped by the model and reattached
+        let new_code = "def send(to, body):\n    \"\"\"Sends a message.\"\"\"\n    retries = 3\n    return deliver(to, body, retries)\n";
+        assert_eq!(
+            preserve_type_comments(old_code, new_code).unwrap(),
+            "def send(to, body):\n    # type: (str, str) -> bool\n    \"\"\"Sends a message.\"\"\"\n    retries = 3  # type: int\n    return deliver(to, body, retries)\n"
+        );
+        // the annotated line changed
+        assert!(preserve_type_comments(
+            old_code,
+            "def send(to, body):\n    # type: (str, str) -> bool\n    retries = 5\n"
+        )
+        .is_err());
+    }
+
     #[test]
     fn test_extract_python_code() {
-        let code = extract_python_code(
+        let code = extract_fenced_code(
             r#"
 Sure! Here's a Python function that takes a list and returns the sum of its elements:
 
@@ -109,7 +491,89 @@ return sum(lst)
 ```
 
 You can use this function by passing your list as an argument to the function `calculate_sum()`. The `sum()` function takes an iterable (like a list) as input and returns the sum of its elements."#,
+            Some("python"),
+        );
+        assert_eq!(
+            code.as_deref(),
+            Some("def calculate_sum(lst):\nreturn sum(lst)\n")
+        );
+    }
+
+    #[test]
+    fn test_extract_fenced_code() {
+        let rust = "Here is the function:\n\n```rust\nfn a() -> u8 {\n    1\n}\n```\n";
+        assert_eq!(
+            extract_fenced_code(rust, Some("rust")).as_deref(),
+            Some("fn a() -> u8 {\n    1\n}\n")
+        );
+        assert_eq!(
+            extract_fenced_code(rust, None),
+            extract_fenced_code(rust, Some("Rust"))
+        );
+        assert_eq!(extract_fenced_code(rust, Some("python")), None);
+
+        let plain = "```\nfn a() {}\n```";
+        assert_eq!(
+            extract_fenced_code(plain, Some("rust")).as_deref(),
+            Some("fn a() {}\n")
+        );
+
+        // a usage example in another language comes first
+        let mixed = "```sh\ncargo run\n```\n```rust\nfn main() {}\n```";
+        assert_eq!(
+            extract_fenced_code(mixed, Some("rust")).as_deref(),
+            Some("fn main() {}\n")
+        );
+
+        assert_eq!(extract_fenced_code("fn a() {}", Some("rust")), None);
+        assert_eq!(extract_fenced_code("```rust\n```", None), None);
+    }
+
+    #[test]
+    fn test_extract_answer_code_joins_blocks() {
+        use crate::lang::{ProgItem, PythonProgItem};
+        use crate::search::parse_code;
+
+        let is_valid = |code: &str| {
+            parse_code(code, &ProgItem::Python(PythonProgItem::Function))
+                .map_or(false, |(_, tree)| !tree.root_node().has_error())
+        };
+        let answer = "Add the decorator:\n\n```python\n@lru_cache(maxsize=None)\n```\n\nand then the function:\n\n```python\ndef fib(n):\n    return n if n < 2 else fib(n - 1) + fib(n - 2)\n```\n";
+        assert_eq!(extract_all_fenced_code(answer).len(), 2);
+        assert!(!is_valid(
+            &extract_fenced_code(answer, Some("python")).unwrap()
+        ));
+
+        let code = extract_answer_code(answer, "python", is_valid).unwrap();
+        assert!(code.starts_with("@lru_cache(maxsize=None)\n"));
+        assert!(code.contains("def fib(n):"));
+        assert!(is_valid(&code));
+
+        // a valid first block is used as it is
+        let two_functions =
+            "```python\ndef a():\n    pass\n```\n```python\ndef b():\n    pass\n```";
+        assert_eq!(
+            extract_answer_code(two_functions, "python", is_valid).as_deref(),
+            Some("def a():\n    pass\n")
+        );
+        assert_eq!(
+            extract_answer_code("def a(): pass", "python", is_valid),
+            None
+        );
+    }
+
+    #[test]
+    fn test_extract_json_object() {
+        let json = r#"{"item": {"Python": "Function"}}"#;
+        assert_eq!(extract_json_object(json), json);
+        assert_eq!(
+            extract_json_object(&format!("```json\n{}\n```", json)),
+            json
+        );
+        assert_eq!(
+            extract_json_object(&format!("Here are the instructions:\n{}", json)),
+            json
         );
-        println!("{:?}", code);
+        assert_eq!(extract_json_object(" Quit "), "Quit");
     }
 }
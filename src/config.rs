@@ -0,0 +1,166 @@
+use std::fs;
+use std::num::NonZeroUsize;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+
+use crate::llm::Backend;
+use crate::Cli;
+
+/// Name of the configuration file, looked up in the root of the git repository
+pub const CONFIG_FILE: &str = ".mechatyper.toml";
+
+/// Defaults for the command line options, read from `.mechatyper.toml`. Options given on the
+/// command line win over the file, which wins over the built-in defaults
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub model: Option<String>,
+    pub backend: Option<Backend>,
+    pub concurrency: Option<NonZeroUsize>,
+    /// `false` is `--no-idempotency-keys`
+    pub idempotency_keys: Option<bool>,
+    /// directories skipped in addition to the ones of the language, e.g. `generated`
+    pub exclude_dirs: Vec<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    /// house rules added to every prompt, like `--prompt-extra`
+    pub system_prompt_suffix: Option<String>,
+}
+
+/// Reads `.mechatyper.toml` from `repo_root`, the default configuration when there is none
+pub fn load_config(repo_root: &Path) -> Result<Config> {
+    let path = repo_root.join(CONFIG_FILE);
+    if !path.exists() {
+        return Ok(Config::default());
+    }
+    let contents = fs::read_to_string(&path)?;
+    toml::from_str(&contents).with_context(|| format!("Invalid configuration file {:?}", path))
+}
+
+impl Config {
+    /// Sets the options of `cli` that were not given on the command line
+    pub fn apply(self, cli: &mut Cli, matches: &ArgMatches) {
+        let from_command_line =
+            |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+        if let Some(model) = self.model.filter(|_| !from_command_line("model")) {
+            cli.model = model;
+        }
+        if let Some(backend) = self.backend.filter(|_| !from_command_line("backend")) {
+            cli.backend = backend;
+        }
+        if let Some(concurrency) = self
+            .concurrency
+            .filter(|_| !from_command_line("concurrency"))
+        {
+            cli.concurrency = concurrency;
+        }
+        if self.idempotency_keys == Some(false) {
+            cli.no_idempotency_keys = true;
+        }
+        if !from_command_line("include") && !self.include.is_empty() {
+            cli.include = self.include;
+        }
+        if !from_command_line("exclude") && !self.exclude.is_empty() {
+            cli.exclude = self.exclude;
+        }
+        cli.exclude_dirs.extend(self.exclude_dirs);
+        if cli.prompt_extra.is_none() {
+            cli.prompt_extra = self.system_prompt_suffix;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::{CommandFactory, FromArgMatches};
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_load_config_and_merge() {
+        let dir = tempdir().unwrap();
+        assert_eq!(load_config(dir.path()).unwrap(), Config::default());
+
+        fs::write(
+            dir.path().join(CONFIG_FILE),
+            r#"
+model = "gpt-4o-mini"
+backend = "ollama"
+concurrency = 8
+idempotency_keys = false
+exclude_dirs = ["generated"]
+include = ["src/**"]
+system_prompt_suffix = "Keep the comments."
+"#,
+        )
+        .unwrap();
+        let config = load_config(dir.path()).unwrap();
+        assert_eq!(config.backend, Some(Backend::Ollama));
+
+        let parse = |args: &[&str]| {
+            let matches = Cli::command().try_get_matches_from(args).unwrap();
+            let mut cli = Cli::from_arg_matches(&matches).unwrap();
+            config.clone().apply(&mut cli, &matches);
+            cli
+        };
+
+        // the file wins over the built-in defaults
+        let cli = parse(&["mechatyper"]);
+        assert_eq!(cli.model, "gpt-4o-mini");
+        assert_eq!(cli.backend, Backend::Ollama);
+        assert_eq!(cli.concurrency.get(), 8);
+        assert!(cli.no_idempotency_keys);
+        assert_eq!(cli.include, vec!["src/**"]);
+        assert_eq!(cli.exclude_dirs, vec!["generated"]);
+        assert_eq!(cli.prompt_extra.as_deref(), Some("Keep the comments."));
+
+        // the command line wins over the file
+        let cli = parse(&[
+            "mechatyper",
+            "--model",
+            "gpt-4o",
+            "--concurrency",
+            "2",
+            "--include",
+            "lib/**",
+            "--prompt-extra",
+            "Keep the docstrings.",
+        ]);
+        assert_eq!(cli.model, "gpt-4o");
+        assert_eq!(cli.backend, Backend::Ollama);
+        assert_eq!(cli.concurrency.get(), 2);
+        assert_eq!(cli.include, vec!["lib/**"]);
+        assert_eq!(cli.prompt_extra.as_deref(), Some("Keep the docstrings."));
+
+        // looked up from the folder of the subcommand
+        let folder = |args: &str| {
+            let args: Vec<&str> = args.split_whitespace().collect();
+            parse(&args).target_folder()
+        };
+        assert_eq!(folder("mechatyper"), Path::new("."));
+        assert_eq!(
+            folder("mechatyper --folder api --folder web"),
+            Path::new("api")
+        );
+        assert_eq!(
+            folder("mechatyper run --task t --item Python.Function --folder api"),
+            Path::new("api")
+        );
+        assert_eq!(
+            folder("mechatyper estimate --task t --item Python.Function"),
+            Path::new(".")
+        );
+        assert_eq!(
+            folder("mechatyper list --item Python.Function --folder web"),
+            Path::new("web")
+        );
+
+        fs::write(dir.path().join(CONFIG_FILE), "modle = \"gpt-4o\"\n").unwrap();
+        assert!(load_config(dir.path()).is_err());
+    }
+}
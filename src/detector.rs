@@ -0,0 +1,159 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+
+use crate::lang::{ProgItem, PythonProgItem};
+use crate::search::parse_code;
+
+/// A Python `detect(code: str) -> bool` predicate deciding if an item is worth sending to the model
+#[derive(Clone, Debug)]
+pub struct Detector {
+    pub source: String,
+}
+
+impl Detector {
+    /// Validates that the source defines a top level `detect` function with a single parameter
+    pub fn from_source(source: &str) -> Result<Self> {
+        let (_, tree) = parse_code(source, &ProgItem::Python(PythonProgItem::Function))?;
+        let root = tree.root_node();
+        if root.has_error() {
+            bail!("The detector is not valid Python code");
+        }
+
+        let mut cursor = root.walk();
+        let detect = root
+            .named_children(&mut cursor)
+            .filter(|node| node.kind() == "function_definition")
+            .find(|node| {
+                node.child_by_field_name("name")
+                    .and_then(|name| name.utf8_text(source.as_bytes()).ok())
+                    == Some("detect")
+            })
+            .context("The detector must define a top level `detect(code: str) -> bool` function")?;
+
+        let parameter_count = detect
+            .child_by_field_name("parameters")
+            .map(|parameters| parameters.named_child_count())
+            .unwrap_or(0);
+        if parameter_count != 1 {
+            bail!(
+                "`detect` must take exactly one parameter (code: str), it takes {}",
+                parameter_count
+            );
+        }
+
+        if let Some(return_type) = detect.child_by_field_name("return_type") {
+            let return_type = return_type.utf8_text(source.as_bytes())?;
+            if return_type != "bool" {
+                bail!("`detect` must return bool, it returns {}", return_type);
+            }
+        }
+
+        Ok(Detector {
+            source: source.to_string(),
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read the detector from {:?}", path))?;
+        Self::from_source(&source).with_context(|| format!("Invalid detector in {:?}", path))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        fs::write(path, &self.source)
+            .with_context(|| format!("Cannot write the detector to {:?}", path))
+    }
+
+    /// Runs `detect(code)` with the `python3` interpreter
+    pub fn detect(&self, code: &str) -> Result<bool> {
+        let script = format!(
+            "{}\n\nimport sys\nprint(bool(detect(sys.stdin.read())))\n",
+            self.source
+        );
+        let mut child = Command::new("python3")
+            .arg("-c")
+            .arg(script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Cannot run python3 to evaluate the detector")?;
+        child
+            .stdin
+            .take()
+            .context("Cannot write to python3")?
+            .write_all(code.as_bytes())?;
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            bail!(
+                "The detector failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        match String::from_utf8_lossy(&output.stdout).trim() {
+            "True" => Ok(true),
+            "False" => Ok(false),
+            other => bail!("Unexpected detector output: {}", other),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    const UNWRAP_DETECTOR: &str = "def detect(code: str) -> bool:\n    return \"unwrap\" in code\n";
+
+    #[test]
+    fn test_detector_validation() {
+        assert!(Detector::from_source(UNWRAP_DETECTOR).is_ok());
+        assert!(Detector::from_source("def detect(code):\n    return True\n").is_ok());
+        assert!(Detector::from_source("def check(code: str) -> bool:\n    return True\n").is_err());
+        assert!(Detector::from_source("def detect(a, b) -> bool:\n    return True\n").is_err());
+        assert!(Detector::from_source("def detect(code: str) -> str:\n    return ''\n").is_err());
+        assert!(Detector::from_source("def detect(code: str) -> bool\n    return True\n").is_err());
+    }
+
+    #[test]
+    fn test_detector_save_load_and_detect() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("detector.py");
+        Detector::from_source(UNWRAP_DETECTOR)
+            .unwrap()
+            .save(&path)
+            .unwrap();
+
+        let detector = Detector::load(&path).unwrap();
+        assert!(detector.detect("fn a() { b.unwrap(); }").unwrap());
+        assert!(!detector.detect("fn a() { b?; }").unwrap());
+    }
+
+    #[test]
+    fn test_detector_prefilters_snippets() {
+        // the kind of detector the model writes for "remove unwrap from functions"
+        let detector = Detector::from_source(
+            "import re\n\ndef detect(code: str) -> bool:\n    \"\"\"Calls to unwrap\"\"\"\n    return re.search(r\"\\.unwrap\\(\\)\", code) is not None\n",
+        )
+        .unwrap();
+        for snippet in [
+            "fn a() -> u32 {\n    \"1\".parse().unwrap()\n}",
+            "fn b(x: Option<&str>) -> &str {\n    // ünïcode\n    x.unwrap()\n}",
+        ] {
+            assert!(detector.detect(snippet).unwrap(), "{}", snippet);
+        }
+        for snippet in [
+            "fn c() -> Result<u32> {\n    Ok(\"1\".parse()?)\n}",
+            "fn unwrap_all() {}",
+            "",
+        ] {
+            assert!(!detector.detect(snippet).unwrap(), "{}", snippet);
+        }
+    }
+}
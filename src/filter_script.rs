@@ -0,0 +1,151 @@
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+
+use crate::lang::{ProgItem, PythonProgItem};
+use crate::search::{parse_code, ItemDef};
+
+/// A Python `include(item: dict) -> bool` function choosing the items to transform
+#[derive(Clone, Debug)]
+pub struct FilterScript {
+    pub source: String,
+}
+
+/// What the filter script receives for every item
+#[derive(Clone, Debug, Serialize)]
+struct FilterInput<'a> {
+    definition: &'a str,
+    name: Option<&'a str>,
+    filename: &'a PathBuf,
+    start_line: usize,
+    end_line: usize,
+    complexity: usize,
+}
+
+impl FilterScript {
+    pub fn from_source(source: &str) -> Result<Self> {
+        let (_, tree) = parse_code(source, &ProgItem::Python(PythonProgItem::Function))?;
+        let root = tree.root_node();
+        if root.has_error() {
+            bail!("The filter script is not valid Python code");
+        }
+
+        let mut cursor = root.walk();
+        let has_include = root
+            .named_children(&mut cursor)
+            .filter(|node| node.kind() == "function_definition")
+            .any(|node| {
+                node.child_by_field_name("name")
+                    .and_then(|name| name.utf8_text(source.as_bytes()).ok())
+                    == Some("include")
+            });
+        if !has_include {
+            bail!(
+                "The filter script must define a top level `include(item: dict) -> bool` function"
+            );
+        }
+
+        Ok(FilterScript {
+            source: source.to_string(),
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read the filter script {:?}", path))?;
+        Self::from_source(&source).with_context(|| format!("Invalid filter script {:?}", path))
+    }
+
+    /// Keeps the items for which `include` returns True. All items are evaluated by a single
+    /// `python3` process
+    pub fn filter(&self, items: Vec<ItemDef>) -> Result<Vec<ItemDef>> {
+        if items.is_empty() {
+            return Ok(items);
+        }
+        let input: Vec<FilterInput> = items
+            .iter()
+            .map(|item| FilterInput {
+                definition: &item.definition,
+                name: item.name.as_deref(),
+                filename: &item.filename,
+                start_line: item.start_pos + 1,
+                end_line: item.end_pos + 1,
+                complexity: item.complexity,
+            })
+            .collect();
+
+        let script = format!(
+            "{}\n\nimport json, sys\nprint(json.dumps([bool(include(item)) for item in json.load(sys.stdin)]))\n",
+            self.source
+        );
+        let mut child = Command::new("python3")
+            .arg("-c")
+            .arg(script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Cannot run python3 to evaluate the filter script")?;
+        child
+            .stdin
+            .take()
+            .context("Cannot write to python3")?
+            .write_all(serde_json::to_string(&input)?.as_bytes())?;
+
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            bail!(
+                "The filter script failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let included: Vec<bool> =
+            serde_json::from_slice(&output.stdout).context("Unexpected filter script output")?;
+        if included.len() != items.len() {
+            bail!(
+                "The filter script returned {} results for {} items",
+                included.len(),
+                items.len()
+            );
+        }
+
+        Ok(items
+            .into_iter()
+            .zip(included)
+            .filter_map(|(item, include)| include.then_some(item))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_script() {
+        assert!(FilterScript::from_source("def detect(code):\n    return True\n").is_err());
+
+        let script = FilterScript::from_source(
+            "def include(item):\n    return item['complexity'] > 1 and 'test' not in item['filename']\n",
+        )
+        .unwrap();
+        let item = |filename: &str, complexity| ItemDef {
+            filename: PathBuf::from(filename),
+            complexity,
+            ..Default::default()
+        };
+        let items = script
+            .filter(vec![
+                item("src/a.py", 3),
+                item("src/b.py", 1),
+                item("tests/test_a.py", 3),
+            ])
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].filename, PathBuf::from("src/a.py"));
+    }
+}
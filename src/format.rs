@@ -0,0 +1,115 @@
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::process::Command;
+
+use anyhow::{bail, Result};
+use tracing::warn;
+
+/// An external formatter run on every changed file with `--format`
+#[derive(Clone, Debug)]
+pub struct Formatter {
+    program: String,
+    args: Vec<String>,
+}
+
+/// A file the formatter failed on, with its error output
+#[derive(Clone, Debug, PartialEq)]
+pub struct FormatFailure {
+    pub file: PathBuf,
+    pub message: String,
+}
+
+impl Formatter {
+    /// `command` is the program followed by its arguments, e.g. `black --quiet`
+    pub fn new(command: &str) -> Result<Self> {
+        let mut parts = command.split_whitespace().map(str::to_string);
+        let Some(program) = parts.next() else {
+            bail!("The formatter command is empty");
+        };
+        Ok(Formatter {
+            program,
+            args: parts.collect(),
+        })
+    }
+
+    /// Runs the formatter on each file. A formatter that is not installed is skipped with a
+    /// warning, other failures are returned per file
+    pub fn format_files(&self, files: &[PathBuf]) -> Vec<FormatFailure> {
+        let mut failures = vec![];
+        for file in files {
+            let output = Command::new(&self.program)
+                .args(&self.args)
+                .arg(file)
+                .output();
+            match output {
+                Ok(output) if output.status.success() => {}
+                Ok(output) => failures.push(FormatFailure {
+                    file: file.clone(),
+                    message: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+                }),
+                Err(err) if err.kind() == ErrorKind::NotFound => {
+                    warn!(
+                        "{} is not installed, the files are not formatted",
+                        self.program
+                    );
+                    return vec![];
+                }
+                Err(err) => failures.push(FormatFailure {
+                    file: file.clone(),
+                    message: err.to_string(),
+                }),
+            }
+        }
+        failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_format_files() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir().unwrap();
+        let log = dir.path().join("formatted.log");
+        let script = dir.path().join("fake-formatter");
+        // records its arguments and fails on files named bad.py
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\necho \"$@\" >> {:?}\ncase \"$2\" in *bad.py) echo broken >&2; exit 1;; esac\n",
+                log
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let good = dir.path().join("good.py");
+        let bad = dir.path().join("bad.py");
+        let formatter = Formatter::new(&format!("{} --quiet", script.display())).unwrap();
+        let failures = formatter.format_files(&[good.clone(), bad.clone()]);
+
+        assert_eq!(
+            fs::read_to_string(&log).unwrap(),
+            format!("--quiet {}\n--quiet {}\n", good.display(), bad.display())
+        );
+        assert_eq!(
+            failures,
+            vec![FormatFailure {
+                file: bad,
+                message: "broken".to_string()
+            }]
+        );
+
+        let missing = Formatter::new("mechatyper-missing-formatter").unwrap();
+        assert!(missing.format_files(&[good]).is_empty());
+        assert!(Formatter::new("  ").is_err());
+    }
+}
@@ -0,0 +1,286 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use git2::{BranchType, Repository, Status, StatusOptions};
+
+/// Files added or modified in the git index under `folder`, with one of the extensions
+pub fn staged_files(folder: &Path, extensions: &[&str]) -> Result<Vec<PathBuf>> {
+    let repo = Repository::discover(folder)
+        .with_context(|| format!("{:?} is not inside a git repository", folder))?;
+    let workdir = repo
+        .workdir()
+        .context("--staged needs a repository with a working directory")?
+        .to_path_buf();
+    let folder = fs::canonicalize(folder)?;
+
+    let mut options = StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    let statuses = repo.statuses(Some(&mut options))?;
+
+    let mut files = vec![];
+    for entry in statuses.iter() {
+        if !entry
+            .status()
+            .intersects(Status::INDEX_NEW | Status::INDEX_MODIFIED | Status::INDEX_RENAMED)
+        {
+            continue;
+        }
+        let Some(path) = entry.path() else {
+            continue;
+        };
+        let path = workdir.join(path);
+        let matches_extension = path
+            .extension()
+            .and_then(|extension| extension.to_str())
+            .map_or(false, |extension| extensions.contains(&extension));
+        // staged files can be deleted from the working directory afterwards
+        let in_folder = fs::canonicalize(&path).map_or(false, |path| path.starts_with(&folder));
+        if matches_extension && in_folder {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Commit message of a `--commit` run: the task as subject and the changed files in the body
+pub fn commit_message(task: &str, files: &[PathBuf]) -> String {
+    let task = task.lines().next().unwrap_or_default().trim();
+    let mut subject = format!("mechatyper: {}", task);
+    if subject.chars().count() > 72 {
+        subject = subject.chars().take(69).collect::<String>() + "...";
+    }
+    let mut message = format!("{}\n\nChanged files:\n", subject);
+    for file in files {
+        message.push_str(&format!("- {}\n", file.display()));
+    }
+    message
+}
+
+fn check_nothing_staged(repo: &Repository, except: &[PathBuf]) -> Result<()> {
+    let mut options = StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    let staged: Vec<String> = repo
+        .statuses(Some(&mut options))?
+        .iter()
+        .filter(|entry| {
+            entry.status().intersects(
+                Status::INDEX_NEW
+                    | Status::INDEX_MODIFIED
+                    | Status::INDEX_DELETED
+                    | Status::INDEX_RENAMED
+                    | Status::INDEX_TYPECHANGE,
+            )
+        })
+        .filter_map(|entry| entry.path().map(str::to_string))
+        .filter(|path| !except.iter().any(|file| Path::new(path) == file))
+        .collect();
+    if !staged.is_empty() {
+        bail!(
+            "Other changes are already staged ({}), commit or unstage them before using --commit",
+            staged.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Fails when changes are staged in the repository containing `root`, checked before a `--commit`
+/// run changes anything
+pub fn ensure_nothing_staged(root: &Path) -> Result<()> {
+    let repo = Repository::discover(root)
+        .with_context(|| format!("{:?} is not inside a git repository", root))?;
+    check_nothing_staged(&repo, &[])
+}
+
+/// Stages `files` and commits them on the current branch of the repository in `root`. Refuses to
+/// commit when other changes are already staged, so that they don't end up in the commit
+pub fn commit_files(root: &Path, files: &[PathBuf], task: &str) -> Result<git2::Oid> {
+    let repo = Repository::discover(root)
+        .with_context(|| format!("{:?} is not inside a git repository", root))?;
+    let workdir = fs::canonicalize(
+        repo.workdir()
+            .context("--commit needs a repository with a working directory")?,
+    )?;
+    let relative_files = files
+        .iter()
+        .map(|file| {
+            let file = fs::canonicalize(file)?;
+            Ok(file
+                .strip_prefix(&workdir)
+                .with_context(|| format!("{:?} is outside of the repository {:?}", file, workdir))?
+                .to_path_buf())
+        })
+        .collect::<Result<Vec<PathBuf>>>()?;
+
+    check_nothing_staged(&repo, &relative_files)?;
+
+    let mut index = repo.index()?;
+    for file in &relative_files {
+        index.add_path(file)?;
+    }
+    index.write()?;
+    let tree = repo.find_tree(index.write_tree()?)?;
+    let signature = repo
+        .signature()
+        .context("Set user.name and user.email in the git config to use --commit")?;
+    let parent = match repo.head() {
+        Ok(head) => Some(head.peel_to_commit()?),
+        // a repository without commits yet
+        Err(_) => None,
+    };
+    let parents: Vec<&git2::Commit> = parent.iter().collect();
+    let oid = repo.commit(
+        Some("HEAD"),
+        &signature,
+        &signature,
+        &commit_message(task, &relative_files),
+        &tree,
+        &parents,
+    )?;
+    Ok(oid)
+}
+
+/// Creates the branch `name` at HEAD and checks it out, so that a run's changes land there. An
+/// existing branch is only reset to HEAD with `force`. Refuses to run with uncommitted changes
+pub fn create_branch(root: &Path, name: &str, force: bool) -> Result<()> {
+    let repo = Repository::discover(root)
+        .with_context(|| format!("{:?} is not inside a git repository", root))?;
+
+    let mut options = StatusOptions::new();
+    options.include_untracked(false).include_ignored(false);
+    let changed: Vec<String> = repo
+        .statuses(Some(&mut options))?
+        .iter()
+        .filter(|entry| entry.status() != Status::CURRENT)
+        .filter_map(|entry| entry.path().map(str::to_string))
+        .collect();
+    if !changed.is_empty() {
+        bail!(
+            "Commit or stash the uncommitted changes ({}) before using --branch",
+            changed.join(", ")
+        );
+    }
+
+    let head = repo
+        .head()
+        .context("The repository has no commit to branch off")?;
+    if head.is_branch() && head.shorthand() == Some(name) {
+        return Ok(());
+    }
+    if !force && repo.find_branch(name, BranchType::Local).is_ok() {
+        bail!(
+            "The branch {} already exists, use --branch-force to reset it to HEAD",
+            name
+        );
+    }
+    let branch = repo.branch(name, &head.peel_to_commit()?, force)?;
+    let reference = branch
+        .get()
+        .name()
+        .with_context(|| format!("Invalid branch name {}", name))?;
+    // the branch points to HEAD and the working directory is clean, nothing to check out
+    repo.set_head(reference)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_staged_files() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join("staged.py"), "def a():\n    pass\n").unwrap();
+        fs::write(dir.path().join("unstaged.py"), "def b():\n    pass\n").unwrap();
+        fs::write(dir.path().join("notes.txt"), "notes\n").unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("staged.py")).unwrap();
+        index.add_path(Path::new("notes.txt")).unwrap();
+        index.write().unwrap();
+
+        let files = staged_files(dir.path(), &["py"]).unwrap();
+        assert_eq!(files.len(), 1);
+        assert!(files[0].ends_with("staged.py"));
+    }
+
+    #[test]
+    fn test_commit_files() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Test").unwrap();
+        config.set_str("user.email", "test@example.com").unwrap();
+        fs::write(dir.path().join("a.py"), "def a():\n    pass\n").unwrap();
+        fs::write(dir.path().join("b.py"), "def b():\n    pass\n").unwrap();
+        commit_files(dir.path(), &[dir.path().join("a.py")], "initial").unwrap();
+
+        fs::write(dir.path().join("a.py"), "def a():\n    return 1\n").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("b.py")).unwrap();
+        index.write().unwrap();
+        let err = commit_files(dir.path(), &[dir.path().join("a.py")], "add returns").unwrap_err();
+        assert!(err.to_string().contains("b.py"), "{}", err);
+
+        index.remove_path(Path::new("b.py")).unwrap();
+        index.write().unwrap();
+        let oid = commit_files(
+            dir.path(),
+            &[dir.path().join("a.py")],
+            "add returns\nto functions",
+        )
+        .unwrap();
+        let commit = repo.find_commit(oid).unwrap();
+        assert_eq!(
+            commit.message().unwrap(),
+            "mechatyper: add returns\n\nChanged files:\n- a.py\n"
+        );
+        assert_eq!(commit.parent_count(), 1);
+        let blob = commit
+            .tree()
+            .unwrap()
+            .get_path(Path::new("a.py"))
+            .unwrap()
+            .to_object(&repo)
+            .unwrap();
+        assert_eq!(
+            blob.as_blob().unwrap().content(),
+            b"def a():\n    return 1\n"
+        );
+        assert!(commit.tree().unwrap().get_path(Path::new("b.py")).is_err());
+    }
+
+    #[test]
+    fn test_create_branch() {
+        let dir = tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let mut config = repo.config().unwrap();
+        config.set_str("user.name", "Test").unwrap();
+        config.set_str("user.email", "test@example.com").unwrap();
+        assert!(create_branch(dir.path(), "refactor", false).is_err());
+
+        fs::write(dir.path().join("a.py"), "def a():\n    pass\n").unwrap();
+        commit_files(dir.path(), &[dir.path().join("a.py")], "initial").unwrap();
+        let initial_branch = repo.head().unwrap().shorthand().unwrap().to_string();
+
+        create_branch(dir.path(), "refactor", false).unwrap();
+        assert_eq!(repo.head().unwrap().shorthand(), Some("refactor"));
+        // already on it
+        create_branch(dir.path(), "refactor", false).unwrap();
+
+        repo.set_head(&format!("refs/heads/{}", initial_branch))
+            .unwrap();
+        let err = create_branch(dir.path(), "refactor", false).unwrap_err();
+        assert!(err.to_string().contains("--branch-force"), "{}", err);
+        create_branch(dir.path(), "refactor", true).unwrap();
+        assert_eq!(repo.head().unwrap().shorthand(), Some("refactor"));
+
+        fs::write(dir.path().join("a.py"), "def a():\n    return 1\n").unwrap();
+        let err = create_branch(dir.path(), "other", false).unwrap_err();
+        assert!(err.to_string().contains("a.py"), "{}", err);
+    }
+}
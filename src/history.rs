@@ -0,0 +1,179 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::search::WrittenFile;
+
+/// Journal of the runs made with backups, relative to the working directory
+pub const DEFAULT_HISTORY_FILE: &str = ".mechatyper_history.json";
+
+/// A file changed by a run, with the backup of its original contents
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JournaledFile {
+    pub path: PathBuf,
+    pub backup: PathBuf,
+    /// sha256 of the contents written by the run, to detect later edits
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub files: Vec<JournaledFile>,
+}
+
+fn file_sha256(path: &Path) -> Result<String> {
+    let contents = fs::read(path).with_context(|| format!("Cannot read {:?}", path))?;
+    Ok(format!("{:x}", Sha256::digest(contents)))
+}
+
+fn read_history(history: &Path) -> Result<Vec<HistoryEntry>> {
+    if !history.exists() {
+        return Ok(vec![]);
+    }
+    let contents = fs::read_to_string(history)?;
+    serde_json::from_str(&contents).with_context(|| format!("Invalid history file {:?}", history))
+}
+
+fn write_history(history: &Path, entries: &[HistoryEntry]) -> Result<()> {
+    fs::write(history, serde_json::to_string_pretty(entries)?)?;
+    Ok(())
+}
+
+/// Adds the files of a run that made backups to the journal, with the hash of their contents
+/// once the run is done with them (formatted, verified)
+pub fn record_run(history: &Path, written: &[WrittenFile]) -> Result<()> {
+    let mut files = Vec::with_capacity(written.len());
+    for file in written {
+        let Some(backup) = &file.backup else {
+            continue;
+        };
+        files.push(JournaledFile {
+            path: file.path.clone(),
+            backup: backup.clone(),
+            sha256: file_sha256(&file.path)?,
+        });
+    }
+    if files.is_empty() {
+        return Ok(());
+    }
+    let mut entries = read_history(history)?;
+    entries.push(HistoryEntry { files });
+    write_history(history, &entries)
+}
+
+/// Restores the files of the last journaled run from their backups and removes the backups.
+/// Refuses when any of the files changed since the run, so later edits are not lost
+pub fn undo_last_run(history: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = read_history(history)?;
+    let Some(entry) = entries.last() else {
+        bail!("Nothing to undo, {:?} has no runs", history);
+    };
+
+    let mut changed = vec![];
+    for file in &entry.files {
+        if !file.backup.exists() {
+            bail!("The backup {:?} of {:?} is missing", file.backup, file.path);
+        }
+        if file_sha256(&file.path)? != file.sha256 {
+            changed.push(file.path.display().to_string());
+        }
+    }
+    if !changed.is_empty() {
+        bail!(
+            "Not undoing the last run, these files changed since: {}",
+            changed.join(", ")
+        );
+    }
+
+    let mut restored = Vec::with_capacity(entry.files.len());
+    for file in &entry.files {
+        fs::copy(&file.backup, &file.path)
+            .with_context(|| format!("Cannot restore {:?} from {:?}", file.path, file.backup))?;
+        fs::remove_file(&file.backup)?;
+        restored.push(file.path.clone());
+    }
+    entries.pop();
+    write_history(history, &entries)?;
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::search::{apply_changes, ApplyOptions, ItemChange, ItemDef};
+
+    use super::*;
+
+    fn run_with_backup(path: &Path, history: &Path, new_code: &str) {
+        let contents = fs::read_to_string(path).unwrap();
+        let changes = vec![ItemChange {
+            before: ItemDef {
+                definition: contents.clone(),
+                end_byte: contents.len(),
+                filename: path.to_path_buf(),
+                ..Default::default()
+            },
+            after: new_code.to_string(),
+        }];
+        let options = ApplyOptions {
+            backup: true,
+            ..Default::default()
+        };
+        let written = apply_changes(changes, &options).unwrap();
+        record_run(history, &written).unwrap();
+    }
+
+    #[test]
+    fn test_undo_last_run() {
+        let dir = tempdir().unwrap();
+        let history = dir.path().join(DEFAULT_HISTORY_FILE);
+        let path = dir.path().join("a.py");
+        fs::write(&path, "def a():\n    return 1\n").unwrap();
+
+        run_with_backup(&path, &history, "def a():\n    return 2\n");
+        run_with_backup(&path, &history, "def a():\n    return 3\n");
+        assert_eq!(read_history(&history).unwrap().len(), 2);
+
+        // the last run first
+        let restored = undo_last_run(&history).unwrap();
+        assert_eq!(restored, vec![fs::canonicalize(&path).unwrap()]);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "def a():\n    return 2\n"
+        );
+        undo_last_run(&history).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "def a():\n    return 1\n"
+        );
+
+        assert!(!dir.path().join("a.py.mechatyper.bak").exists());
+        assert!(read_history(&history).unwrap().is_empty());
+        assert!(undo_last_run(&history).is_err());
+    }
+
+    #[test]
+    fn test_undo_refuses_files_changed_since_the_run() {
+        let dir = tempdir().unwrap();
+        let history = dir.path().join(DEFAULT_HISTORY_FILE);
+        let path = dir.path().join("a.py");
+        fs::write(&path, "def a():\n    return 1\n").unwrap();
+
+        run_with_backup(&path, &history, "def a():\n    return 2\n");
+        fs::write(&path, "def a():\n    return 2  # edited by hand\n").unwrap();
+
+        let err = undo_last_run(&history).unwrap_err().to_string();
+        assert!(err.contains("changed since"), "{}", err);
+        // nothing was touched
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "def a():\n    return 2  # edited by hand\n"
+        );
+        assert!(dir.path().join("a.py.mechatyper.bak").exists());
+        assert_eq!(read_history(&history).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,120 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+
+use crate::search::ItemDef;
+
+/// How much of an item must be covered by changed lines to be processed with `--since`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum HunkOverlap {
+    /// at least one line of the item changed
+    #[default]
+    Any,
+    /// every line of the item changed
+    Contained,
+    /// more than half of the lines of the item changed
+    Majority,
+}
+
+impl HunkOverlap {
+    /// Compares the `start..=end` line range of an item (0-based, like `ItemDef.start_pos` and
+    /// `ItemDef.end_pos`) with the changed line ranges of its file
+    pub fn matches(&self, start: usize, end: usize, hunks: &[(usize, usize)]) -> bool {
+        let total = end - start + 1;
+        let changed = (start..=end)
+            .filter(|line| {
+                hunks
+                    .iter()
+                    .any(|(hunk_start, hunk_end)| hunk_start <= line && line <= hunk_end)
+            })
+            .count();
+        match self {
+            HunkOverlap::Any => changed > 0,
+            HunkOverlap::Contained => changed == total,
+            HunkOverlap::Majority => changed * 2 > total,
+        }
+    }
+
+    pub fn matches_item(&self, item: &ItemDef, hunks: &[(usize, usize)]) -> bool {
+        self.matches(item.start_pos, item.end_pos, hunks)
+    }
+}
+
+/// Parses the `@@ -a,b +c,d @@` headers of a unified diff into 0-based inclusive line ranges
+/// of the new file. A pure deletion marks the line preceding it
+pub fn parse_hunks(diff: &str) -> Vec<(usize, usize)> {
+    diff.lines()
+        .filter_map(|line| line.strip_prefix("@@ "))
+        .filter_map(|header| header.split_whitespace().find(|part| part.starts_with('+')))
+        .filter_map(|new_range| {
+            let mut parts = new_range[1..].splitn(2, ',');
+            let start: usize = parts.next()?.parse().ok()?;
+            let count: usize = match parts.next() {
+                Some(count) => count.parse().ok()?,
+                None => 1,
+            };
+            let start = start.saturating_sub(1);
+            Some((start, start + count.saturating_sub(1)))
+        })
+        .collect()
+}
+
+/// Line ranges of `file` changed since the git revision `since`, including uncommitted changes
+pub fn changed_line_ranges(file: &Path, since: &str) -> Result<Vec<(usize, usize)>> {
+    let file = fs::canonicalize(file)?;
+    let output = Command::new("git")
+        .args(["--no-pager", "diff", "-U0", since, "--"])
+        .arg(&file)
+        .current_dir(file.parent().unwrap_or(Path::new("/")))
+        .output()
+        .context("Cannot run git diff")?;
+    if !output.status.success() {
+        bail!(
+            "git diff {} failed for {:?}: {}",
+            since,
+            file,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(parse_hunks(&String::from_utf8_lossy(&output.stdout)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_hunks() {
+        let diff = "diff --git a/a.py b/a.py\n\
+                    --- a/a.py\n\
+                    +++ b/a.py\n\
+                    @@ -3 +3 @@ def a():\n\
+                    -    return 1\n\
+                    +    return 2\n\
+                    @@ -10,0 +11,3 @@\n\
+                    @@ -20,2 +23,0 @@\n";
+        assert_eq!(parse_hunks(diff), vec![(2, 2), (10, 12), (22, 22)]);
+    }
+
+    // a function on lines 2..=6 straddling the start of a hunk
+    #[test]
+    fn test_any_overlap() {
+        assert!(HunkOverlap::Any.matches(2, 6, &[(6, 9)]));
+        assert!(!HunkOverlap::Any.matches(2, 6, &[(7, 9)]));
+    }
+
+    #[test]
+    fn test_contained_overlap() {
+        assert!(!HunkOverlap::Contained.matches(2, 6, &[(4, 9)]));
+        assert!(HunkOverlap::Contained.matches(2, 6, &[(0, 3), (4, 9)]));
+    }
+
+    #[test]
+    fn test_majority_overlap() {
+        assert!(HunkOverlap::Majority.matches(2, 6, &[(4, 9)]));
+        assert!(!HunkOverlap::Majority.matches(2, 6, &[(5, 9)]));
+    }
+}
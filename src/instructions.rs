@@ -1,8 +1,11 @@
+use std::path::PathBuf;
+
 use anyhow::{anyhow, Result};
 use schemars::{schema_for, JsonSchema};
 use serde::{Deserialize, Serialize};
 
 use crate::lang::{ProgItem, ProgLanguage, PythonProgItem};
+use crate::llm::AnswerSchema;
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub struct GoodInstructions {
@@ -14,6 +17,32 @@ pub struct GoodInstructions {
     /// if the user mentions any folder
     /// leave empty if a folder is not mentioned
     pub folder: Option<String>,
+    /// the folders after the first of a repeated `--folder`, never filled by the model
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    #[schemars(skip)]
+    pub extra_folders: Vec<String>,
+}
+
+impl GoodInstructions {
+    /// Instructions given with command line options instead of understood by the model
+    pub fn from_command_line(item: ProgItem, user_message: String, folders: &[String]) -> Self {
+        GoodInstructions {
+            item,
+            answer: String::new(),
+            user_message,
+            folder: folders.first().cloned(),
+            extra_folders: folders.iter().skip(1).cloned().collect(),
+        }
+    }
+
+    /// The folders to scan, the current directory when none is given
+    pub fn folders(&self) -> Vec<PathBuf> {
+        let first = self.folder.clone().unwrap_or(".".to_string());
+        std::iter::once(&first)
+            .chain(&self.extra_folders)
+            .map(PathBuf::from)
+            .collect()
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
@@ -50,6 +79,7 @@ pub fn good_instruction_example() -> Result<String> {
             .to_string(),
         user_message: "Create a Python function".to_string(),
         folder: Some("src".to_string()),
+        extra_folders: vec![],
     };
     serde_json::to_string_pretty(&data).map_err(|e| anyhow!(e))
 }
@@ -61,6 +91,7 @@ pub fn good_instruction_example_2() -> Result<String> {
             .to_string(),
         user_message: "Create a Python function".to_string(),
         folder: Some("src".to_string()),
+        extra_folders: vec![],
     };
     serde_json::to_string_pretty(&data).map_err(|e| anyhow!(e))
 }
@@ -85,6 +116,32 @@ pub fn clarification_needed_instruction_example() -> Result<String> {
     serde_json::to_string_pretty(&data).map_err(|e| anyhow!(e))
 }
 
+/// The answers the model gives to an instruction, for the backends with structured output
+pub fn instruction_schemas() -> Vec<AnswerSchema> {
+    let schema = |name: &str, description: &str, schema| AnswerSchema {
+        name: name.to_string(),
+        description: description.to_string(),
+        schema: serde_json::to_value(schema).unwrap(),
+    };
+    vec![
+        schema(
+            "good_instructions",
+            "The prompt says which items to change and how",
+            schema_for!(GoodInstructions),
+        ),
+        schema(
+            "clarification_needed",
+            "The prompt misses the language, the item or what to do",
+            schema_for!(ClarificationNeeded),
+        ),
+        schema(
+            "user_error",
+            "The prompt cannot be understood or asks for something unsupported",
+            schema_for!(UserError),
+        ),
+    ]
+}
+
 pub fn all_instruction_examples() -> Result<String> {
     let good_instruction = schema_for!(GoodInstructions);
     let good_instruction_json_schema = serde_json::to_string_pretty(&good_instruction).unwrap();
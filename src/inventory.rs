@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use colored::Colorize;
+use serde::{Deserialize, Serialize};
+
+use crate::lang::{ProgItem, ProgLanguage};
+use crate::search::{extract_all_items_from_directory, item_name, ItemDef};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct InventoryEntry {
+    pub item: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub filename: PathBuf,
+    pub start_line: usize,
+    pub end_line: usize,
+}
+
+/// Extractable items of a folder, grouped by item kind
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Inventory {
+    pub counts: BTreeMap<String, usize>,
+    pub items: Vec<InventoryEntry>,
+}
+
+pub fn build_inventory(folder: &Path, items: &[ProgItem]) -> Result<Inventory> {
+    let mut inventory = Inventory::default();
+    for item in items {
+        let language: ProgLanguage = item.clone().into();
+        let item_defs = match extract_all_items_from_directory(folder, language, item.clone()) {
+            Ok(item_defs) => item_defs,
+            Err(err) => {
+                eprintln!("{}", format!("Skipping {}: {}", item, err).yellow());
+                continue;
+            }
+        };
+
+        inventory.counts.insert(item.to_string(), item_defs.len());
+        inventory
+            .items
+            .extend(item_defs.into_iter().map(|item_def| InventoryEntry {
+                item: item.to_string(),
+                name: item_name(&item_def.definition, item),
+                filename: item_def.filename,
+                start_line: item_def.start_pos + 1,
+                end_line: item_def.end_pos + 1,
+            }));
+    }
+    Ok(inventory)
+}
+
+impl Inventory {
+    pub fn to_table(&self) -> String {
+        let mut table = String::new();
+        for entry in &self.items {
+            table.push_str(&format!(
+                "{:<24} {}:{}-{}\n",
+                entry.item,
+                entry.filename.display(),
+                entry.start_line,
+                entry.end_line
+            ));
+        }
+        table.push('\n');
+        for (item, count) in &self.counts {
+            table.push_str(&format!("{:<24} {}\n", item, count));
+        }
+        table.push_str(&format!("{:<24} {}\n", "Total", self.items.len()));
+        table
+    }
+
+    /// A sorted ctags `tags` file of the named items. The kind is the item kind without the
+    /// language, e.g. `function`
+    pub fn to_ctags(&self) -> String {
+        let mut tags: Vec<String> = self
+            .items
+            .iter()
+            .filter_map(|entry| {
+                let name = entry.name.as_ref()?;
+                let kind = entry.item.rsplit('.').next().unwrap_or(&entry.item);
+                Some(format!(
+                    "{}\t{}\t{};\"\t{}",
+                    name,
+                    entry.filename.display(),
+                    entry.start_line,
+                    kind.to_lowercase()
+                ))
+            })
+            .collect();
+        tags.sort();
+        tags.dedup();
+
+        let mut ctags = String::from(
+            "!_TAG_FILE_FORMAT\t2\t/extended format/\n!_TAG_FILE_SORTED\t1\t/0=unsorted, 1=sorted/\n!_TAG_PROGRAM_NAME\tmechatyper\t//\n",
+        );
+        for tag in tags {
+            ctags.push_str(&tag);
+            ctags.push('\n');
+        }
+        ctags
+    }
+}
+
+/// One line per item with its location and the first line of its definition, then the count
+pub fn item_list(items: &[ItemDef]) -> String {
+    let mut list = String::new();
+    for item in items {
+        list.push_str(&format!(
+            "{}:{}-{}  {}\n",
+            item.filename.display(),
+            item.start_pos + 1,
+            item.end_pos + 1,
+            item.definition.lines().next().unwrap_or_default().trim()
+        ));
+    }
+    list.push_str(&format!("{} items\n", items.len()));
+    list
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use crate::lang::{PythonProgItem, RustProgItem};
+
+    use super::*;
+
+    #[test]
+    fn test_build_inventory() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("shapes.py"),
+            "class Circle:\n    def area(self):\n        return 1\n\ndef helper():\n    pass\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.path().join("lib.rs"),
+            "struct Point {\n    x: i32,\n}\n",
+        )
+        .unwrap();
+
+        let inventory = build_inventory(
+            dir.path(),
+            &[
+                ProgItem::Python(PythonProgItem::Function),
+                ProgItem::Python(PythonProgItem::Class),
+                ProgItem::Rust(RustProgItem::Struct),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(inventory.counts["Python.Function"], 2);
+        assert_eq!(inventory.counts["Python.Class"], 1);
+        assert_eq!(inventory.counts["Rust.Struct"], 1);
+        assert_eq!(inventory.items.len(), 4);
+
+        let json = serde_json::to_string(&inventory).unwrap();
+        assert_eq!(serde_json::from_str::<Inventory>(&json).unwrap(), inventory);
+        assert!(inventory.to_table().contains("Total"));
+
+        let ctags = inventory.to_ctags();
+        let circle = format!(
+            "Circle\t{}\t1;\"\tclass",
+            dir.path().join("shapes.py").display()
+        );
+        let lines: Vec<&str> = ctags.lines().collect();
+        assert!(lines[0].starts_with("!_TAG_FILE_FORMAT"));
+        // sorted by name, `Point` from lib.rs before the Python functions
+        assert_eq!(lines[3], circle);
+        assert!(lines[4].starts_with("Point\t"));
+        assert!(lines[5].starts_with("area\t"));
+        assert!(lines[6].starts_with("helper\t"));
+    }
+
+    #[test]
+    fn test_item_list() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("shapes.py");
+        fs::write(
+            &file_path,
+            "def area(r):\n    return r * r\n\n\n    \ndef helper():\n    pass\n",
+        )
+        .unwrap();
+
+        let items = extract_all_items_from_directory(
+            dir.path(),
+            ProgLanguage::Python,
+            ProgItem::Python(PythonProgItem::Function),
+        )
+        .unwrap();
+
+        assert_eq!(
+            item_list(&items),
+            format!(
+                "{0}:1-2  def area(r):\n{0}:6-7  def helper():\n2 items\n",
+                file_path.display()
+            )
+        );
+    }
+}
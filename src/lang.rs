@@ -1,23 +1,48 @@
 use std::fmt;
 use std::fmt::{Display, Formatter};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use anyhow::{anyhow, Error};
+use anyhow::{anyhow, bail, Error};
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
-use tree_sitter::Language;
+use strum::IntoEnumIterator;
+use strum_macros::{Display, EnumIter, EnumString};
+use tree_sitter::{Language, LANGUAGE_VERSION, MIN_COMPATIBLE_LANGUAGE_VERSION};
 
-#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+extern "C" {
+    /// The SQL grammar compiled by build.rs
+    fn tree_sitter_sql() -> Language;
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, EnumIter)]
 pub enum ProgLanguage {
     Python,
     Rust,
+    JavaScript,
+    TypeScript,
+    Go,
+    C,
+    Cpp,
+    Ruby,
+    Java,
+    Sql,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
 pub enum ProgItem {
     Rust(RustProgItem),
     Python(PythonProgItem),
+    JavaScript(JavaScriptProgItem),
+    TypeScript(TypeScriptProgItem),
+    Go(GoProgItem),
+    C(CProgItem),
+    Cpp(CppProgItem),
+    Ruby(RubyProgItem),
+    Java(JavaProgItem),
+    Sql(SqlProgItem),
+    /// every item kind of the language at once, e.g. both the functions and the structs
+    All(ProgLanguage),
 }
 
 impl From<ProgItem> for ProgLanguage {
@@ -25,11 +50,21 @@ impl From<ProgItem> for ProgLanguage {
         match value {
             ProgItem::Rust(_) => ProgLanguage::Rust,
             ProgItem::Python(_) => ProgLanguage::Python,
+            ProgItem::JavaScript(_) => ProgLanguage::JavaScript,
+            ProgItem::TypeScript(_) => ProgLanguage::TypeScript,
+            ProgItem::Go(_) => ProgLanguage::Go,
+            ProgItem::C(_) => ProgLanguage::C,
+            ProgItem::Cpp(_) => ProgLanguage::Cpp,
+            ProgItem::Ruby(_) => ProgLanguage::Ruby,
+            ProgItem::Java(_) => ProgLanguage::Java,
+            ProgItem::Sql(_) => ProgLanguage::Sql,
+            ProgItem::All(language) => language,
         }
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Display, EnumString, EnumIter)]
+#[strum(ascii_case_insensitive)]
 pub enum PythonProgItem {
     Function,
     Class,
@@ -37,9 +72,12 @@ pub enum PythonProgItem {
     Decorator,
     Generator,
     Comprehension,
+    /// contiguous top-level statements of a script that are not definitions or imports
+    TopLevel,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema)]
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Display, EnumString, EnumIter)]
+#[strum(ascii_case_insensitive)]
 pub enum RustProgItem {
     Function,
     Struct,
@@ -50,6 +88,92 @@ pub enum RustProgItem {
     Const,
     Static,
     TypeAlias,
+    /// fenced ```rust code blocks in `///` and `//!` doc comments
+    DocTest,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Display, EnumString, EnumIter)]
+#[strum(ascii_case_insensitive)]
+pub enum JavaScriptProgItem {
+    Function,
+    ArrowFunction,
+    Class,
+    Method,
+    /// React function components, see `COMPONENT_QUERY`
+    Component,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Display, EnumString, EnumIter)]
+#[strum(ascii_case_insensitive)]
+pub enum TypeScriptProgItem {
+    Function,
+    Class,
+    Interface,
+    TypeAlias,
+    Method,
+    /// React function components in `.tsx` files, see `COMPONENT_QUERY`
+    Component,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Display, EnumString, EnumIter)]
+#[strum(ascii_case_insensitive)]
+pub enum GoProgItem {
+    Function,
+    /// functions with a receiver
+    Method,
+    Struct,
+    Interface,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Display, EnumString, EnumIter)]
+#[strum(ascii_case_insensitive)]
+pub enum CProgItem {
+    Function,
+    /// struct definitions with a body, not the `struct name` in declarations
+    Struct,
+    Enum,
+    Typedef,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Display, EnumString, EnumIter)]
+#[strum(ascii_case_insensitive)]
+pub enum CppProgItem {
+    /// every function definition, methods included
+    Function,
+    Class,
+    Struct,
+    /// methods defined in the class body or out of line (`void Foo::bar() {}`)
+    Method,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Display, EnumString, EnumIter)]
+#[strum(ascii_case_insensitive)]
+pub enum RubyProgItem {
+    Method,
+    Class,
+    Module,
+    /// methods defined on an object, e.g. `def self.create`
+    SingletonMethod,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Display, EnumString, EnumIter)]
+#[strum(ascii_case_insensitive)]
+pub enum JavaProgItem {
+    Class,
+    Interface,
+    /// also the methods of nested and anonymous classes
+    Method,
+    Enum,
+}
+
+/// Statements of PostgreSQL, the dialect of the vendored grammar
+#[derive(Clone, Debug, Serialize, Deserialize, JsonSchema, Display, EnumString, EnumIter)]
+#[strum(ascii_case_insensitive)]
+pub enum SqlProgItem {
+    /// `CREATE FUNCTION`, with its body in a string. `CREATE OR REPLACE FUNCTION` and
+    /// procedures don't parse
+    CreateFunction,
+    CreateTable,
 }
 
 impl FromStr for ProgLanguage {
@@ -59,23 +183,305 @@ impl FromStr for ProgLanguage {
         match s.to_ascii_lowercase().as_str() {
             "python" => Ok(ProgLanguage::Python),
             "rust" => Ok(ProgLanguage::Rust),
+            "javascript" | "js" => Ok(ProgLanguage::JavaScript),
+            "typescript" | "ts" => Ok(ProgLanguage::TypeScript),
+            "go" => Ok(ProgLanguage::Go),
+            "c" => Ok(ProgLanguage::C),
+            "cpp" | "c++" => Ok(ProgLanguage::Cpp),
+            "ruby" | "rb" => Ok(ProgLanguage::Ruby),
+            "java" => Ok(ProgLanguage::Java),
+            "sql" => Ok(ProgLanguage::Sql),
             _ => Err(anyhow!("Cannot parse {}", s)),
         }
     }
 }
 
+impl FromStr for ProgItem {
+    type Err = Error;
+
+    /// Parses items written as `<Language>.<Item>`, e.g. `Python.Function`, `rust.struct` or
+    /// `Rust.All`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (language, item) = s.split_once('.').ok_or_else(|| {
+            anyhow!(
+                "Expected <Language>.<Item> (e.g. Python.Function), got {}",
+                s
+            )
+        })?;
+        let parse_error = |_| anyhow!("Unknown item {} for language {}", item, language);
+        if item.eq_ignore_ascii_case("all") {
+            return Ok(ProgItem::All(language.parse()?));
+        }
+        match language.parse::<ProgLanguage>()? {
+            ProgLanguage::Python => Ok(ProgItem::Python(item.parse().map_err(parse_error)?)),
+            ProgLanguage::Rust => Ok(ProgItem::Rust(item.parse().map_err(parse_error)?)),
+            ProgLanguage::JavaScript => {
+                Ok(ProgItem::JavaScript(item.parse().map_err(parse_error)?))
+            }
+            ProgLanguage::TypeScript => {
+                Ok(ProgItem::TypeScript(item.parse().map_err(parse_error)?))
+            }
+            ProgLanguage::Go => Ok(ProgItem::Go(item.parse().map_err(parse_error)?)),
+            ProgLanguage::C => Ok(ProgItem::C(item.parse().map_err(parse_error)?)),
+            ProgLanguage::Cpp => Ok(ProgItem::Cpp(item.parse().map_err(parse_error)?)),
+            ProgLanguage::Ruby => Ok(ProgItem::Ruby(item.parse().map_err(parse_error)?)),
+            ProgLanguage::Java => Ok(ProgItem::Java(item.parse().map_err(parse_error)?)),
+            ProgLanguage::Sql => Ok(ProgItem::Sql(item.parse().map_err(parse_error)?)),
+        }
+    }
+}
+
+impl Display for ProgItem {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProgItem::Python(item) => write!(f, "Python.{}", item),
+            ProgItem::Rust(item) => write!(f, "Rust.{}", item),
+            ProgItem::JavaScript(item) => write!(f, "JavaScript.{}", item),
+            ProgItem::TypeScript(item) => write!(f, "TypeScript.{}", item),
+            ProgItem::Go(item) => write!(f, "Go.{}", item),
+            ProgItem::C(item) => write!(f, "C.{}", item),
+            ProgItem::Cpp(item) => write!(f, "Cpp.{}", item),
+            ProgItem::Ruby(item) => write!(f, "Ruby.{}", item),
+            ProgItem::Java(item) => write!(f, "Java.{}", item),
+            ProgItem::Sql(item) => write!(f, "Sql.{}", item),
+            ProgItem::All(language) => write!(f, "{:?}.All", language),
+        }
+    }
+}
+
 impl ProgLanguage {
+    /// All item kinds that can be extracted for the language
+    pub fn items(&self) -> Vec<ProgItem> {
+        match self {
+            ProgLanguage::Python => PythonProgItem::iter().map(ProgItem::Python).collect(),
+            ProgLanguage::Rust => RustProgItem::iter().map(ProgItem::Rust).collect(),
+            ProgLanguage::JavaScript => JavaScriptProgItem::iter()
+                .map(ProgItem::JavaScript)
+                .collect(),
+            ProgLanguage::TypeScript => TypeScriptProgItem::iter()
+                .map(ProgItem::TypeScript)
+                .collect(),
+            ProgLanguage::Go => GoProgItem::iter().map(ProgItem::Go).collect(),
+            ProgLanguage::C => CProgItem::iter().map(ProgItem::C).collect(),
+            ProgLanguage::Cpp => CppProgItem::iter().map(ProgItem::Cpp).collect(),
+            ProgLanguage::Ruby => RubyProgItem::iter().map(ProgItem::Ruby).collect(),
+            ProgLanguage::Java => JavaProgItem::iter().map(ProgItem::Java).collect(),
+            ProgLanguage::Sql => SqlProgItem::iter().map(ProgItem::Sql).collect(),
+        }
+    }
+
     pub fn tree_sitter_language(&self) -> Language {
         match self {
             ProgLanguage::Python => tree_sitter_python::language(),
             ProgLanguage::Rust => tree_sitter_rust::language(),
+            ProgLanguage::JavaScript => tree_sitter_javascript::language(),
+            ProgLanguage::TypeScript => tree_sitter_typescript::language_typescript(),
+            ProgLanguage::Go => tree_sitter_go::language(),
+            ProgLanguage::C => tree_sitter_c::language(),
+            ProgLanguage::Cpp => tree_sitter_cpp::language(),
+            ProgLanguage::Ruby => tree_sitter_ruby::language(),
+            ProgLanguage::Java => tree_sitter_java::language(),
+            ProgLanguage::Sql => unsafe { tree_sitter_sql() },
+        }
+    }
+
+    /// The grammar for a file of the language, which differs from `tree_sitter_language` when a
+    /// file extension needs its own grammar (`.tsx`)
+    pub fn tree_sitter_language_for_file(&self, filename: &Path) -> Language {
+        let extension = filename
+            .extension()
+            .and_then(|extension| extension.to_str());
+        match (self, extension) {
+            (ProgLanguage::TypeScript, Some("tsx")) => tree_sitter_typescript::language_tsx(),
+            _ => self.tree_sitter_language(),
+        }
+    }
+
+    /// Name of the crate providing the tree-sitter grammar
+    pub fn grammar_crate(&self) -> &'static str {
+        match self {
+            ProgLanguage::Python => "tree-sitter-python",
+            ProgLanguage::Rust => "tree-sitter-rust",
+            ProgLanguage::JavaScript => "tree-sitter-javascript",
+            ProgLanguage::TypeScript => "tree-sitter-typescript",
+            ProgLanguage::Go => "tree-sitter-go",
+            ProgLanguage::C => "tree-sitter-c",
+            ProgLanguage::Cpp => "tree-sitter-cpp",
+            ProgLanguage::Ruby => "tree-sitter-ruby",
+            ProgLanguage::Java => "tree-sitter-java",
+            ProgLanguage::Sql => "tree-sitter-sql (vendor/tree-sitter-sql)",
+        }
+    }
+
+    /// Checks that the grammar was generated for an ABI version supported by the linked
+    /// tree-sitter runtime, which breaks when only one of the crates is bumped
+    pub fn check_grammar(&self) -> Result<(), Error> {
+        let version = self.tree_sitter_language().version();
+        if !(MIN_COMPATIBLE_LANGUAGE_VERSION..=LANGUAGE_VERSION).contains(&version) {
+            bail!(
+                "Incompatible tree-sitter grammar for {:?}: {} uses language ABI version {}, \
+                 but the tree-sitter runtime supports versions {} to {}. \
+                 Use versions of tree-sitter and {} that were released together.",
+                self,
+                self.grammar_crate(),
+                version,
+                MIN_COMPATIBLE_LANGUAGE_VERSION,
+                LANGUAGE_VERSION,
+                self.grammar_crate()
+            );
+        }
+        Ok(())
+    }
+
+    /// Prefix of a single line comment
+    pub fn comment_prefix(&self) -> &'static str {
+        match self {
+            ProgLanguage::Python | ProgLanguage::Ruby => "#",
+            ProgLanguage::Sql => "--",
+            ProgLanguage::Rust
+            | ProgLanguage::JavaScript
+            | ProgLanguage::TypeScript
+            | ProgLanguage::Go
+            | ProgLanguage::C
+            | ProgLanguage::Cpp
+            | ProgLanguage::Java => "//",
         }
     }
 
+    /// Opening and closing delimiters of a block comment, if the language has one
+    pub fn block_comment(&self) -> Option<(&'static str, &'static str)> {
+        match self {
+            // Ruby's =begin and =end must start their lines, they can't wrap code inline
+            ProgLanguage::Python | ProgLanguage::Ruby => None,
+            ProgLanguage::Rust
+            | ProgLanguage::JavaScript
+            | ProgLanguage::TypeScript
+            | ProgLanguage::Go
+            | ProgLanguage::C
+            | ProgLanguage::Cpp
+            | ProgLanguage::Java
+            | ProgLanguage::Sql => Some(("/*", "*/")),
+        }
+    }
+
+    /// Turns every line of `text` into a line comment
+    pub fn comment(&self, text: &str) -> String {
+        text.lines()
+            .map(|line| {
+                if line.is_empty() {
+                    self.comment_prefix().to_string()
+                } else {
+                    format!("{} {}", self.comment_prefix(), line)
+                }
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
     pub fn file_extensions(&self) -> Vec<&'static str> {
         match self {
             ProgLanguage::Python => vec!["py"],
             ProgLanguage::Rust => vec!["rs"],
+            ProgLanguage::JavaScript => vec!["js", "mjs", "cjs"],
+            ProgLanguage::TypeScript => vec!["ts", "tsx"],
+            ProgLanguage::Go => vec!["go"],
+            // headers and sources share the grammar
+            ProgLanguage::C => vec!["c", "h"],
+            // `.h` headers are scanned with the grammar of the requested item, C or C++
+            ProgLanguage::Cpp => vec!["cpp", "cc", "cxx", "hpp", "h"],
+            ProgLanguage::Ruby => vec!["rb"],
+            ProgLanguage::Java => vec!["java"],
+            ProgLanguage::Sql => vec!["sql"],
+        }
+    }
+
+    /// Node kinds that add a path through the code, used for the cyclomatic complexity
+    pub fn branch_node_kinds(&self) -> Vec<&'static str> {
+        match self {
+            ProgLanguage::Python => vec![
+                "if_statement",
+                "elif_clause",
+                "for_statement",
+                "while_statement",
+                "except_clause",
+                "conditional_expression",
+                "boolean_operator",
+                "for_in_clause",
+                "if_clause",
+                "case_clause",
+            ],
+            ProgLanguage::Rust => vec![
+                "if_expression",
+                "if_let_expression",
+                "match_arm",
+                "for_expression",
+                "while_expression",
+                "while_let_expression",
+                "loop_expression",
+                "try_expression",
+            ],
+            ProgLanguage::JavaScript | ProgLanguage::TypeScript => vec![
+                "if_statement",
+                "for_statement",
+                "for_in_statement",
+                "while_statement",
+                "do_statement",
+                "catch_clause",
+                "ternary_expression",
+                "switch_case",
+            ],
+            ProgLanguage::Go => vec![
+                "if_statement",
+                "for_statement",
+                "expression_case",
+                "type_case",
+                "communication_case",
+            ],
+            ProgLanguage::C => vec![
+                "if_statement",
+                "for_statement",
+                "while_statement",
+                "do_statement",
+                "case_statement",
+                "conditional_expression",
+            ],
+            ProgLanguage::Cpp => vec![
+                "if_statement",
+                "for_statement",
+                "for_range_loop",
+                "while_statement",
+                "do_statement",
+                "case_statement",
+                "catch_clause",
+                "conditional_expression",
+            ],
+            ProgLanguage::Ruby => vec![
+                "if",
+                "unless",
+                "elsif",
+                "if_modifier",
+                "unless_modifier",
+                "while",
+                "until",
+                "while_modifier",
+                "until_modifier",
+                "for",
+                "when",
+                "rescue",
+                "conditional",
+            ],
+            ProgLanguage::Java => vec![
+                "if_statement",
+                "for_statement",
+                "enhanced_for_statement",
+                "while_statement",
+                "do_statement",
+                "switch_label",
+                "catch_clause",
+                "ternary_expression",
+            ],
+            // the body of a function is a string, there is no control flow to parse
+            ProgLanguage::Sql => vec![],
         }
     }
 
@@ -83,22 +489,134 @@ impl ProgLanguage {
         match self {
             ProgLanguage::Python => vec!["site-packages", "venv", "__pycache__", ".pytest_cache"],
             ProgLanguage::Rust => vec!["target", ".cargo"],
+            ProgLanguage::JavaScript => vec!["node_modules", "dist", "build"],
+            ProgLanguage::TypeScript => vec!["node_modules", "dist"],
+            ProgLanguage::Go => vec!["vendor"],
+            ProgLanguage::C | ProgLanguage::Cpp => vec!["build", "CMakeFiles"],
+            ProgLanguage::Ruby => vec!["vendor", "tmp"],
+            ProgLanguage::Java => vec!["target", "build", ".gradle"],
+            ProgLanguage::Sql => vec![],
+        }
+    }
+
+    /// Language tag of a markdown code block
+    pub fn markdown_tag(&self) -> &'static str {
+        match self {
+            ProgLanguage::Python => "python",
+            ProgLanguage::Rust => "rust",
+            ProgLanguage::JavaScript => "javascript",
+            ProgLanguage::TypeScript => "typescript",
+            ProgLanguage::Go => "go",
+            ProgLanguage::C => "c",
+            ProgLanguage::Cpp => "cpp",
+            ProgLanguage::Ruby => "ruby",
+            ProgLanguage::Java => "java",
+            ProgLanguage::Sql => "sql",
+        }
+    }
+
+    /// Formatter run by `--format` on the changed files, the file name is appended
+    pub fn default_formatter(&self) -> &'static str {
+        match self {
+            ProgLanguage::Python => "black --quiet",
+            ProgLanguage::Rust => "rustfmt --edition 2021",
+            ProgLanguage::JavaScript | ProgLanguage::TypeScript => "prettier --write",
+            ProgLanguage::Go => "gofmt -w",
+            ProgLanguage::C | ProgLanguage::Cpp => "clang-format -i",
+            ProgLanguage::Ruby => "rubocop --autocorrect --format quiet",
+            ProgLanguage::Java => "google-java-format --replace",
+            ProgLanguage::Sql => "pg_format --inplace",
         }
     }
 }
 
+/// React function components: a function declaration or an arrow function assigned to a
+/// constant, with a PascalCase name, that returns JSX. The JSX is looked for in a `return` of
+/// the body or of a block right inside it (an `if`), or as the body of the arrow function.
+/// Components wrapped in `memo` or `forwardRef`, or returning JSX kept in a variable, are missed
+const COMPONENT_QUERY: &str = r#"
+(function_declaration
+    name: (identifier) @_name
+    body: (statement_block [
+        (return_statement [
+            (jsx_element) (jsx_self_closing_element)
+            (parenthesized_expression [(jsx_element) (jsx_self_closing_element)])
+        ])
+        (_ (statement_block (return_statement [
+            (jsx_element) (jsx_self_closing_element)
+            (parenthesized_expression [(jsx_element) (jsx_self_closing_element)])
+        ])))
+    ])
+    (#match? @_name "^[A-Z]")) @item
+(lexical_declaration
+    (variable_declarator
+        name: (identifier) @_name
+        value: (arrow_function body: [
+            (jsx_element) (jsx_self_closing_element)
+            (parenthesized_expression [(jsx_element) (jsx_self_closing_element)])
+            (statement_block [
+                (return_statement [
+                    (jsx_element) (jsx_self_closing_element)
+                    (parenthesized_expression [(jsx_element) (jsx_self_closing_element)])
+                ])
+                (_ (statement_block (return_statement [
+                    (jsx_element) (jsx_self_closing_element)
+                    (parenthesized_expression [(jsx_element) (jsx_self_closing_element)])
+                ])))
+            ])
+        ]))
+    (#match? @_name "^[A-Z]")) @item
+"#;
+
 impl ProgItem {
+    /// The item kinds `<Language>.All` stands for. Doc tests and top-level statements are left
+    /// out, they aren't found by a query, and so are components, which are functions already
+    pub fn kinds(&self) -> Vec<ProgItem> {
+        match self {
+            ProgItem::All(language) => language
+                .items()
+                .into_iter()
+                .filter(|item| {
+                    !matches!(
+                        item,
+                        ProgItem::Rust(RustProgItem::DocTest)
+                            | ProgItem::Python(PythonProgItem::TopLevel)
+                            | ProgItem::JavaScript(JavaScriptProgItem::Component)
+                            | ProgItem::TypeScript(TypeScriptProgItem::Component)
+                    )
+                })
+                .collect(),
+            item => vec![item.clone()],
+        }
+    }
+
     pub fn to_sexpr(&self) -> String {
         match self {
             ProgItem::Python(item) => match item {
                 PythonProgItem::Function => "(function_definition) @item".into(),
                 PythonProgItem::Class => "(class_definition) @item".into(),
-                PythonProgItem::Method => "(class_definition method_definition) @item".into(),
+                // decorated methods are wrapped in a decorated_definition
+                PythonProgItem::Method => "(class_definition body: (block [
+                    (function_definition) @item
+                    (decorated_definition definition: (function_definition) @item)
+                ]))"
+                .into(),
                 PythonProgItem::Decorator => "(decorator) @item".into(),
-                PythonProgItem::Generator => "(function_definition yield) @item".into(),
-                PythonProgItem::Comprehension => {
-                    "(list_comprehension set_comprehension dictionary_comprehension) @item".into()
-                }
+                // queries can't look at every descendant, a `yield` is found in the body or in a
+                // block right inside it (a loop, an `if`, a `with`)
+                PythonProgItem::Generator => "(function_definition body: (block [
+                    (expression_statement (yield))
+                    (_ (block (expression_statement (yield))))
+                ])) @item"
+                    .into(),
+                PythonProgItem::Comprehension => "[
+                    (list_comprehension)
+                    (set_comprehension)
+                    (dictionary_comprehension)
+                ] @item"
+                    .into(),
+                // statements are grouped by hand, see `extract_top_level_statements`
+                PythonProgItem::TopLevel => "(module) @item".into(),
             },
             ProgItem::Rust(item) => match item {
                 RustProgItem::Function => "(function_item) @item".into(),
@@ -109,8 +627,150 @@ impl ProgItem {
                 RustProgItem::Macro => "(macro_definition) @item".into(),
                 RustProgItem::Const => "(const_item) @item".into(),
                 RustProgItem::Static => "(static_item) @item".into(),
-                RustProgItem::TypeAlias => "(type_alias) @item".into(),
+                RustProgItem::TypeAlias => "(type_item) @item".into(),
+                // code blocks are found in the text of the comments, see `extract_doc_tests`
+                RustProgItem::DocTest => "(line_comment) @item".into(),
+            },
+            ProgItem::JavaScript(item) => match item {
+                JavaScriptProgItem::Function => "(function_declaration) @item".into(),
+                JavaScriptProgItem::ArrowFunction => "(arrow_function) @item".into(),
+                JavaScriptProgItem::Class => "(class_declaration) @item".into(),
+                JavaScriptProgItem::Method => "(method_definition) @item".into(),
+                JavaScriptProgItem::Component => COMPONENT_QUERY.into(),
+            },
+            ProgItem::TypeScript(item) => match item {
+                TypeScriptProgItem::Function => "(function_declaration) @item".into(),
+                TypeScriptProgItem::Class => "(class_declaration) @item".into(),
+                TypeScriptProgItem::Interface => "(interface_declaration) @item".into(),
+                TypeScriptProgItem::TypeAlias => "(type_alias_declaration) @item".into(),
+                TypeScriptProgItem::Method => "(method_definition) @item".into(),
+                TypeScriptProgItem::Component => COMPONENT_QUERY.into(),
+            },
+            ProgItem::Go(item) => match item {
+                GoProgItem::Function => "(function_declaration) @item".into(),
+                GoProgItem::Method => "(method_declaration) @item".into(),
+                GoProgItem::Struct => {
+                    "(type_declaration (type_spec type: (struct_type))) @item".into()
+                }
+                GoProgItem::Interface => {
+                    "(type_declaration (type_spec type: (interface_type))) @item".into()
+                }
+            },
+            ProgItem::C(item) => match item {
+                CProgItem::Function => "(function_definition) @item".into(),
+                CProgItem::Struct => {
+                    "(struct_specifier body: (field_declaration_list)) @item".into()
+                }
+                CProgItem::Enum => "(enum_specifier body: (enumerator_list)) @item".into(),
+                CProgItem::Typedef => "(type_definition) @item".into(),
+            },
+            ProgItem::Cpp(item) => match item {
+                CppProgItem::Function => "(function_definition) @item".into(),
+                CppProgItem::Class => {
+                    "(class_specifier body: (field_declaration_list)) @item".into()
+                }
+                CppProgItem::Struct => {
+                    "(struct_specifier body: (field_declaration_list)) @item".into()
+                }
+                // out of line, the declarator names the class, which can't be told apart from
+                // a function of a namespace defined out of line
+                CppProgItem::Method => "[
+                    (field_declaration_list (function_definition) @item)
+                    (field_declaration_list (template_declaration (function_definition) @item))
+                    (function_definition declarator: [
+                        (function_declarator declarator: (qualified_identifier))
+                        (pointer_declarator declarator:
+                            (function_declarator declarator: (qualified_identifier)))
+                        (reference_declarator
+                            (function_declarator declarator: (qualified_identifier)))
+                    ]) @item
+                ]"
+                .into(),
+            },
+            ProgItem::Ruby(item) => match item {
+                RubyProgItem::Method => "(method) @item".into(),
+                RubyProgItem::Class => "(class) @item".into(),
+                RubyProgItem::Module => "(module) @item".into(),
+                RubyProgItem::SingletonMethod => "(singleton_method) @item".into(),
+            },
+            // queries match at any depth, so the methods of nested classes are found too
+            ProgItem::Java(item) => match item {
+                JavaProgItem::Class => "(class_declaration) @item".into(),
+                JavaProgItem::Interface => "(interface_declaration) @item".into(),
+                JavaProgItem::Method => "(method_declaration) @item".into(),
+                JavaProgItem::Enum => "(enum_declaration) @item".into(),
             },
+            ProgItem::Sql(item) => match item {
+                SqlProgItem::CreateFunction => "(create_function_statement) @item".into(),
+                SqlProgItem::CreateTable => "(create_table_statement) @item".into(),
+            },
+            // one pattern per item kind
+            ProgItem::All(_) => self
+                .kinds()
+                .iter()
+                .map(ProgItem::to_sexpr)
+                .collect::<Vec<String>>()
+                .join("\n"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comment() {
+        assert_eq!(
+            ProgLanguage::Python.comment("changed by\n\nMechaTyper"),
+            "# changed by\n#\n# MechaTyper"
+        );
+        assert_eq!(ProgLanguage::Rust.comment("TODO"), "// TODO");
+        assert_eq!(ProgLanguage::Rust.block_comment(), Some(("/*", "*/")));
+        assert_eq!(ProgLanguage::Python.block_comment(), None);
+    }
+
+    #[test]
+    fn test_parse_all_items() {
+        let item: ProgItem = "Rust.All".parse().unwrap();
+        assert!(matches!(item, ProgItem::All(ProgLanguage::Rust)));
+        assert_eq!(item.to_string(), "Rust.All");
+        assert!(matches!(
+            "cpp.all".parse::<ProgItem>().unwrap(),
+            ProgItem::All(ProgLanguage::Cpp)
+        ));
+        assert!("Cobol.All".parse::<ProgItem>().is_err());
+
+        let kinds = item.kinds();
+        assert!(kinds.len() > 1);
+        assert!(!kinds
+            .iter()
+            .any(|kind| matches!(kind, ProgItem::Rust(RustProgItem::DocTest))));
+        assert_eq!(ProgItem::Rust(RustProgItem::Enum).kinds().len(), 1);
+    }
+
+    #[test]
+    fn test_grammars_are_compatible_with_runtime() {
+        for language in [
+            ProgLanguage::Python,
+            ProgLanguage::Rust,
+            ProgLanguage::JavaScript,
+            ProgLanguage::TypeScript,
+            ProgLanguage::Go,
+            ProgLanguage::C,
+            ProgLanguage::Cpp,
+            ProgLanguage::Ruby,
+            ProgLanguage::Java,
+            ProgLanguage::Sql,
+        ] {
+            assert!(language.check_grammar().is_ok(), "{:?}", language);
+            // a grammar the parser rejects is an error, not a panic
+            let item = language.items().remove(0);
+            assert!(
+                crate::search::parse_code("", &item).is_ok(),
+                "{:?}",
+                language
+            );
         }
     }
 }
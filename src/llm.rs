@@ -1,26 +1,1306 @@
-use crate::code_cleaning::extract_python_code;
+use crate::cache::ResponseCache;
+use crate::code_cleaning::{extract_fenced_code, extract_json_object};
+use crate::detector::Detector;
+use crate::instructions::InitialInstruction;
 use crate::lang::{ProgItem, PythonProgItem};
-use crate::prompts::quickcheck_prompt;
-use crate::search::parse_code;
-use dotenv::dotenv;
-use openai::chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole};
-use openai::set_key;
+use crate::prompts::{chatgpt_wrong_answer, quickcheck_prompt};
+use crate::search::parse_code_checked;
+use crate::usage::{estimate_tokens, UsageTracker};
+use async_trait::async_trait;
+use clap::ValueEnum;
+use colored::Colorize;
+use openai::chat::{
+    ChatCompletion, ChatCompletionBuilder, ChatCompletionDelta, ChatCompletionFunctionDefinition,
+    ChatCompletionMessage, ChatCompletionMessageRole,
+};
+use openai::{OpenAiError, Usage};
 use serde::{Deserialize, Serialize};
+use std::cell::Cell;
 use std::env;
 use std::error::Error;
+use std::future::Future;
+use std::io::{stdout, Write};
+use std::num::NonZeroU32;
+use std::sync::Mutex;
+use std::time::Duration;
 use tokio::runtime::Runtime;
+use tokio::time::Instant;
+use tracing::{debug, warn};
 
-pub fn load_env_variables() {
-    dotenv().expect("Failed to read .env file");
-    set_key(env::var("OPENAI_KEY").expect("OPENAI_KEY not set"));
+pub const DEFAULT_MODEL: &str = "gpt-3.5-turbo-16k-0613";
+
+/// Adds the model to a failed request's error, the usual cause being a model name that doesn't
+/// exist or isn't available for the API key
+pub fn request_error(model: &str, err: impl std::fmt::Display) -> anyhow::Error {
+    anyhow::anyhow!(
+        "The request to model {} failed: {} (check the --model name)",
+        model,
+        err
+    )
+}
+
+/// Default for `--max-retries`
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+
+/// Rate limits, server errors and connection problems go away by themselves, other errors (a bad
+/// request, a wrong API key, an exhausted quota) don't
+pub fn is_retryable(err: &OpenAiError) -> bool {
+    matches!(
+        err.error_type.as_str(),
+        "requests" | "tokens" | "rate_limit_error" | "server_error" | "reqwest"
+    ) || err.code.as_deref() == Some("rate_limit_exceeded")
+}
+
+/// Default for `--retry-delay`
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Parses `--retry-delay`, in seconds
+pub fn parse_retry_delay(value: &str) -> Result<Duration, String> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+        .ok_or_else(|| format!("{:?} is not a number of seconds", value))
+}
+
+/// Wait before retry number `attempt` (starting at 0): `first`, twice as long, four times, ...
+/// up to a minute, scaled by a factor between 0.5 and 1 from `jitter` (0..1) so that concurrent
+/// requests spread out
+pub fn backoff_delay(first: Duration, attempt: u32, jitter: f64) -> Duration {
+    let delay = (first * 2u32.pow(attempt.min(6))).min(Duration::from_secs(60));
+    delay.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+}
+
+async fn retry_with_backoff<T, E, Request, Sleep>(
+    max_retries: u32,
+    first_delay: Duration,
+    retryable: impl Fn(&E) -> bool,
+    mut request: impl FnMut() -> Request,
+    mut sleep: impl FnMut(Duration) -> Sleep,
+) -> Result<T, E>
+where
+    E: std::fmt::Display,
+    Request: Future<Output = Result<T, E>>,
+    Sleep: Future<Output = ()>,
+{
+    let mut attempt = 0;
+    loop {
+        match request().await {
+            Err(err) if attempt < max_retries && retryable(&err) => {
+                // not counted outside `LlmClient::complete`
+                let _ = RETRIES.try_with(|retries| retries.set(retries.get() + 1));
+                let delay = backoff_delay(first_delay, attempt, rand::random());
+                warn!("{}, retrying in {:.1}s", err, delay.as_secs_f64());
+                sleep(delay).await;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Sends the request, retrying up to `max_retries` times with exponential backoff from
+/// `retry_delay` when it is rate limited, fails for a transient reason or gets no answer within
+/// `timeout`. The builder is consumed by `create`, `builder` makes a new one for every attempt
+pub async fn create_with_retry(
+    builder: impl Fn() -> ChatCompletionBuilder,
+    max_retries: u32,
+    retry_delay: Duration,
+    timeout: Option<Duration>,
+) -> Result<ChatCompletion, AttemptError<OpenAiError>> {
+    retry_with_backoff(
+        max_retries,
+        retry_delay,
+        |err: &AttemptError<OpenAiError>| err.is_retryable(is_retryable),
+        || {
+            let builder = builder();
+            attempt(timeout, async move { builder.create().await })
+        },
+        tokio::time::sleep,
+    )
+    .await
+}
+
+/// Default for `--request-timeout`, in seconds
+pub const DEFAULT_REQUEST_TIMEOUT: u64 = 120;
+
+/// A request that got no answer within `--request-timeout`
+#[derive(Debug)]
+pub struct RequestTimeout(pub Duration);
+
+impl std::fmt::Display for RequestTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No answer after {}s", self.0.as_secs_f64())
+    }
+}
+
+impl Error for RequestTimeout {}
+
+/// A failed attempt of a request: an error of the service, or no answer in time
+#[derive(Debug)]
+pub enum AttemptError<E> {
+    Failed(E),
+    TimedOut(RequestTimeout),
+}
+
+impl<E> AttemptError<E> {
+    /// Timeouts are retried, like the errors of the service `retryable` accepts
+    fn is_retryable(&self, retryable: impl Fn(&E) -> bool) -> bool {
+        match self {
+            AttemptError::Failed(err) => retryable(err),
+            AttemptError::TimedOut(_) => true,
+        }
+    }
+}
+
+impl<E: std::fmt::Display> AttemptError<E> {
+    /// The error of the request, see `request_error`. A timeout stays a `RequestTimeout` so that
+    /// the item can be skipped
+    fn into_error(self, model: &str) -> anyhow::Error {
+        match self {
+            AttemptError::Failed(err) => request_error(model, err),
+            AttemptError::TimedOut(timeout) => timeout.into(),
+        }
+    }
+}
+
+impl<E: std::fmt::Display> std::fmt::Display for AttemptError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AttemptError::Failed(err) => err.fmt(f),
+            AttemptError::TimedOut(timeout) => timeout.fmt(f),
+        }
+    }
+}
+
+/// One attempt of a request, abandoned without an answer after `timeout`. For a streamed answer
+/// it is the wait for the answer to start, the answer itself can take longer
+async fn attempt<T, E>(
+    timeout: Option<Duration>,
+    request: impl Future<Output = Result<T, E>>,
+) -> Result<T, AttemptError<E>> {
+    let result = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, request)
+            .await
+            .map_err(|_| AttemptError::TimedOut(RequestTimeout(timeout)))?,
+        None => request.await,
+    };
+    result.map_err(AttemptError::Failed)
+}
+
+/// Token bucket pacing the requests to `--rpm` per minute, shared by the concurrent requests.
+/// The bucket holds a single token so the requests are spread evenly over the minute
+#[derive(Debug)]
+pub struct RateLimiter {
+    interval: Duration,
+    /// when the next token is available, reserved by the callers in turn
+    next: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn new(rpm: NonZeroU32) -> Self {
+        RateLimiter {
+            interval: Duration::from_secs(60) / rpm.get(),
+            next: Mutex::new(None),
+        }
+    }
+
+    /// Waits for a token
+    pub async fn acquire(&self) {
+        let wait = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let available = next.map_or(now, |next| next.max(now));
+            *next = Some(available + self.interval);
+            available - now
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+tokio::task_local! {
+    /// Number of retries of the request `LlmClient::complete` is sending. A retry means a rate
+    /// limit or an overloaded server, `--auto-concurrency` backs off when it sees one
+    static RETRIES: Cell<u32>;
+}
+
+/// Most requests `--auto-concurrency` sends at the same time
+pub const MAX_AUTO_CONCURRENCY: usize = 32;
+
+/// Limit on the requests running at the same time that finds the highest one the service
+/// accepts, `--auto-concurrency`. Starts at a single request, allows one more after `limit`
+/// requests in a row are answered without a retry and halves when a request is throttled
+#[derive(Debug)]
+pub struct ConcurrencyLimiter {
+    max: usize,
+    state: Mutex<ConcurrencyState>,
+    released: tokio::sync::Notify,
+}
+
+#[derive(Debug)]
+struct ConcurrencyState {
+    limit: usize,
+    running: usize,
+    /// requests answered without a retry since the limit last changed
+    successes: usize,
+    /// bumped when the limit is halved, so the requests started before halve it only once
+    epoch: u64,
+}
+
+/// A request allowed to run by the `ConcurrencyLimiter`, given back with `release`
+#[derive(Debug)]
+pub struct ConcurrencyPermit {
+    epoch: u64,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(max: usize) -> Self {
+        ConcurrencyLimiter {
+            max: max.max(1),
+            state: Mutex::new(ConcurrencyState {
+                limit: 1,
+                running: 0,
+                successes: 0,
+                epoch: 0,
+            }),
+            released: tokio::sync::Notify::new(),
+        }
+    }
+
+    /// Waits until fewer than `limit` requests are running
+    pub async fn acquire(&self) -> ConcurrencyPermit {
+        loop {
+            let released = self.released.notified();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.running < state.limit {
+                    state.running += 1;
+                    return ConcurrencyPermit { epoch: state.epoch };
+                }
+            }
+            released.await;
+        }
+    }
+
+    /// Ends the request of `permit`, `throttled` when it was retried or timed out
+    pub fn release(&self, permit: ConcurrencyPermit, throttled: bool) {
+        {
+            let mut state = self.state.lock().unwrap();
+            state.running -= 1;
+            if throttled {
+                if permit.epoch == state.epoch {
+                    state.limit = (state.limit / 2).max(1);
+                    state.successes = 0;
+                    state.epoch += 1;
+                }
+            } else {
+                state.successes += 1;
+                if state.successes >= state.limit && state.limit < self.max {
+                    state.limit += 1;
+                    state.successes = 0;
+                }
+            }
+        }
+        self.released.notify_waiters();
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+}
+
+/// Which service answers the requests, `--backend`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    #[default]
+    #[value(name = "openai")]
+    OpenAi,
+    /// A local Ollama server, nothing leaves the machine
+    Ollama,
+    Anthropic,
+}
+
+/// Address of the Ollama server, `--ollama-url`
+pub const DEFAULT_OLLAMA_URL: &str = "http://localhost:11434";
+
+/// Settings of a single request
+#[derive(Clone, Debug, Default)]
+pub struct CompletionOptions {
+    pub temperature: Option<f32>,
+    /// Limit on the length of the answer, in tokens
+    pub max_tokens: Option<u32>,
+    /// Print the answer, dimmed, as it arrives
+    pub stream: bool,
+    /// The answer must be a JSON object matching one of these. OpenAI and Azure get them as
+    /// functions to call, Ollama answers in JSON mode, other backends only have the prompt
+    pub answer_schemas: Vec<AnswerSchema>,
+}
+
+/// A JSON answer the model is asked for
+#[derive(Clone, Debug)]
+pub struct AnswerSchema {
+    pub name: String,
+    pub description: String,
+    /// JSON Schema of the answer
+    pub schema: serde_json::Value,
+}
+
+impl AnswerSchema {
+    fn function(&self) -> ChatCompletionFunctionDefinition {
+        ChatCompletionFunctionDefinition {
+            name: self.name.clone(),
+            description: Some(self.description.clone()),
+            parameters: Some(self.schema.clone()),
+        }
+    }
+}
+
+/// The `function_call` of a request: a single schema is forced, out of several the model picks
+fn function_call(schemas: &[AnswerSchema]) -> serde_json::Value {
+    match schemas {
+        [schema] => serde_json::json!({ "name": schema.name }),
+        _ => serde_json::json!("auto"),
+    }
+}
+
+/// Parses `--temperature`, which the APIs accept between 0 and 2
+pub fn parse_temperature(value: &str) -> Result<f32, String> {
+    let temperature: f32 = value
+        .parse()
+        .map_err(|_| format!("{:?} is not a number", value))?;
+    if !(0.0..=2.0).contains(&temperature) {
+        return Err(format!("{} is not between 0.0 and 2.0", temperature));
+    }
+    Ok(temperature)
+}
+
+/// An answer of the model
+#[derive(Clone, Debug, Default)]
+pub struct Completion {
+    /// `None` when the model gave no answer, e.g. a response blocked by the content filter
+    pub content: Option<String>,
+    /// Not known for cached answers, estimated for answers streamed from OpenAI and Azure
+    pub usage: Option<Usage>,
+}
+
+impl Completion {
+    /// Sets the usage the API doesn't report, estimated from the length of the messages and of
+    /// the answer, see `estimate_tokens`
+    fn with_estimated_usage(mut self, messages: &[ChatCompletionMessage]) -> Self {
+        let prompt_tokens = messages
+            .iter()
+            .filter_map(|message| message.content.as_deref())
+            .map(estimate_tokens)
+            .sum::<usize>() as u32;
+        let completion_tokens = estimate_tokens(self.content.as_deref().unwrap_or_default()) as u32;
+        self.usage = Some(Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        });
+        self
+    }
+}
+
+/// A service that answers chat messages
+#[async_trait]
+pub trait LlmBackend: Send + Sync {
+    async fn complete(
+        &self,
+        model: &str,
+        messages: &[ChatCompletionMessage],
+        options: &CompletionOptions,
+    ) -> anyhow::Result<Completion>;
+}
+
+/// The OpenAI chat completions API, with the key set by `load_env_variables`
+pub struct OpenAi {
+    pub max_retries: u32,
+    /// `--retry-delay`, the wait before the first retry
+    pub retry_delay: Duration,
+    /// `--request-timeout` of every attempt
+    pub timeout: Option<Duration>,
+}
+
+#[async_trait]
+impl LlmBackend for OpenAi {
+    async fn complete(
+        &self,
+        model: &str,
+        messages: &[ChatCompletionMessage],
+        options: &CompletionOptions,
+    ) -> anyhow::Result<Completion> {
+        let builder = || chat_builder(model, messages, options);
+        if options.stream {
+            let completion = create_streamed(
+                model,
+                builder,
+                self.max_retries,
+                self.retry_delay,
+                self.timeout,
+            )
+            .await?;
+            return Ok(completion.with_estimated_usage(messages));
+        }
+        let completion =
+            create_with_retry(builder, self.max_retries, self.retry_delay, self.timeout)
+                .await
+                .map_err(|err| err.into_error(model))?;
+        Ok(completion.into())
+    }
+}
+
+impl From<ChatCompletion> for Completion {
+    /// The arguments of a function call are the answer to a request with answer schemas
+    fn from(completion: ChatCompletion) -> Self {
+        Completion {
+            content: completion.choices.into_iter().next().and_then(|choice| {
+                let message = choice.message;
+                message
+                    .content
+                    .or(message.function_call.map(|call| call.arguments))
+            }),
+            usage: completion.usage,
+        }
+    }
+}
+
+/// The text of a streamed answer, put together from its deltas
+#[derive(Debug, Default)]
+struct StreamedAnswer {
+    content: String,
+}
+
+impl StreamedAnswer {
+    /// Adds a delta and returns the new text of the first choice, if any
+    fn push(&mut self, delta: ChatCompletionDelta) -> Option<String> {
+        let choice = delta.choices.into_iter().find(|choice| choice.index == 0)?;
+        let chunk = choice.delta.content?;
+        self.content.push_str(&chunk);
+        Some(chunk)
+    }
+
+    fn into_completion(self) -> Completion {
+        Completion {
+            // nothing streamed is retried like an empty answer
+            content: (!self.content.is_empty()).then_some(self.content),
+            usage: None,
+        }
+    }
+}
+
+fn print_chunk(chunk: &str) -> anyhow::Result<()> {
+    print!("{}", chunk.dimmed());
+    stdout().flush()?;
+    Ok(())
+}
+
+fn chat_builder(
+    model: &str,
+    messages: &[ChatCompletionMessage],
+    options: &CompletionOptions,
+) -> ChatCompletionBuilder {
+    let mut builder = ChatCompletion::builder(model, messages.to_vec());
+    if let Some(temperature) = options.temperature {
+        builder = builder.temperature(temperature);
+    }
+    if let Some(max_tokens) = options.max_tokens {
+        builder = builder.max_tokens(max_tokens);
+    }
+    if !options.answer_schemas.is_empty() {
+        let functions: Vec<_> = options
+            .answer_schemas
+            .iter()
+            .map(AnswerSchema::function)
+            .collect();
+        builder = builder
+            .functions(functions)
+            .function_call(function_call(&options.answer_schemas));
+    }
+    builder
+}
+
+/// Why a streamed answer didn't start
+#[derive(Debug)]
+enum StreamError {
+    /// The request couldn't be sent
+    Request(String),
+    /// The stream ended before its first delta: the service refused the request, which the
+    /// library doesn't report, or dropped the connection
+    Empty,
+}
+
+impl std::fmt::Display for StreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StreamError::Request(err) => f.write_str(err),
+            StreamError::Empty => f.write_str("The answer stream ended before it started"),
+        }
+    }
+}
+
+/// Sends the request with streaming and prints the answer as it arrives. Starting the stream,
+/// up to its first delta, is retried like `create_with_retry`, an answer that breaks off is not
+async fn create_streamed(
+    model: &str,
+    builder: impl Fn() -> ChatCompletionBuilder,
+    max_retries: u32,
+    retry_delay: Duration,
+    timeout: Option<Duration>,
+) -> anyhow::Result<Completion> {
+    let (first, mut deltas) = retry_with_backoff(
+        max_retries,
+        retry_delay,
+        |err: &AttemptError<StreamError>| err.is_retryable(|err| matches!(err, StreamError::Empty)),
+        || {
+            let builder = builder();
+            attempt(timeout, async move {
+                let mut deltas = builder
+                    .create_stream()
+                    .await
+                    .map_err(|err| StreamError::Request(err.to_string()))?;
+                let first = deltas.recv().await.ok_or(StreamError::Empty)?;
+                Ok((first, deltas))
+            })
+        },
+        tokio::time::sleep,
+    )
+    .await
+    .map_err(|err| err.into_error(model))?;
+    let mut answer = StreamedAnswer::default();
+    let mut next = Some(first);
+    while let Some(delta) = next {
+        if let Some(chunk) = answer.push(delta) {
+            print_chunk(&chunk)?;
+        }
+        next = deltas.recv().await;
+    }
+    println!();
+    Ok(answer.into_completion())
+}
+
+/// A local Ollama server (https://github.com/ollama/ollama/blob/main/docs/api.md#generate-a-chat-completion)
+pub struct Ollama {
+    url: String,
+    timeout: Option<Duration>,
+    client: reqwest::Client,
+}
+
+impl Ollama {
+    pub fn new(url: &str) -> Self {
+        Ollama {
+            url: url.trim_end_matches('/').to_string(),
+            timeout: None,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Gives up on a request without an answer after `timeout`, `--request-timeout`. The local
+    /// server doesn't rate limit, the requests are not retried
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+}
+
+/// One line of an Ollama chat response, the whole response when it isn't streamed
+#[derive(Debug, Deserialize)]
+struct OllamaResponse {
+    message: Option<OllamaMessage>,
+    #[serde(default)]
+    done: bool,
+    prompt_eval_count: Option<u32>,
+    eval_count: Option<u32>,
+    error: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct OllamaMessage {
+    content: String,
+}
+
+fn ollama_request(
+    model: &str,
+    messages: &[ChatCompletionMessage],
+    options: &CompletionOptions,
+) -> serde_json::Value {
+    let messages: Vec<_> = messages
+        .iter()
+        .map(|message| {
+            serde_json::json!({
+                "role": message.role,
+                "content": message.content.clone().unwrap_or_default(),
+            })
+        })
+        .collect();
+    let mut request = serde_json::json!({
+        "model": model,
+        "messages": messages,
+        "stream": options.stream,
+    });
+    if let Some(temperature) = options.temperature {
+        request["options"]["temperature"] = temperature.into();
+    }
+    if let Some(max_tokens) = options.max_tokens {
+        request["options"]["num_predict"] = max_tokens.into();
+    }
+    if !options.answer_schemas.is_empty() {
+        request["format"] = "json".into();
+    }
+    request
+}
+
+/// The answer of an Ollama server, a single line of JSON or one line per chunk when streamed
+#[derive(Debug, Default)]
+struct OllamaAnswer {
+    content: String,
+    usage: Option<Usage>,
+}
+
+impl OllamaAnswer {
+    /// Adds a line of the response and returns its text
+    fn push_line(&mut self, model: &str, line: &str) -> anyhow::Result<String> {
+        if line.trim().is_empty() {
+            return Ok(String::new());
+        }
+        let response: OllamaResponse = serde_json::from_str(line)
+            .map_err(|err| anyhow::anyhow!("Unexpected response from Ollama: {}", err))?;
+        if let Some(error) = response.error {
+            return Err(request_error(model, error));
+        }
+        if response.done {
+            let prompt_tokens = response.prompt_eval_count.unwrap_or(0);
+            let completion_tokens = response.eval_count.unwrap_or(0);
+            self.usage = Some(Usage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+            });
+        }
+        let chunk = response
+            .message
+            .map(|message| message.content)
+            .unwrap_or_default();
+        self.content.push_str(&chunk);
+        Ok(chunk)
+    }
+
+    fn into_completion(self) -> Completion {
+        Completion {
+            content: (!self.content.is_empty()).then_some(self.content),
+            usage: self.usage,
+        }
+    }
+}
+
+#[async_trait]
+impl LlmBackend for Ollama {
+    async fn complete(
+        &self,
+        model: &str,
+        messages: &[ChatCompletionMessage],
+        options: &CompletionOptions,
+    ) -> anyhow::Result<Completion> {
+        let request = self
+            .client
+            .post(format!("{}/api/chat", self.url))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(ollama_request(model, messages, options).to_string())
+            .send();
+        let mut response = attempt(self.timeout, request)
+            .await
+            .map_err(|err| match err {
+                AttemptError::Failed(err) => anyhow::anyhow!(
+                    "Cannot reach Ollama at {} (is it running?): {}",
+                    self.url,
+                    err
+                ),
+                AttemptError::TimedOut(timeout) => timeout.into(),
+            })?;
+
+        let mut answer = OllamaAnswer::default();
+        for_each_line(&mut response, |line| {
+            let chunk = answer.push_line(model, line)?;
+            if options.stream {
+                print_chunk(&chunk)?;
+            }
+            Ok(())
+        })
+        .await?;
+        if options.stream {
+            println!();
+        }
+        Ok(answer.into_completion())
+    }
+}
+
+/// Calls `f` with every line of the body of `response` as it arrives
+async fn for_each_line(
+    response: &mut reqwest::Response,
+    mut f: impl FnMut(&str) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut pending = Vec::new();
+    while let Some(bytes) = response.chunk().await? {
+        pending.extend_from_slice(&bytes);
+        while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            f(String::from_utf8_lossy(&line).trim_end())?;
+        }
+    }
+    f(String::from_utf8_lossy(&pending).trim_end())
+}
+
+const ANTHROPIC_URL: &str = "https://api.anthropic.com/v1/messages";
+/// Sent in the `anthropic-version` header
+const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// The Messages API requires a limit on the length of the answer, used without `--max-tokens`
+const ANTHROPIC_MAX_TOKENS: u32 = 4096;
+
+/// Anthropic's Messages API, with the key from `ANTHROPIC_KEY`
+pub struct Anthropic {
+    key: String,
+    max_retries: u32,
+    retry_delay: Duration,
+    timeout: Option<Duration>,
+    idempotency_keys: bool,
+    client: reqwest::Client,
+}
+
+impl Anthropic {
+    pub fn from_env(max_retries: u32) -> anyhow::Result<Self> {
+        let key = env::var("ANTHROPIC_KEY").map_err(|_| {
+            anyhow::anyhow!("ANTHROPIC_KEY not set, it is needed by --backend anthropic")
+        })?;
+        Ok(Anthropic {
+            key,
+            max_retries,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            timeout: None,
+            idempotency_keys: true,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// Abandons an attempt without an answer after `timeout` and retries it, `--request-timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Waits `delay` before the first retry, `--retry-delay`
+    pub fn with_retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Sends every request with an `Idempotency-Key`, see `send_json`
+    pub fn with_idempotency_keys(mut self, enabled: bool) -> Self {
+        self.idempotency_keys = enabled;
+        self
+    }
+
+    async fn send(
+        &self,
+        body: &str,
+        idempotency_key: Option<&str>,
+    ) -> Result<reqwest::Response, ApiError> {
+        let request = self
+            .client
+            .post(ANTHROPIC_URL)
+            .header("x-api-key", &self.key)
+            .header("anthropic-version", ANTHROPIC_VERSION);
+        send_json(request, body, idempotency_key).await
+    }
+}
+
+/// A new key for the `Idempotency-Key` header, one per request and the same for its retries
+fn idempotency_key(enabled: bool) -> Option<String> {
+    enabled.then(|| format!("mechatyper-{:032x}", rand::random::<u128>()))
+}
+
+/// Posts the JSON `body`, an unsuccessful status is an error. The `idempotency_key` lets the
+/// service answer a retry of a request it already did without charging for it again
+async fn send_json(
+    request: reqwest::RequestBuilder,
+    body: &str,
+    idempotency_key: Option<&str>,
+) -> Result<reqwest::Response, ApiError> {
+    let request = match idempotency_key {
+        Some(key) => request.header("Idempotency-Key", key),
+        None => request,
+    };
+    let response = request
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .body(body.to_string())
+        .send()
+        .await
+        .map_err(|err| ApiError {
+            status: None,
+            error_type: "connection_error".to_string(),
+            message: err.to_string(),
+        })?;
+    if response.status().is_success() {
+        return Ok(response);
+    }
+    let status = response.status().as_u16();
+    let body = response.text().await.unwrap_or_default();
+    Err(ApiError::from_response(status, &body))
+}
+
+/// A failed request to an HTTP API that answers with `{"error": {"type" or "code", "message"}}`
+#[derive(Debug)]
+pub struct ApiError {
+    status: Option<u16>,
+    error_type: String,
+    message: String,
+}
+
+impl ApiError {
+    fn from_response(status: u16, body: &str) -> Self {
+        let error = serde_json::from_str::<serde_json::Value>(body)
+            .ok()
+            .and_then(|response| response.get("error").cloned());
+        let field = |name: &str| {
+            error
+                .as_ref()
+                .and_then(|error| error[name].as_str())
+                .map(str::to_string)
+        };
+        ApiError {
+            status: Some(status),
+            error_type: field("type")
+                .or_else(|| field("code"))
+                .unwrap_or_else(|| "http_error".to_string()),
+            message: field("message").unwrap_or_else(|| body.to_string()),
+        }
+    }
+
+    /// Rate limits, overloaded or failing servers and connection problems
+    fn is_retryable(&self) -> bool {
+        matches!(self.status, None | Some(429 | 500..=599))
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.message, self.error_type)
+    }
+}
+
+/// The Messages API takes the system prompt separately and the conversation as alternating user
+/// and assistant turns, consecutive messages of the same role are joined
+fn anthropic_request(
+    model: &str,
+    messages: &[ChatCompletionMessage],
+    options: &CompletionOptions,
+) -> serde_json::Value {
+    let mut system = vec![];
+    let mut turns: Vec<(&str, String)> = vec![];
+    for message in messages {
+        let content = message.content.clone().unwrap_or_default();
+        let role = match message.role {
+            ChatCompletionMessageRole::System => {
+                system.push(content);
+                continue;
+            }
+            ChatCompletionMessageRole::Assistant => "assistant",
+            _ => "user",
+        };
+        match turns.last_mut() {
+            Some((last_role, last_content)) if *last_role == role => {
+                last_content.push_str("\n\n");
+                last_content.push_str(&content);
+            }
+            _ => turns.push((role, content)),
+        }
+    }
+    let messages: Vec<_> = turns
+        .into_iter()
+        .map(|(role, content)| serde_json::json!({ "role": role, "content": content }))
+        .collect();
+    let mut request = serde_json::json!({
+        "model": model,
+        "max_tokens": options.max_tokens.unwrap_or(ANTHROPIC_MAX_TOKENS),
+        "messages": messages,
+        "stream": options.stream,
+    });
+    if !system.is_empty() {
+        request["system"] = system.join("\n\n").into();
+    }
+    if let Some(temperature) = options.temperature {
+        request["temperature"] = temperature.into();
+    }
+    request
+}
+
+/// The text and usage of a Messages API answer, from the whole response or its streamed events
+#[derive(Debug, Default)]
+struct AnthropicAnswer {
+    content: String,
+    input_tokens: u32,
+    output_tokens: u32,
+}
+
+impl AnthropicAnswer {
+    fn from_response(response: &serde_json::Value) -> Self {
+        let content = response["content"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|block| block["text"].as_str())
+            .collect();
+        let mut answer = AnthropicAnswer {
+            content,
+            ..Default::default()
+        };
+        answer.add_usage(&response["usage"]);
+        answer
+    }
+
+    fn add_usage(&mut self, usage: &serde_json::Value) {
+        if let Some(tokens) = usage["input_tokens"].as_u64() {
+            self.input_tokens = tokens as u32;
+        }
+        if let Some(tokens) = usage["output_tokens"].as_u64() {
+            self.output_tokens = tokens as u32;
+        }
+    }
+
+    /// Adds a server-sent event and returns its text, if any
+    fn push_event(&mut self, event: &serde_json::Value) -> anyhow::Result<Option<String>> {
+        match event["type"].as_str() {
+            Some("message_start") => self.add_usage(&event["message"]["usage"]),
+            Some("message_delta") => self.add_usage(&event["usage"]),
+            Some("content_block_delta") => {
+                if let Some(text) = event["delta"]["text"].as_str() {
+                    self.content.push_str(text);
+                    return Ok(Some(text.to_string()));
+                }
+            }
+            Some("error") => anyhow::bail!(
+                "{}",
+                event["error"]["message"]
+                    .as_str()
+                    .unwrap_or("unknown error")
+            ),
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    fn into_completion(self) -> Completion {
+        Completion {
+            content: (!self.content.is_empty()).then_some(self.content),
+            usage: Some(Usage {
+                prompt_tokens: self.input_tokens,
+                completion_tokens: self.output_tokens,
+                total_tokens: self.input_tokens + self.output_tokens,
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl LlmBackend for Anthropic {
+    async fn complete(
+        &self,
+        model: &str,
+        messages: &[ChatCompletionMessage],
+        options: &CompletionOptions,
+    ) -> anyhow::Result<Completion> {
+        let body = anthropic_request(model, messages, options).to_string();
+        let body = &body;
+        let key = idempotency_key(self.idempotency_keys);
+        let key = key.as_deref();
+        let mut response = retry_with_backoff(
+            self.max_retries,
+            self.retry_delay,
+            |err: &AttemptError<ApiError>| err.is_retryable(ApiError::is_retryable),
+            move || attempt(self.timeout, self.send(body, key)),
+            tokio::time::sleep,
+        )
+        .await
+        .map_err(|err| err.into_error(model))?;
+
+        if !options.stream {
+            let response: serde_json::Value = serde_json::from_str(&response.text().await?)?;
+            return Ok(AnthropicAnswer::from_response(&response).into_completion());
+        }
+        let mut answer = AnthropicAnswer::default();
+        for_each_line(&mut response, |line| {
+            let Some(data) = line.strip_prefix("data:") else {
+                return Ok(());
+            };
+            let event: serde_json::Value = serde_json::from_str(data.trim())?;
+            if let Some(chunk) = answer
+                .push_event(&event)
+                .map_err(|err| request_error(model, err))?
+            {
+                print_chunk(&chunk)?;
+            }
+            Ok(())
+        })
+        .await?;
+        println!();
+        Ok(answer.into_completion())
+    }
+}
+
+/// Default for `--azure-api-version`
+pub const DEFAULT_AZURE_API_VERSION: &str = "2024-02-01";
+
+/// An OpenAI model deployed on Azure, with the key from `AZURE_OPENAI_KEY`. Requests go to the
+/// deployment, whatever the model name
+pub struct AzureOpenAi {
+    url: String,
+    key: String,
+    max_retries: u32,
+    retry_delay: Duration,
+    timeout: Option<Duration>,
+    idempotency_keys: bool,
+    client: reqwest::Client,
+}
+
+impl AzureOpenAi {
+    pub fn from_env(
+        endpoint: &str,
+        deployment: &str,
+        api_version: &str,
+        max_retries: u32,
+    ) -> anyhow::Result<Self> {
+        let key = env::var("AZURE_OPENAI_KEY").map_err(|_| {
+            anyhow::anyhow!("AZURE_OPENAI_KEY not set, it is needed by --azure-endpoint")
+        })?;
+        Ok(AzureOpenAi {
+            url: azure_url(endpoint, deployment, api_version),
+            key,
+            max_retries,
+            retry_delay: DEFAULT_RETRY_DELAY,
+            timeout: None,
+            idempotency_keys: true,
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// Abandons an attempt without an answer after `timeout` and retries it, `--request-timeout`
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Waits `delay` before the first retry, `--retry-delay`
+    pub fn with_retry_delay(mut self, delay: Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Sends every request with an `Idempotency-Key`, see `send_json`
+    pub fn with_idempotency_keys(mut self, enabled: bool) -> Self {
+        self.idempotency_keys = enabled;
+        self
+    }
+
+    async fn send(
+        &self,
+        body: &str,
+        idempotency_key: Option<&str>,
+    ) -> Result<reqwest::Response, ApiError> {
+        send_json(
+            self.client.post(&self.url).header("api-key", &self.key),
+            body,
+            idempotency_key,
+        )
+        .await
+    }
+}
+
+fn azure_url(endpoint: &str, deployment: &str, api_version: &str) -> String {
+    format!(
+        "{}/openai/deployments/{}/chat/completions?api-version={}",
+        endpoint.trim_end_matches('/'),
+        deployment,
+        api_version
+    )
+}
+
+#[async_trait]
+impl LlmBackend for AzureOpenAi {
+    async fn complete(
+        &self,
+        model: &str,
+        messages: &[ChatCompletionMessage],
+        options: &CompletionOptions,
+    ) -> anyhow::Result<Completion> {
+        let request_messages: Vec<_> = messages
+            .iter()
+            .map(|message| {
+                serde_json::json!({
+                    "role": message.role,
+                    "content": message.content.clone().unwrap_or_default(),
+                })
+            })
+            .collect();
+        let mut request =
+            serde_json::json!({ "messages": request_messages, "stream": options.stream });
+        if let Some(temperature) = options.temperature {
+            request["temperature"] = temperature.into();
+        }
+        if let Some(max_tokens) = options.max_tokens {
+            request["max_tokens"] = max_tokens.into();
+        }
+        if !options.answer_schemas.is_empty() {
+            let functions: Vec<_> = options
+                .answer_schemas
+                .iter()
+                .map(AnswerSchema::function)
+                .collect();
+            request["functions"] = serde_json::to_value(functions)?;
+            request["function_call"] = function_call(&options.answer_schemas);
+        }
+        let body = request.to_string();
+        let body = &body;
+        let key = idempotency_key(self.idempotency_keys);
+        let key = key.as_deref();
+        let mut response = retry_with_backoff(
+            self.max_retries,
+            self.retry_delay,
+            |err: &AttemptError<ApiError>| err.is_retryable(ApiError::is_retryable),
+            move || attempt(self.timeout, self.send(body, key)),
+            tokio::time::sleep,
+        )
+        .await
+        .map_err(|err| err.into_error(model))?;
+
+        if !options.stream {
+            let completion: ChatCompletion = serde_json::from_str(&response.text().await?)?;
+            return Ok(completion.into());
+        }
+        let mut answer = StreamedAnswer::default();
+        for_each_line(&mut response, |line| {
+            let Some(data) = line.strip_prefix("data:").map(str::trim) else {
+                return Ok(());
+            };
+            if data == "[DONE]" {
+                return Ok(());
+            }
+            if let Some(chunk) = answer.push(serde_json::from_str(data)?) {
+                print_chunk(&chunk)?;
+            }
+            Ok(())
+        })
+        .await?;
+        println!();
+        Ok(answer.into_completion().with_estimated_usage(messages))
+    }
+}
+
+/// The backend and model every request of a session goes to, with the token usage of the session
+pub struct LlmClient {
+    backend: Box<dyn LlmBackend>,
+    pub model: String,
+    pub usage: Mutex<UsageTracker>,
+    rate_limiter: Option<RateLimiter>,
+    concurrency: Option<ConcurrencyLimiter>,
+}
+
+impl LlmClient {
+    pub fn new(backend: Box<dyn LlmBackend>, model: &str, usage: UsageTracker) -> Self {
+        LlmClient {
+            backend,
+            model: model.to_string(),
+            usage: Mutex::new(usage),
+            rate_limiter: None,
+            concurrency: None,
+        }
+    }
+
+    /// Adjusts the number of requests sent at the same time to the rate limits of the service,
+    /// up to `max`, `--auto-concurrency`
+    pub fn with_auto_concurrency(mut self, max: Option<usize>) -> Self {
+        self.concurrency = max.map(ConcurrencyLimiter::new);
+        self
+    }
+
+    /// The number of concurrent requests `--auto-concurrency` settled on
+    pub fn auto_concurrency(&self) -> Option<usize> {
+        self.concurrency.as_ref().map(ConcurrencyLimiter::limit)
+    }
+
+    /// Sends at most `rpm` requests per minute, `--rpm`. Cached answers don't count
+    pub fn with_rpm(mut self, rpm: Option<NonZeroU32>) -> Self {
+        self.rate_limiter = rpm.map(RateLimiter::new);
+        self
+    }
+
+    /// Sends the messages to the model, through `cache` when one is given. `definition` is the
+    /// code of the item the request is about, if any. Fails once the usage is over budget
+    pub async fn complete(
+        &self,
+        messages: Vec<ChatCompletionMessage>,
+        options: &CompletionOptions,
+        cache: Option<&ResponseCache>,
+        definition: &str,
+    ) -> anyhow::Result<Completion> {
+        self.usage.lock().unwrap().check_budget()?;
+        // the backends retry the failed and timed out attempts themselves
+        let request = async {
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.acquire().await;
+            }
+            let permit = match &self.concurrency {
+                Some(concurrency) => Some(concurrency.acquire().await),
+                None => None,
+            };
+            let (result, retries) = RETRIES
+                .scope(Cell::new(0), async {
+                    let result = self.backend.complete(&self.model, &messages, options).await;
+                    (result, RETRIES.with(Cell::get))
+                })
+                .await;
+            if let (Some(concurrency), Some(permit)) = (&self.concurrency, permit) {
+                let throttled =
+                    retries > 0 || matches!(&result, Err(err) if err.is::<RequestTimeout>());
+                concurrency.release(permit, throttled);
+            }
+            result
+        };
+        let completion = match cache {
+            Some(cache) => {
+                let key = ResponseCache::key(&self.model, &messages, options, definition);
+                cache.get_or_create(&key, request).await?
+            }
+            None => request.await?,
+        };
+        self.usage
+            .lock()
+            .unwrap()
+            .record(&self.model, completion.usage.as_ref())?;
+        Ok(completion)
+    }
+}
+
+/// Number of attempts for a request the model answers without any content
+pub const MAX_EMPTY_RESPONSES: usize = 3;
+
+/// The text of an answer. No answer (e.g. a response blocked by the content filter) is an error
+/// the caller can retry
+pub fn completion_content(completion: &Completion) -> anyhow::Result<String> {
+    completion.content.clone().ok_or_else(|| {
+        anyhow::anyhow!(
+            "The model returned no answer, the response may have been blocked by the content filter"
+        )
+    })
 }
 
 async fn process_chat_prompt(
     prompt: &str,
     default_output: String,
+    llm: &LlmClient,
+    cache: Option<&ResponseCache>,
 ) -> Result<String, Box<dyn Error>> {
-    load_env_variables();
-
     let mut messages = vec![ChatCompletionMessage {
         role: ChatCompletionMessageRole::System,
         content: Some("You are a code assists that writes Python code without any additional comments or explanations".to_string()),
@@ -39,20 +1319,28 @@ async fn process_chat_prompt(
     let max_attempts = 3;
 
     loop {
-        let chat_completion = ChatCompletion::builder("gpt-3.5-turbo-16k-0613", messages.clone())
-            .create()
+        let completion = llm
+            .complete(messages.clone(), &CompletionOptions::default(), cache, "")
             .await?;
 
-        let returned_message = chat_completion.choices.first().unwrap().message.clone();
-        let content = returned_message.content.clone().unwrap().trim().to_string();
+        let content = match completion_content(&completion) {
+            Ok(content) => content.trim().to_string(),
+            Err(err) if attempt_count < max_attempts => {
+                warn!("{}, retrying", err);
+                attempt_count += 1;
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
 
-        println!("Answer: {}", content);
+        debug!("Answer: {}", content);
 
-        match parse_code(&content, &ProgItem::Python(PythonProgItem::Function)) {
+        let python = ProgItem::Python(PythonProgItem::Function);
+        match parse_code_checked(&content, &python, None) {
             Ok(_) => return Ok(content),
             Err(_) => {
-                if let Some(python_code) = extract_python_code(&content) {
-                    match parse_code(&python_code, &ProgItem::Python(PythonProgItem::Function)) {
+                if let Some(python_code) = extract_fenced_code(&content, Some("python")) {
+                    match parse_code_checked(&python_code, &python, None) {
                         Ok(_) => return Ok(python_code),
                         Err(_) if attempt_count < max_attempts => {
                             messages.push(ChatCompletionMessage {
@@ -81,9 +1369,658 @@ async fn process_chat_prompt(
     }
 }
 
-// ... include the other functions `extract_python_code`, `parse_code`, and the necessary enum definitions ...
+/// Asks the model for a `detect` function that pre-selects the items relevant for the task
+pub async fn generate_detector(
+    task: &str,
+    llm: &LlmClient,
+    cache: Option<&ResponseCache>,
+) -> anyhow::Result<Detector> {
+    let source = process_chat_prompt(&quickcheck_prompt(task), String::new(), llm, cache)
+        .await
+        .map_err(|err| anyhow::anyhow!("Cannot generate a detector: {}", err))?;
+    Detector::from_source(&source)
+}
+
+/// Default for `--max-instruction-retries`
+pub const DEFAULT_MAX_INSTRUCTION_RETRIES: u32 = 3;
+
+/// Asks the model what the user wants. An answer that doesn't match the instruction schemas is
+/// sent back together with the error, at most `max_retries` times, before giving up with
+/// `TooManyTries`, as are repeated answers without content. The feedback stays in `messages`
+pub async fn request_instruction(
+    llm: &LlmClient,
+    messages: &mut Vec<ChatCompletionMessage>,
+    user_message: &str,
+    options: &CompletionOptions,
+    max_retries: u32,
+) -> anyhow::Result<InitialInstruction> {
+    let mut retries = 0;
+    let mut empty_responses = 0;
+    loop {
+        let completion = llm.complete(messages.clone(), options, None, "").await?;
+        let content = match completion_content(&completion) {
+            Ok(content) => content,
+            Err(err) => {
+                empty_responses += 1;
+                if empty_responses >= MAX_EMPTY_RESPONSES {
+                    warn!("{}", err);
+                    return Ok(InitialInstruction::TooManyTries);
+                }
+                warn!("{}, retrying", err);
+                continue;
+            }
+        };
+        debug!("Raw answer:\n{}", content);
+        let maybe_json = extract_json_object(&content);
+        let err = match serde_json::from_str::<InitialInstruction>(maybe_json) {
+            Ok(instruction) => return Ok(instruction),
+            Err(err) => err,
+        };
+        warn!("The answer doesn't match the instruction schemas: {}", err);
+        if retries >= max_retries {
+            return Ok(InitialInstruction::TooManyTries);
+        }
+        retries += 1;
+        // Tell the model that it sent a wrong answer
+        let error_message = chatgpt_wrong_answer(maybe_json, user_message, &err.to_string())?;
+        debug!("Error message:\n{}", error_message);
+        messages.push(ChatCompletionMessage {
+            role: ChatCompletionMessageRole::User,
+            content: Some(error_message),
+            name: None,
+            function_call: None,
+        });
+    }
+}
+
+#[test]
+fn test_completion_content() {
+    let completion = |choices: serde_json::Value| -> Completion {
+        serde_json::from_value::<ChatCompletion>(serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion",
+            "created": 0,
+            "model": DEFAULT_MODEL,
+            "choices": choices,
+        }))
+        .unwrap()
+        .into()
+    };
+
+    let answer = completion(serde_json::json!([{
+        "index": 0,
+        "finish_reason": "stop",
+        "message": {"role": "assistant", "content": "def a():\n    pass"},
+    }]));
+    assert_eq!(completion_content(&answer).unwrap(), "def a():\n    pass");
+
+    // the answer to a request with answer schemas
+    let call = completion(serde_json::json!([{
+        "index": 0,
+        "finish_reason": "function_call",
+        "message": {
+            "role": "assistant",
+            "content": null,
+            "function_call": {"name": "user_error", "arguments": "{\"answer\": \"?\"}"},
+        },
+    }]));
+    assert_eq!(completion_content(&call).unwrap(), "{\"answer\": \"?\"}");
+
+    let empty = completion(serde_json::json!([]));
+    assert!(completion_content(&empty)
+        .unwrap_err()
+        .to_string()
+        .contains("no answer"));
+}
+
+#[test]
+fn test_streamed_answer() {
+    let delta = |choices: serde_json::Value| -> ChatCompletionDelta {
+        serde_json::from_value(serde_json::json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": DEFAULT_MODEL,
+            "choices": choices,
+        }))
+        .unwrap()
+    };
+    let chunk = |content: &str| {
+        delta(serde_json::json!([{
+            "index": 0,
+            "finish_reason": null,
+            "delta": {"role": "assistant", "content": content},
+        }]))
+    };
+
+    let mut answer = StreamedAnswer::default();
+    assert_eq!(answer.push(chunk("def a():")), Some("def a():".to_string()));
+    answer.push(chunk("\n    pass"));
+    assert_eq!(
+        answer.push(delta(serde_json::json!([{
+            "index": 0,
+            "finish_reason": "stop",
+            "delta": {},
+        }]))),
+        None
+    );
+    let completion = answer.into_completion();
+    assert_eq!(
+        completion_content(&completion).unwrap(),
+        "def a():\n    pass"
+    );
+    // OpenAI doesn't report the usage of a streamed answer, it is estimated
+    let messages = [ChatCompletionMessage {
+        role: ChatCompletionMessageRole::User,
+        content: Some("add docstrings".to_string()),
+        name: None,
+        function_call: None,
+    }];
+    let usage = completion.with_estimated_usage(&messages).usage.unwrap();
+    assert_eq!(
+        (
+            usage.prompt_tokens,
+            usage.completion_tokens,
+            usage.total_tokens
+        ),
+        (4, 5, 9)
+    );
+
+    let empty = StreamedAnswer::default().into_completion();
+    assert!(completion_content(&empty).is_err());
+}
+
+#[test]
+fn test_ollama_answer() {
+    let messages = vec![ChatCompletionMessage {
+        role: ChatCompletionMessageRole::User,
+        content: Some("add docstrings".to_string()),
+        name: None,
+        function_call: None,
+    }];
+    let options = CompletionOptions {
+        temperature: Some(0.2),
+        max_tokens: Some(512),
+        stream: true,
+        ..Default::default()
+    };
+    let request = ollama_request("llama3", &messages, &options);
+    assert_eq!(request["messages"][0]["role"], "user");
+    assert_eq!(request["stream"], true);
+    assert!(request["options"]["temperature"].is_number());
+    assert_eq!(request["options"]["num_predict"], 512);
+    let request = ollama_request("llama3", &messages, &CompletionOptions::default());
+    assert!(request.get("options").is_none());
+    assert!(request.get("format").is_none());
+    let options = CompletionOptions {
+        answer_schemas: crate::instructions::instruction_schemas(),
+        ..Default::default()
+    };
+    let request = ollama_request("llama3", &messages, &options);
+    assert_eq!(request["format"], "json");
+
+    let mut answer = OllamaAnswer::default();
+    let lines = [
+        r#"{"message":{"role":"assistant","content":"def a():"},"done":false}"#,
+        r#"{"message":{"role":"assistant","content":"\n    pass"},"done":false}"#,
+        "",
+        r#"{"message":{"role":"assistant","content":""},"done":true,"prompt_eval_count":12,"eval_count":5}"#,
+    ];
+    for line in lines {
+        answer.push_line("llama3", line).unwrap();
+    }
+    let completion = answer.into_completion();
+    assert_eq!(completion.content.as_deref(), Some("def a():\n    pass"));
+    assert_eq!(completion.usage.unwrap().total_tokens, 17);
+
+    let err = OllamaAnswer::default()
+        .push_line("llama4", r#"{"error":"model 'llama4' not found"}"#)
+        .unwrap_err();
+    assert!(err.to_string().contains("not found"));
+}
+
+#[test]
+fn test_ollama_backend() {
+    let response = r#"{"message":{"role":"assistant","content":"def a():\n    pass"},"done":true,"prompt_eval_count":3,"eval_count":4}"#;
+    let (url, requests) = crate::test_server::serve_http(vec![("200 OK", response.to_string())]);
+
+    let messages = vec![ChatCompletionMessage {
+        role: ChatCompletionMessageRole::User,
+        content: Some("add docstrings".to_string()),
+        name: None,
+        function_call: None,
+    }];
+    let rt = Runtime::new().unwrap();
+    let completion = rt
+        .block_on(Ollama::new(&url).complete("llama3", &messages, &CompletionOptions::default()))
+        .unwrap();
+    assert_eq!(completion.content.as_deref(), Some("def a():\n    pass"));
+    assert_eq!(completion.usage.unwrap().prompt_tokens, 3);
+
+    let request: serde_json::Value =
+        serde_json::from_str(&requests.lock().unwrap()[0].body).unwrap();
+    assert_eq!(request["model"], "llama3");
+    assert_eq!(request["stream"], false);
+}
+
+#[test]
+fn test_anthropic_request_and_answer() {
+    let message = |role, content: &str| ChatCompletionMessage {
+        role,
+        content: Some(content.to_string()),
+        name: None,
+        function_call: None,
+    };
+    let messages = vec![
+        message(ChatCompletionMessageRole::System, "answer with JSON"),
+        message(ChatCompletionMessageRole::User, "add docstrings"),
+        message(ChatCompletionMessageRole::Assistant, "{}"),
+        message(ChatCompletionMessageRole::User, "that's not valid"),
+        message(ChatCompletionMessageRole::User, "in Python"),
+    ];
+    let request = anthropic_request(
+        "claude-3-5-sonnet-latest",
+        &messages,
+        &CompletionOptions::default(),
+    );
+    assert_eq!(request["system"], "answer with JSON");
+    assert_eq!(request["messages"].as_array().unwrap().len(), 3);
+    assert_eq!(request["messages"][2]["role"], "user");
+    assert_eq!(
+        request["messages"][2]["content"],
+        "that's not valid\n\nin Python"
+    );
+    assert!(request.get("temperature").is_none());
+    assert_eq!(request["max_tokens"], ANTHROPIC_MAX_TOKENS);
+    let options = CompletionOptions {
+        max_tokens: Some(1000),
+        ..Default::default()
+    };
+    let request = anthropic_request("claude-3-5-sonnet-latest", &messages, &options);
+    assert_eq!(request["max_tokens"], 1000);
+
+    let response = serde_json::json!({
+        "type": "message",
+        "role": "assistant",
+        "content": [{"type": "text", "text": "def a():\n    pass"}],
+        "stop_reason": "end_turn",
+        "usage": {"input_tokens": 20, "output_tokens": 8},
+    });
+    let completion = AnthropicAnswer::from_response(&response).into_completion();
+    assert_eq!(completion.content.as_deref(), Some("def a():\n    pass"));
+    assert_eq!(completion.usage.unwrap().total_tokens, 28);
+
+    let mut answer = AnthropicAnswer::default();
+    let events = [
+        serde_json::json!({"type": "message_start", "message": {"usage": {"input_tokens": 20, "output_tokens": 1}}}),
+        serde_json::json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "def a():"}}),
+        serde_json::json!({"type": "content_block_delta", "index": 0, "delta": {"type": "text_delta", "text": "\n    pass"}}),
+        serde_json::json!({"type": "message_delta", "delta": {"stop_reason": "end_turn"}, "usage": {"output_tokens": 8}}),
+    ];
+    for event in &events {
+        answer.push_event(event).unwrap();
+    }
+    let streamed = answer.into_completion();
+    assert_eq!(streamed.content, completion.content);
+    let (streamed, usage) = (streamed.usage.unwrap(), completion.usage.unwrap());
+    assert_eq!(streamed.prompt_tokens, usage.prompt_tokens);
+    assert_eq!(streamed.completion_tokens, usage.completion_tokens);
+
+    let overloaded = ApiError::from_response(
+        529,
+        r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#,
+    );
+    assert!(overloaded.is_retryable());
+    assert_eq!(overloaded.to_string(), "Overloaded (overloaded_error)");
+    assert!(!ApiError::from_response(401, "unauthorized").is_retryable());
+}
+
+#[test]
+fn test_azure_url_and_errors() {
+    assert_eq!(
+        azure_url("https://acme.openai.azure.com/", "gpt-4o", "2024-02-01"),
+        "https://acme.openai.azure.com/openai/deployments/gpt-4o/chat/completions?api-version=2024-02-01"
+    );
+    let throttled = ApiError::from_response(
+        429,
+        r#"{"error":{"code":"429","message":"Requests to the deployment have exceeded the rate limit"}}"#,
+    );
+    assert!(throttled.is_retryable());
+    assert!(throttled.to_string().contains("(429)"));
+}
+
+#[test]
+fn test_idempotency_key() {
+    use crate::test_server::{serve_http, Request};
+
+    let completion = serde_json::json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "gpt-4o",
+        "choices": [{"index": 0, "finish_reason": "stop", "message": {"role": "assistant", "content": "4"}}],
+    })
+    .to_string();
+    let rate_limited = r#"{"error":{"code":"429","message":"Rate limit"}}"#.to_string();
+    let complete = |url: String, idempotency_keys: bool| {
+        let backend = AzureOpenAi {
+            url,
+            key: "key".to_string(),
+            max_retries: 1,
+            retry_delay: Duration::ZERO,
+            timeout: None,
+            idempotency_keys,
+            client: reqwest::Client::new(),
+        };
+        let messages = [ChatCompletionMessage {
+            role: ChatCompletionMessageRole::User,
+            content: Some("2 + 2".to_string()),
+            name: None,
+            function_call: None,
+        }];
+        Runtime::new()
+            .unwrap()
+            .block_on(backend.complete("gpt-4o", &messages, &CompletionOptions::default()))
+            .unwrap()
+    };
+    let key = |request: &Request| request.header("idempotency-key").map(str::to_string);
+
+    // the retry is sent with the key of the first attempt
+    let (url, requests) = serve_http(vec![
+        ("429 Too Many Requests", rate_limited),
+        ("200 OK", completion.clone()),
+    ]);
+    assert_eq!(complete(url, true).content.as_deref(), Some("4"));
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 2);
+    assert!(key(&requests[0]).is_some());
+    assert_eq!(key(&requests[0]), key(&requests[1]));
+    drop(requests);
+
+    let (url, requests) = serve_http(vec![("200 OK", completion)]);
+    complete(url, false);
+    assert_eq!(key(&requests.lock().unwrap()[0]), None);
+}
+
+#[test]
+fn test_retry_with_backoff() {
+    use std::cell::RefCell;
+
+    let error = |error_type: &str, code: Option<&str>| -> OpenAiError {
+        serde_json::from_value(serde_json::json!({
+            "message": "failed",
+            "type": error_type,
+            "param": null,
+            "code": code,
+        }))
+        .unwrap()
+    };
+    let run = |responses: Vec<OpenAiError>, max_retries| {
+        let responses = RefCell::new(responses);
+        let requests = RefCell::new(0);
+        let delays = RefCell::new(vec![]);
+        let result = Runtime::new().unwrap().block_on(retry_with_backoff(
+            max_retries,
+            DEFAULT_RETRY_DELAY,
+            is_retryable,
+            || {
+                *requests.borrow_mut() += 1;
+                let response = responses.borrow_mut().pop();
+                async move { response.map_or(Ok("answer"), Err) }
+            },
+            |delay| {
+                delays.borrow_mut().push(delay);
+                async {}
+            },
+        ));
+        (result, requests.into_inner(), delays.into_inner())
+    };
+
+    // a 429 then a 500, the responses are popped from the end
+    let (result, requests, delays) = run(
+        vec![
+            error("server_error", None),
+            error("requests", Some("rate_limit_exceeded")),
+        ],
+        5,
+    );
+    assert_eq!(result.unwrap(), "answer");
+    assert_eq!(requests, 3);
+    assert_eq!(delays.len(), 2);
+    assert!(delays[0] >= Duration::from_millis(500) && delays[0] <= Duration::from_secs(1));
+    assert!(delays[1] >= Duration::from_secs(1) && delays[1] <= Duration::from_secs(2));
+
+    // a wrong API key fails immediately
+    let (result, requests, delays) = run(vec![error("invalid_request_error", None)], 5);
+    assert!(result.is_err());
+    assert_eq!((requests, delays.len()), (1, 0));
+
+    // gives up after max_retries
+    let (result, requests, _) = run(vec![error("server_error", None); 4], 2);
+    assert!(result.is_err());
+    assert_eq!(requests, 3);
+
+    let sequence: Vec<f64> = (0..8)
+        .map(|attempt| backoff_delay(DEFAULT_RETRY_DELAY, attempt, 1.0).as_secs_f64())
+        .collect();
+    assert_eq!(sequence, vec![1.0, 2.0, 4.0, 8.0, 16.0, 32.0, 60.0, 60.0]);
+    assert_eq!(
+        backoff_delay(DEFAULT_RETRY_DELAY, 2, 0.0),
+        Duration::from_secs(2)
+    );
+    assert_eq!(
+        backoff_delay(Duration::from_millis(100), 1, 1.0),
+        Duration::from_millis(200)
+    );
+    assert_eq!(backoff_delay(Duration::ZERO, 3, 1.0), Duration::ZERO);
+}
+
+#[test]
+fn test_parse_retry_delay() {
+    assert_eq!(parse_retry_delay("0.5"), Ok(Duration::from_millis(500)));
+    assert_eq!(parse_retry_delay("0"), Ok(Duration::ZERO));
+    assert!(parse_retry_delay("-1").is_err());
+    assert!(parse_retry_delay("soon").is_err());
+}
+
+#[test]
+fn test_parse_temperature() {
+    assert_eq!(parse_temperature("0"), Ok(0.0));
+    assert_eq!(parse_temperature("1.5"), Ok(1.5));
+    assert_eq!(parse_temperature("2.0"), Ok(2.0));
+    assert!(parse_temperature("2.1")
+        .unwrap_err()
+        .contains("between 0.0 and 2.0"));
+    assert!(parse_temperature("-0.5").is_err());
+    assert!(parse_temperature("warm")
+        .unwrap_err()
+        .contains("not a number"));
+    assert!(parse_temperature("NaN").is_err());
+}
+
+/// Answers with the given contents in order
+#[cfg(test)]
+struct ScriptedBackend {
+    answers: Mutex<Vec<String>>,
+}
+
+#[cfg(test)]
+#[async_trait]
+impl LlmBackend for ScriptedBackend {
+    async fn complete(
+        &self,
+        _model: &str,
+        _messages: &[ChatCompletionMessage],
+        _options: &CompletionOptions,
+    ) -> anyhow::Result<Completion> {
+        Ok(Completion {
+            content: Some(self.answers.lock().unwrap().remove(0)),
+            ..Default::default()
+        })
+    }
+}
+
+#[test]
+fn test_function_call() {
+    let schemas = crate::instructions::instruction_schemas();
+    assert_eq!(function_call(&schemas), "auto");
+    assert_eq!(function_call(&schemas[..1])["name"], schemas[0].name);
+    let function = schemas[0].function();
+    assert_eq!(function.parameters.unwrap()["type"], "object");
+}
 
 #[test]
+fn test_request_instruction() {
+    let client = |answers: Vec<String>| {
+        LlmClient::new(
+            Box::new(ScriptedBackend {
+                answers: Mutex::new(answers),
+            }),
+            DEFAULT_MODEL,
+            UsageTracker::new(Default::default(), None),
+        )
+    };
+    let good = crate::instructions::good_instruction_example().unwrap();
+    let bad = "{\"item\": \"Cobol\"}".to_string();
+    let rt = Runtime::new().unwrap();
+
+    // two wrong answers, then the model gets it right
+    let llm = client(vec![bad.clone(), bad.clone(), good]);
+    let mut messages = vec![];
+    let instruction = rt
+        .block_on(request_instruction(
+            &llm,
+            &mut messages,
+            "document the functions",
+            &CompletionOptions::default(),
+            DEFAULT_MAX_INSTRUCTION_RETRIES,
+        ))
+        .unwrap();
+    assert!(matches!(
+        instruction,
+        InitialInstruction::GoodInstructions(_)
+    ));
+    // the model was told about both mistakes
+    assert_eq!(messages.len(), 2);
+    assert!(messages[0]
+        .content
+        .as_deref()
+        .unwrap()
+        .contains("Doesn't match the schemas"));
+
+    // still wrong after the retries
+    let llm = client(vec![bad.clone(), bad.clone()]);
+    let instruction = rt
+        .block_on(request_instruction(
+            &llm,
+            &mut vec![],
+            "document the functions",
+            &CompletionOptions::default(),
+            1,
+        ))
+        .unwrap();
+    assert!(matches!(instruction, InitialInstruction::TooManyTries));
+}
+
+#[test]
+fn test_request_timeout() {
+    let complete = |delays: Vec<Duration>, max_retries| {
+        let delays = Mutex::new(delays);
+        Runtime::new().unwrap().block_on(retry_with_backoff(
+            max_retries,
+            DEFAULT_RETRY_DELAY,
+            |err: &AttemptError<ApiError>| err.is_retryable(ApiError::is_retryable),
+            || {
+                let delay = delays.lock().unwrap().remove(0);
+                attempt(Some(Duration::from_millis(50)), async move {
+                    tokio::time::sleep(delay).await;
+                    Ok("ok")
+                })
+            },
+            |_| async {},
+        ))
+    };
+
+    // the stuck attempt is abandoned and sent again
+    let slow_then_fast = vec![Duration::from_secs(10), Duration::ZERO];
+    assert_eq!(complete(slow_then_fast, 1).unwrap(), "ok");
+
+    let err = complete(vec![Duration::from_secs(10)], 0).unwrap_err();
+    let err = err.into_error(DEFAULT_MODEL);
+    assert!(err.is::<RequestTimeout>(), "{}", err);
+}
+
+#[test]
+fn test_retries_are_counted_per_request() {
+    let flaky = RETRIES.scope(Cell::new(0), async {
+        let failures = Cell::new(1);
+        retry_with_backoff(
+            3,
+            DEFAULT_RETRY_DELAY,
+            |_: &String| true,
+            || {
+                let result = match failures.replace(0) {
+                    0 => Ok(()),
+                    _ => Err("busy".to_string()),
+                };
+                async move { result }
+            },
+            |_| async {},
+        )
+        .await
+        .unwrap();
+        RETRIES.with(Cell::get)
+    });
+    let steady = RETRIES.scope(Cell::new(0), async { RETRIES.with(Cell::get) });
+    let counts = Runtime::new()
+        .unwrap()
+        .block_on(async { futures::join!(flaky, steady) });
+    assert_eq!(counts, (1, 0));
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_rate_limiter() {
+    // 600 requests per minute, one every 100ms
+    let limiter = RateLimiter::new(NonZeroU32::new(600).unwrap());
+    let start = Instant::now();
+    // the concurrent callers take turns, the first token is available right away
+    futures::future::join_all((0..5).map(|_| limiter.acquire())).await;
+    assert_eq!(start.elapsed(), Duration::from_millis(400));
+}
+
+#[test]
+fn test_concurrency_limiter() {
+    let limiter = ConcurrencyLimiter::new(3);
+    Runtime::new().unwrap().block_on(async {
+        assert_eq!(limiter.limit(), 1);
+        // one more request after `limit` successes in a row, up to the maximum
+        for expected in [2, 2, 3, 3, 3, 3, 3] {
+            let permit = limiter.acquire().await;
+            limiter.release(permit, false);
+            assert_eq!(limiter.limit(), expected);
+        }
+
+        // the requests throttled together halve the limit once
+        let first = limiter.acquire().await;
+        let second = limiter.acquire().await;
+        limiter.release(first, true);
+        assert_eq!(limiter.limit(), 1);
+        limiter.release(second, true);
+        assert_eq!(limiter.limit(), 1);
+
+        // a request waits for a running one to end
+        let running = limiter.acquire().await;
+        let waiting = limiter.acquire();
+        futures::pin_mut!(waiting);
+        assert!(futures::poll!(waiting.as_mut()).is_pending());
+        limiter.release(running, false);
+        let permit = waiting.await;
+        limiter.release(permit, false);
+    });
+}
+
+#[test]
+#[ignore = "calls the OpenAI API, needs OPENAI_API_KEY"]
 fn main_test() {
     // Create a new runtime
     let rt = Runtime::new().unwrap();
@@ -95,7 +2032,17 @@ fn main_test() {
         );
         let default_output = "Unable to retrieve Python code.".to_string();
 
-        match process_chat_prompt(&prompt, default_output).await {
+        crate::utils::load_env_variables(Backend::OpenAi, false);
+        let llm = LlmClient::new(
+            Box::new(OpenAi {
+                max_retries: 0,
+                retry_delay: DEFAULT_RETRY_DELAY,
+                timeout: None,
+            }),
+            DEFAULT_MODEL,
+            UsageTracker::new(Default::default(), None),
+        );
+        match process_chat_prompt(&prompt, default_output, &llm, None).await {
             Ok(result) => println!("Result:\n{}", result),
             Err(e) => println!("An error occurred: {}", e),
         }
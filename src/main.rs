@@ -2,45 +2,605 @@ extern crate colored;
 
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{stdin, stdout, Read, Write};
+use std::io::{stdin, stdout, IsTerminal, Read, Write};
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use std::thread::Scope;
+use std::time::{Duration, Instant};
 use std::{env, fs};
 
 use anyhow::{anyhow, bail, Result};
-use clap::{Parser as ClapParser, Subcommand};
+use clap::builder::NonEmptyStringValueParser;
+use clap::{CommandFactory, FromArgMatches, Parser as ClapParser, Subcommand, ValueEnum};
 use colored::Colorize;
 use dotenv::dotenv;
-use openai::chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole};
+use futures::stream::{self, StreamExt};
+use openai::chat::{ChatCompletionFunctionCall, ChatCompletionMessage, ChatCompletionMessageRole};
 use openai::set_key;
+use regex::Regex;
 use schemars::schema_for;
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
 use tree_sitter::{Language, Node, Parser, Query, QueryCursor};
 
-use crate::instructions::{all_instruction_examples, GoodInstructions, InitialInstruction};
-use crate::lang::{ProgItem, ProgLanguage, PythonProgItem};
+use crate::actions::BuiltinAction;
+use crate::cache::{ResponseCache, DEFAULT_CACHE_DIR};
+use crate::code_cleaning::{
+    extract_answer_code, preserve_type_comments, strip_doc_comment, to_doc_comment, type_comments,
+};
+use crate::detector::Detector;
+use crate::filter_script::FilterScript;
+use crate::format::Formatter;
+use crate::history::DEFAULT_HISTORY_FILE;
+use crate::hunks::{changed_line_ranges, HunkOverlap};
+use crate::instructions::{
+    all_instruction_examples, instruction_schemas, GoodInstructions, InitialInstruction,
+};
+use crate::lang::{ProgItem, ProgLanguage, PythonProgItem, RustProgItem};
+use crate::llm::{
+    Anthropic, AzureOpenAi, Backend, CompletionOptions, LlmBackend, LlmClient, Ollama, OpenAi,
+    RequestTimeout,
+};
 use crate::prompts::{
-    chatgpt_wrong_answer, chatgpt_wrong_code_proposal, get_system_prompt,
+    chatgpt_wrong_code_proposal, get_system_prompt, structural_regression_prompt,
     user_action_to_chatgpt_prompt, wrap_user_message,
 };
+use crate::report::{
+    colored_diff, diff_line_count, print_change_report, ChangeReportEntry, ReportOrder, RunReport,
+};
 use crate::search::{
-    apply_changes, extract_all_items_from_files, get_filenames, parse_code, ItemChange,
+    apply_changes, compute_file_changes, extract_all_items_from_directory,
+    extract_all_items_from_files, extract_class_methods, file_count_warning,
+    filter_files_by_content, filter_items_by_lines, filter_items_by_name, get_filenames, item_name,
+    merge_file_lists, parse_code, parse_code_checked, parse_code_with_filename, restore_backups,
+    select_one_file, structural_divergence, unified_patch, ApplyOptions, ItemChange, ItemDef,
+    PathGlobs,
 };
+use crate::transforms::TransformRegistry;
+use crate::usage::{estimate_tokens, ModelPrice, PriceTable, RunEstimate, UsageTracker};
 
+mod actions;
+mod cache;
 mod code_cleaning;
+mod config;
+mod detector;
+mod filter_script;
+mod format;
+mod git;
+mod history;
+mod hunks;
 mod instructions;
+mod inventory;
 mod lang;
 mod llm;
 mod prompts;
+mod report;
 mod search;
+#[cfg(test)]
+mod test_server;
+mod transforms;
+mod usage;
 mod utils;
+mod verify;
+
+#[derive(ClapParser, Debug)]
+#[command(author, version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// OpenAI model used for every request, e.g. gpt-4o
+    #[arg(long, default_value = llm::DEFAULT_MODEL, value_parser = NonEmptyStringValueParser::new())]
+    model: String,
+    /// Print the composed transform prompt for every item instead of sending it to the model.
+    /// Needs --task and --item and works without an API key
+    #[arg(long, requires_all = ["task", "item"])]
+    prompt_only: bool,
+    /// Write each prompt to a separate file in this directory instead of printing it
+    #[arg(long, requires = "prompt_only")]
+    prompt_dir: Option<PathBuf>,
+    /// The change to apply to every item, e.g. "add docstrings"
+    #[arg(long)]
+    task: Option<String>,
+    /// Sampling temperature of the requests rewriting the items, between 0.0 and 2.0. The
+    /// requests understanding the instruction always use 0.2
+    #[arg(long, value_parser = llm::parse_temperature)]
+    temperature: Option<f32>,
+    /// Limit on the length of the rewritten items, in tokens. Anthropic defaults to 4096
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_tokens: Option<u32>,
+    /// Instructions added to every prompt, e.g. "Always keep the existing comments."
+    #[arg(long)]
+    prompt_extra: Option<String>,
+    /// Item kind to target, e.g. Python.Function or Rust.Struct, or Rust.All for every kind
+    #[arg(long)]
+    item: Option<ProgItem>,
+    /// Folder to scan for items (defaults to the current directory). Repeatable, a file in
+    /// several of the folders is processed once
+    #[arg(long)]
+    folder: Vec<String>,
+    /// Only scan files whose content matches this regex, e.g. "import asyncio"
+    #[arg(long)]
+    file_contains: Option<Regex>,
+    /// Only process items whose code matches this regex
+    #[arg(long)]
+    body_contains: Option<Regex>,
+    /// Only process items whose name matches this regex, e.g. "^test_". Unnamed items like
+    /// `impl` blocks are skipped
+    #[arg(long)]
+    name: Option<Regex>,
+    /// Skip items inside a class, impl, trait or module whose header matches this regex,
+    /// e.g. "impl Drop" or "TestCase". Repeatable
+    #[arg(long)]
+    exclude_in: Vec<Regex>,
+    /// Only process items with at least this cyclomatic complexity
+    #[arg(long)]
+    min_complexity: Option<usize>,
+    /// Only process items with at most this cyclomatic complexity
+    #[arg(long)]
+    max_complexity: Option<usize>,
+    /// Only process items for which the Python `include(item: dict) -> bool` function in this
+    /// file returns True. The item has the keys definition, name, filename, start_line, end_line
+    /// and complexity
+    #[arg(long)]
+    filter_script: Option<PathBuf>,
+    /// Transform Python classes longer than this many lines method by method instead of as a
+    /// whole. The class statement, class attributes and docstring are left untouched
+    #[arg(long)]
+    max_class_lines: Option<usize>,
+    /// Items estimated to be larger than this many tokens are skipped instead of sent to a model
+    /// whose context they wouldn't fit, Python classes are transformed method by method instead
+    #[arg(long, default_value_t = usage::DEFAULT_MAX_ITEM_TOKENS)]
+    max_item_tokens: usize,
+    /// Only process items changed since this git revision (e.g. HEAD or main), including
+    /// uncommitted changes
+    #[arg(long)]
+    since: Option<String>,
+    /// How much of an item must be changed to be processed with --since
+    #[arg(long, value_enum, default_value_t = HunkOverlap::Any, requires = "since")]
+    hunk_overlap: HunkOverlap,
+    /// Only process the files added or modified in the git index, e.g. from a pre-commit hook
+    #[arg(long, conflicts_with = "since")]
+    staged: bool,
+    /// Also scan files excluded by .gitignore
+    #[arg(long)]
+    no_gitignore: bool,
+    /// Don't follow symlinks while scanning, e.g. to a vendored directory. Directories reached
+    /// through several symlinks are scanned once either way
+    #[arg(long)]
+    no_follow_symlinks: bool,
+    /// Only scan files matching this glob, relative to the folder (e.g. `src/api/**`).
+    /// Repeatable
+    #[arg(long)]
+    include: Vec<String>,
+    /// Skip files matching this glob, relative to the folder (e.g. `*_test.py`). Repeatable,
+    /// wins over --include
+    #[arg(long)]
+    exclude: Vec<String>,
+    /// Skip the directories with this name (e.g. `proto`), in addition to the ones of the
+    /// language and of .mechatyper.toml. Repeatable
+    #[arg(long = "exclude-dir", value_name = "NAME")]
+    exclude_dirs: Vec<String>,
+    /// Ask for confirmation when a folder contains more matching files than this
+    #[arg(long, default_value_t = 500)]
+    max_files: usize,
+    /// Don't ask for confirmation before large scans
+    #[arg(long, short = 'y')]
+    yes: bool,
+    /// Log more details, -v for the prompts and answers, -vv for everything. RUST_LOG takes
+    /// precedence
+    #[arg(long, short = 'v', action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// Only process a single file (the given one, or the first file with matching items),
+    /// show its diff and stop. A run from the command line exits with code 3 when other files
+    /// are left to process
+    #[arg(long, num_args = 0..=1, value_name = "FILE")]
+    one_file: Option<Option<PathBuf>>,
+    /// With --one-file, only process the items overlapping the lines from this one (1-based)
+    #[arg(long, requires = "one_file")]
+    line_start: Option<usize>,
+    /// With --one-file, only process the items overlapping the lines up to this one (inclusive)
+    #[arg(long, requires = "one_file")]
+    line_end: Option<usize>,
+    /// Only send items for which the Python `detect(code: str) -> bool` function in this file
+    /// returns True
+    #[arg(long, conflicts_with = "save_detector")]
+    detector: Option<PathBuf>,
+    /// Generate a detector for the task, use it and save it to this file for later runs
+    #[arg(long)]
+    save_detector: Option<PathBuf>,
+    /// Send every item to the model instead of prefiltering them with a detector generated for
+    /// the task
+    #[arg(long, conflicts_with_all = ["detector", "save_detector"])]
+    no_prefilter: bool,
+    /// Warn when the structure of the new code diverges more than this (0.0-1.0) from the original
+    #[arg(long, default_value_t = 0.5)]
+    structure_threshold: f64,
+    /// Retry (and eventually skip) changes that diverge beyond --structure-threshold instead of
+    /// only warning about them
+    #[arg(long)]
+    retry_on_structural_regression: bool,
+    /// Accept changes that rename the item. By default they are rejected because the callers
+    /// are not updated
+    #[arg(long)]
+    allow_rename: bool,
+    /// Order of the change report shown before the changes are applied
+    #[arg(long, value_enum, default_value_t = ReportOrder::ByFile)]
+    sort_report: ReportOrder,
+    /// Show every change and ask whether to apply it (all are accepted when stdin is not a
+    /// terminal)
+    #[arg(long)]
+    interactive: bool,
+    /// Run the formatter of the language (rustfmt, black, prettier or gofmt) on the changed files
+    #[arg(long)]
+    format: bool,
+    /// Formatter command used by --format instead of the default one, e.g. "ruff format"
+    #[arg(long, requires = "format")]
+    formatter: Option<String>,
+    /// Run `cargo check` in the repository after changing Rust code and revert the changes when
+    /// it fails. With --dry-run the check runs on a temporary copy
+    #[arg(long)]
+    verify_build: bool,
+    /// Commit the changed files on the current branch, with the task as commit message. Refuses
+    /// to run when other changes are staged
+    #[arg(long, conflicts_with_all = ["dry_run", "patch"])]
+    commit: bool,
+    /// Create this branch at HEAD and switch to it before changing anything. Needs a clean
+    /// working directory
+    #[arg(long)]
+    branch: Option<String>,
+    /// Reset the --branch to HEAD when it already exists
+    #[arg(long, requires = "branch")]
+    branch_force: bool,
+    /// Print the diff of the changes instead of writing them to the files
+    #[arg(long)]
+    dry_run: bool,
+    /// Copy every changed file to `<filename>.mechatyper.bak` first (an existing backup is kept
+    /// and a numbered one is written)
+    #[arg(long)]
+    backup: bool,
+    /// When two changes overlap (e.g. a class and one of its methods) keep the outer one
+    /// instead of stopping with an error
+    #[arg(long)]
+    skip_overlaps: bool,
+    /// Write the changes as a patch for `git apply` to this file instead of changing the files
+    #[arg(long)]
+    patch: Option<PathBuf>,
+    /// Write aggregated statistics of each run (items, tokens, cost, timing) to this JSON file
+    #[arg(long)]
+    summary_json: Option<PathBuf>,
+    /// `json` prints a single JSON document with the instruction, the outcome of every item and
+    /// the token usage instead of the text reports
+    #[arg(long, value_enum, default_value_t = RunOutput::Text, conflicts_with_all = ["dry_run", "interactive", "stream"])]
+    output: RunOutput,
+    /// Apply a deterministic transform to matching items instead of asking the model
+    /// (trim-trailing-whitespace, normalize-quotes). Repeatable. Without --task or --action
+    /// other items are left unchanged
+    #[arg(long)]
+    transform: Vec<String>,
+    /// Run a built-in action on every matching item instead of starting the interactive session
+    #[arg(long, value_enum, conflicts_with_all = ["task", "prompt_only"])]
+    action: Option<BuiltinAction>,
+    /// Number of items sent to the model at the same time
+    #[arg(long, default_value = "4")]
+    concurrency: NonZeroUsize,
+    /// Start with one item at a time and send more at the same time until the service rate
+    /// limits the requests, then back off. The number it settles on is reported at the end
+    #[arg(long, conflicts_with_all = ["concurrency", "stream"])]
+    auto_concurrency: bool,
+    /// Send at most this many requests per minute, however many run at the same time. Also
+    /// paces the requests made to understand the instruction
+    #[arg(long)]
+    rpm: Option<NonZeroU32>,
+    /// How many times a rate limited or failed request is retried, with exponential backoff
+    #[arg(long, default_value_t = llm::DEFAULT_MAX_RETRIES)]
+    max_retries: u32,
+    /// Seconds to wait before the first retry of a request, doubled for each further retry up
+    /// to a minute
+    #[arg(long, default_value = "1", value_parser = llm::parse_retry_delay)]
+    retry_delay: Duration,
+    /// Don't send an Idempotency-Key with the requests. With it, Azure and Anthropic don't
+    /// charge again for a retried request they already answered
+    #[arg(long)]
+    no_idempotency_keys: bool,
+    /// Give up on an attempt of a request without an answer after this many seconds. It is
+    /// retried like a failed request, and an item whose attempts all time out is skipped. With
+    /// --stream it limits the wait for the answer to start
+    #[arg(long, default_value_t = NonZeroU64::new(llm::DEFAULT_REQUEST_TIMEOUT).unwrap())]
+    request_timeout: NonZeroU64,
+    /// How many times an answer that can't be understood as an instruction is sent back to the
+    /// model to be corrected
+    #[arg(long, default_value_t = llm::DEFAULT_MAX_INSTRUCTION_RETRIES)]
+    max_instruction_retries: u32,
+    /// Always ask the model instead of reusing the responses cached in .mechatyper_cache/
+    #[arg(long)]
+    no_cache: bool,
+    /// Print the answers for the items as they arrive. Items are sent one at a time and the
+    /// token usage of OpenAI answers is estimated
+    #[arg(long)]
+    stream: bool,
+    /// Stop the run once the estimated cost of the requests exceeds this many USD
+    #[arg(long)]
+    budget: Option<f64>,
+    /// Price of a model in USD per 1000 prompt and completion tokens, e.g.
+    /// `--price gpt-4o=0.0025,0.01`. Repeatable, overrides the built-in prices
+    #[arg(long, value_name = "MODEL=PROMPT,COMPLETION")]
+    price: Vec<ModelPrice>,
+    /// Where the requests go. With ollama, --model is the name of a local model, e.g. llama3
+    #[arg(long, value_enum, default_value_t)]
+    backend: Backend,
+    /// Address of the Ollama server
+    #[arg(long, default_value = llm::DEFAULT_OLLAMA_URL)]
+    ollama_url: String,
+    /// Send the OpenAI requests to this Azure OpenAI resource, e.g.
+    /// https://<resource>.openai.azure.com, with the key from AZURE_OPENAI_KEY
+    #[arg(long, requires = "azure_deployment")]
+    azure_endpoint: Option<String>,
+    /// The Azure deployment to use, its name is also used as the model name
+    #[arg(long, requires = "azure_endpoint")]
+    azure_deployment: Option<String>,
+    /// The `api-version` of the Azure OpenAI requests
+    #[arg(long, default_value = llm::DEFAULT_AZURE_API_VERSION)]
+    azure_api_version: String,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Count and locate the extractable items without changing anything or calling the model
+    Inventory {
+        /// Item kinds to list, e.g. Python.Function (repeatable)
+        #[arg(long)]
+        item: Vec<ProgItem>,
+        /// List every item kind of these languages (repeatable)
+        #[arg(long)]
+        language: Vec<ProgLanguage>,
+        /// Folder to scan (defaults to the current directory)
+        #[arg(long, default_value = ".")]
+        folder: PathBuf,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Table)]
+        format: OutputFormat,
+        /// Write the inventory to this file instead of stdout
+        #[arg(long)]
+        out: Option<PathBuf>,
+        /// Also write a ctags compatible `tags` file of the named items to this path
+        #[arg(long)]
+        ctags: Option<PathBuf>,
+    },
+    /// List the items a task would target, with their location and first line, without
+    /// calling the model
+    List {
+        /// Item kind to list, e.g. Python.Function or Rust.Struct
+        #[arg(long)]
+        item: ProgItem,
+        /// Folder to scan (defaults to the current directory)
+        #[arg(long, default_value = ".")]
+        folder: PathBuf,
+    },
+    /// Estimate the tokens and the cost of a task per file, from the items it would target,
+    /// without calling the model
+    Estimate {
+        /// The change to apply to every item, e.g. "add docstrings"
+        #[arg(long)]
+        task: String,
+        /// Item kind to target, e.g. Python.Function or Rust.Struct
+        #[arg(long)]
+        item: ProgItem,
+        /// Folder to scan for items (defaults to the current directory). Repeatable
+        #[arg(long)]
+        folder: Vec<String>,
+    },
+    /// Remove the cached model responses
+    ClearCache,
+    /// Restore the files changed by the last run made with --backup and remove its backups.
+    /// Refuses when a file was edited since the run
+    Undo,
+    /// Apply a task to every item without the interactive session, for scripts and CI. Exits
+    /// with code 1 when some items could not be changed
+    Run {
+        /// The change to apply to every item, e.g. "add docstrings"
+        #[arg(long)]
+        task: String,
+        /// Item kind to target, e.g. Python.Function or Rust.Struct
+        #[arg(long)]
+        item: ProgItem,
+        /// Folder to scan for items (defaults to the current directory). Repeatable
+        #[arg(long)]
+        folder: Vec<String>,
+    },
+}
+
+impl Cli {
+    /// The folder the run works on, the first `--folder` of the subcommand or of the session.
+    /// `.mechatyper.toml` is looked up in its repository
+    fn target_folder(&self) -> PathBuf {
+        let folder = match &self.command {
+            Some(Command::Inventory { folder, .. } | Command::List { folder, .. }) => {
+                return folder.clone()
+            }
+            Some(Command::Run { folder, .. } | Command::Estimate { folder, .. }) => folder.first(),
+            _ => self.folder.first(),
+        };
+        PathBuf::from(folder.map_or(".", String::as_str))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    Table,
+    Json,
+}
+
+/// How the result of a run is printed, `--output`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum RunOutput {
+    Text,
+    /// a `RunReport`, for other tools
+    Json,
+}
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    utils::load_env_variables();
+    let matches = Cli::command().get_matches();
+    let mut cli = Cli::from_arg_matches(&matches)?;
+    init_logging(cli.verbose);
+    let folder = cli.target_folder();
+    if let Some(repo_root) = utils::find_git_directory(fs::canonicalize(&folder).unwrap_or(folder))
+    {
+        config::load_config(&repo_root)?.apply(&mut cli, &matches);
+    }
+
+    if let Some(Command::Inventory {
+        item,
+        language,
+        folder,
+        format,
+        out,
+        ctags,
+    }) = &cli.command
+    {
+        let mut items = item.clone();
+        items.extend(language.iter().flat_map(|language| language.items()));
+        if items.is_empty() {
+            return Err("Select the items to list with --item or --language".into());
+        }
+        let inventory = inventory::build_inventory(folder, &items)?;
+        if let Some(path) = ctags {
+            fs::write(path, inventory.to_ctags())?;
+        }
+        let output = match format {
+            OutputFormat::Table => inventory.to_table(),
+            OutputFormat::Json => serde_json::to_string_pretty(&inventory)?,
+        };
+        match out {
+            Some(path) => fs::write(path, output)?,
+            None => print!("{}", output),
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::List { item, folder }) = &cli.command {
+        let items = extract_all_items_from_directory(folder, item.clone().into(), item.clone())?;
+        print!("{}", inventory::item_list(&items));
+        return Ok(());
+    }
+
+    if let Some(Command::Undo) = &cli.command {
+        for path in history::undo_last_run(Path::new(DEFAULT_HISTORY_FILE))? {
+            println!("Restored {:?}", path);
+        }
+        return Ok(());
+    }
+
+    if let Some(Command::Estimate { task, item, folder }) = &cli.command {
+        let instructions = GoodInstructions::from_command_line(item.clone(), task.clone(), folder);
+        let estimate = estimate_run(&instructions, &cli)?;
+        print!(
+            "{}",
+            estimate.to_table(&cli.model, &PriceTable::with_overrides(&cli.price))
+        );
+        return Ok(());
+    }
+
+    if let Some(Command::ClearCache) = &cli.command {
+        ResponseCache::new(DEFAULT_CACHE_DIR).clear()?;
+        println!("Removed {}", DEFAULT_CACHE_DIR);
+        return Ok(());
+    }
+
+    if cli.prompt_only {
+        let instructions = GoodInstructions::from_command_line(
+            cli.item
+                .clone()
+                .expect("--item is required by --prompt-only"),
+            cli.task
+                .clone()
+                .expect("--task is required by --prompt-only"),
+            &cli.folder,
+        );
+        write_prompts(&instructions, &cli)?;
+        return Ok(());
+    }
+
+    if cli.azure_endpoint.is_some() && cli.backend != Backend::OpenAi {
+        return Err("--azure-endpoint can only be used with --backend openai".into());
+    }
+    utils::load_env_variables(cli.backend, cli.azure_endpoint.is_some());
+    let mut model = cli.model.clone();
+    let timeout = Duration::from_secs(cli.request_timeout.get());
+    let backend: Box<dyn LlmBackend> = match (cli.backend, &cli.azure_endpoint) {
+        (Backend::OpenAi, Some(endpoint)) => {
+            let deployment = cli
+                .azure_deployment
+                .as_deref()
+                .expect("--azure-deployment is required by --azure-endpoint");
+            model = deployment.to_string();
+            Box::new(
+                AzureOpenAi::from_env(
+                    endpoint,
+                    deployment,
+                    &cli.azure_api_version,
+                    cli.max_retries,
+                )?
+                .with_retry_delay(cli.retry_delay)
+                .with_timeout(timeout)
+                .with_idempotency_keys(!cli.no_idempotency_keys),
+            )
+        }
+        (Backend::OpenAi, None) => Box::new(OpenAi {
+            max_retries: cli.max_retries,
+            retry_delay: cli.retry_delay,
+            timeout: Some(timeout),
+        }),
+        (Backend::Ollama, _) => Box::new(Ollama::new(&cli.ollama_url).with_timeout(timeout)),
+        (Backend::Anthropic, _) => Box::new(
+            Anthropic::from_env(cli.max_retries)?
+                .with_retry_delay(cli.retry_delay)
+                .with_timeout(timeout)
+                .with_idempotency_keys(!cli.no_idempotency_keys),
+        ),
+    };
+    let usage = UsageTracker::new(PriceTable::with_overrides(&cli.price), cli.budget);
+    let llm = LlmClient::new(backend, &model, usage)
+        .with_rpm(cli.rpm)
+        .with_auto_concurrency(cli.auto_concurrency.then_some(llm::MAX_AUTO_CONCURRENCY));
+
+    if let Some(Command::Run { task, item, folder }) = &cli.command {
+        let instructions = GoodInstructions::from_command_line(item.clone(), task.clone(), folder);
+        let report = make_change(instructions, &cli, &llm).await?;
+        exit_when_files_remain(&report);
+        if report.summary.failed > 0 {
+            std::process::exit(ITEMS_FAILED_EXIT_CODE);
+        }
+        return Ok(());
+    }
+
+    if let Some(action) = cli.action {
+        let item = cli.item.clone().unwrap_or_else(|| action.default_item());
+        if !action.supports(&item) {
+            return Err(format!("The {:?} action cannot be applied to {}", action, item).into());
+        }
+        let instructions =
+            GoodInstructions::from_command_line(item, action.task().to_string(), &cli.folder);
+        exit_when_files_remain(&make_change(instructions, &cli, &llm).await?);
+        return Ok(());
+    }
+
+    if !cli.transform.is_empty() {
+        let Some(item) = cli.item.clone() else {
+            return Err("--transform needs --item".into());
+        };
+        let instructions = GoodInstructions::from_command_line(
+            item,
+            cli.task.clone().unwrap_or_default(),
+            &cli.folder,
+        );
+        exit_when_files_remain(&make_change(instructions, &cli, &llm).await?);
+        return Ok(());
+    }
 
     utils::print_introduction();
 
-    let system_prompt = get_system_prompt()?;
+    let system_prompt = get_system_prompt(cli.prompt_extra.as_deref())?;
     let mut messages = vec![ChatCompletionMessage {
         role: ChatCompletionMessageRole::System,
         content: Some(system_prompt.clone()),
@@ -49,14 +609,25 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     }];
 
     loop {
-        let user_message_content = utils::get_user_input("User")?;
+        let Some(user_message_content) = utils::get_user_input("User")? else {
+            mechatype_answer("Goodbye!");
+            break;
+        };
         messages.push(create_chat_message(
             ChatCompletionMessageRole::User,
             Some(user_message_content.clone()),
             None,
         ));
 
-        if !process_user_message(&user_message_content, &mut messages, &system_prompt).await? {
+        if !process_user_message(
+            &user_message_content,
+            &mut messages,
+            &system_prompt,
+            &cli,
+            &llm,
+        )
+        .await?
+        {
             break;
         }
     }
@@ -68,84 +639,54 @@ async fn process_user_message(
     user_message_content: &str,
     messages: &mut Vec<ChatCompletionMessage>,
     system_prompt: &str,
+    cli: &Cli,
+    llm: &LlmClient,
 ) -> Result<bool, Box<dyn std::error::Error>> {
-    let mut tries = 0;
-
-    while tries == 0 {
-        let chat_completion = ChatCompletion::builder("gpt-3.5-turbo-16k-0613", messages.clone())
-            .temperature(0.2)
-            .create()
-            .await?;
+    // the message the model answers, the answer of the user to a clarification question after
+    // the first request
+    let mut user_message = user_message_content.to_string();
+    loop {
+        let instruction = llm::request_instruction(
+            llm,
+            messages,
+            &user_message,
+            &instruction_options(),
+            cli.max_instruction_retries,
+        )
+        .await?;
 
-        if let Some(returned_message) = chat_completion.choices.first() {
-            let maybe_json = returned_message.message.content.as_ref().unwrap().trim();
-            // println!("Raw answer:\n{}", maybe_json);
-            let instructions: Result<InitialInstruction> =
-                serde_json::from_str(maybe_json).map_err(|e| anyhow!(e));
+        match instruction {
+            InitialInstruction::GoodInstructions(good_instructions) => {
+                mechatype_answer(&good_instructions.answer);
+                make_change(good_instructions, cli, llm).await?;
+                break;
+            }
+            InitialInstruction::UserError(user_error) => {
+                mechatype_answer(&user_error.answer.red());
+                break;
+            }
+            InitialInstruction::ClarificationNeeded(clarification) => {
+                mechatype_answer(&clarification.answer.red());
 
-            match instructions {
-                Ok(InitialInstruction::GoodInstructions(good_instructions)) => {
-                    mechatype_answer(&good_instructions.answer);
-                    make_change(good_instructions).await?;
-                    break;
-                }
-                Ok(InitialInstruction::UserError(user_error)) => {
-                    mechatype_answer(&user_error.answer.red());
-                    break;
-                }
-                Ok(InitialInstruction::ClarificationNeeded(mut clarification)) => {
-                    // Inner loop for clarification
-                    loop {
-                        mechatype_answer(&clarification.answer.red());
-
-                        let clarification_content = utils::get_user_input("User")?;
-
-                        messages.push(create_chat_message(
-                            ChatCompletionMessageRole::User,
-                            Some(clarification_content),
-                            None,
-                        ));
-
-                        let chat_completion =
-                            ChatCompletion::builder("gpt-3.5-turbo-16k-0613", messages.clone())
-                                .temperature(0.2)
-                                .create()
-                                .await?;
-
-                        if let Some(returned_message) = chat_completion.choices.first() {
-                            let maybe_json =
-                                returned_message.message.content.as_ref().unwrap().trim();
-                            match serde_json::from_str::<InitialInstruction>(maybe_json) {
-                                Ok(InitialInstruction::ClarificationNeeded(new_clarification)) => {
-                                    clarification = new_clarification;
-                                }
-                                _ => break, // Break the inner loop if we have any other type of instruction.
-                            }
-                        }
-                    }
-                }
-                Ok(InitialInstruction::Quit) => {
+                let Some(clarification_content) = utils::get_user_input("User")? else {
+                    mechatype_answer("Goodbye!");
                     return Ok(false);
-                }
-                Ok(InitialInstruction::TooManyTries) => {
-                    mechatype_answer("Too many tries. Try to rephrase your query.");
-                    break;
-                }
-                Err(err) => {
-                    // Tell chat model that it sent a wrong answer
-                    let error_message = chatgpt_wrong_answer(
-                        maybe_json,
-                        &user_message_content,
-                        err.to_string().as_str(),
-                    )?;
-                    println!("Error message:\n{}", error_message);
-                    messages.push(create_chat_message(
-                        ChatCompletionMessageRole::User,
-                        Some(error_message),
-                        None,
-                    ));
-                    tries += 1;
-                }
+                };
+
+                // the answer is parsed like the first message, on the next iteration
+                messages.push(create_chat_message(
+                    ChatCompletionMessageRole::User,
+                    Some(clarification_content.clone()),
+                    None,
+                ));
+                user_message = clarification_content;
+            }
+            InitialInstruction::Quit => {
+                return Ok(false);
+            }
+            InitialInstruction::TooManyTries => {
+                mechatype_answer("Too many tries. Try to rephrase your query.");
+                break;
             }
         }
     }
@@ -160,119 +701,903 @@ async fn process_user_message(
     Ok(true)
 }
 
+/// Logs go to stderr, so that the answers and reports on stdout can be piped
+fn init_logging(verbose: u8) {
+    let level = match verbose {
+        0 => "info",
+        1 => "debug",
+        _ => "trace",
+    };
+    let filter = EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(format!("mechatyper={}", level)));
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_target(false)
+        .init();
+}
+
+/// The instructions are parsed from JSON, a low temperature keeps the answers to the format
+fn instruction_options() -> CompletionOptions {
+    CompletionOptions {
+        temperature: Some(0.2),
+        answer_schemas: instruction_schemas(),
+        ..Default::default()
+    }
+}
+
 fn mechatype_answer(text: &str) {
     println!("{}: {}", "MechaTyper".green().bold(), text.green());
 }
 
+/// `function_call` is the call an assistant message asks for, when using OpenAI function calling
 fn create_chat_message(
     role: ChatCompletionMessageRole,
     content: Option<String>,
-    function_call: Option<String>,
+    function_call: Option<ChatCompletionFunctionCall>,
 ) -> ChatCompletionMessage {
     ChatCompletionMessage {
         role,
         content,
         name: None,
-        function_call: None,
+        function_call,
     }
 }
 
-async fn make_change(good_instructions: GoodInstructions) -> Result<()> {
-    println!("Instructions received: {:#?}", good_instructions);
-    println!(
-        "Scope: {:?}, Path: {:?}",
-        good_instructions.item, good_instructions.folder
-    );
+/// Exit code of a `--one-file` run that left other files unprocessed
+const MORE_FILES_REMAINING_EXIT_CODE: i32 = 3;
+
+/// Exit code of a `run` in which the model failed to change some items
+const ITEMS_FAILED_EXIT_CODE: i32 = 1;
+
+/// Ends a run from the command line that `--one-file` stopped before the other files. The
+/// interactive session goes on instead
+fn exit_when_files_remain(report: &RunReport) {
+    if report.summary.remaining_files > 0 {
+        std::process::exit(MORE_FILES_REMAINING_EXIT_CODE);
+    }
+}
+
+/// Returns the items to process and the number of files left out by `--one-file`
+fn collect_items(good_instructions: &GoodInstructions, cli: &Cli) -> Result<(Vec<ItemDef>, usize)> {
+    let folders = good_instructions.folders();
+    let scanned = folders
+        .iter()
+        .map(|folder| format!("{:?}", folder))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    for folder in &folders {
+        if utils::find_git_directory(folder.clone()).is_none() {
+            bail!("The target directory or its parents should be inside a git repository (should contain a .git folder).");
+        }
+    }
+
+    let language: ProgLanguage = good_instructions.item.clone().into();
+    language.check_grammar()?;
+
+    for folder in &folders {
+        if let Some(reason) = utils::risky_scan_root(folder) {
+            warn!("The folder {:?} is {}.", folder, reason);
+            if !cli.yes && !utils::confirm("Scan it anyway?")? {
+                bail!("Scan of {:?} cancelled", folder);
+            }
+        }
+    }
+
+    let mut excluded_directories = language.get_excluded_directories();
+    excluded_directories.extend(cli.exclude_dirs.iter().map(String::as_str));
+    let globs = PathGlobs::new(&cli.include, &cli.exclude)?;
+    let mut file_lists = Vec::with_capacity(folders.len());
+    for folder in &folders {
+        file_lists.push(if cli.staged {
+            git::staged_files(folder, &language.file_extensions())?
+        } else {
+            get_filenames(
+                folder,
+                &language.file_extensions(),
+                &excluded_directories,
+                !cli.no_gitignore,
+                !cli.no_follow_symlinks,
+                &globs,
+            )?
+        });
+    }
+    let files = merge_file_lists(file_lists);
+
+    let files = match &cli.file_contains {
+        Some(pattern) => filter_files_by_content(files, pattern),
+        None => files,
+    };
+
+    if let Some(warning) = file_count_warning(files.len(), cli.max_files, &scanned) {
+        warn!("{}", warning);
+        if !cli.yes {
+            // without a terminal nobody can answer, a runaway scan is an error
+            if !stdin().is_terminal() {
+                bail!("{} Pass --yes to process them anyway", warning);
+            }
+            if !utils::confirm("Continue?")? {
+                bail!("Scan of {} cancelled", scanned);
+            }
+        }
+    }
+    let mut items = extract_all_items_from_files(files, good_instructions.item.clone())?;
+    if let Some(since) = &cli.since {
+        let mut hunks = HashMap::new();
+        let mut changed_items = Vec::with_capacity(items.len());
+        for item in items {
+            if !hunks.contains_key(&item.filename) {
+                let ranges = changed_line_ranges(&item.filename, since)?;
+                hunks.insert(item.filename.clone(), ranges);
+            }
+            if cli.hunk_overlap.matches_item(&item, &hunks[&item.filename]) {
+                changed_items.push(item);
+            }
+        }
+        items = changed_items;
+    }
+    items.retain(|item| {
+        cli.min_complexity
+            .map_or(true, |min| item.complexity >= min)
+            && cli
+                .max_complexity
+                .map_or(true, |max| item.complexity <= max)
+    });
+    if let Some(pattern) = &cli.body_contains {
+        items.retain(|item| pattern.is_match(&item.definition));
+    }
+    if let Some(pattern) = &cli.name {
+        items = filter_items_by_name(items, pattern);
+    }
+    items.retain(|item| {
+        !item
+            .parent_scopes
+            .iter()
+            .any(|scope| cli.exclude_in.iter().any(|pattern| pattern.is_match(scope)))
+    });
+    if let Some(path) = &cli.filter_script {
+        items = FilterScript::load(path)?.filter(items)?;
+    }
+
+    if let ProgItem::Python(PythonProgItem::Class) = &good_instructions.item {
+        let mut split_items = Vec::with_capacity(items.len());
+        for item in items {
+            let lines = item.definition.lines().count();
+            let tokens = estimate_tokens(&item.definition);
+            let reason = match cli.max_class_lines {
+                Some(max_lines) if lines > max_lines => {
+                    format!("is longer than {} lines", max_lines)
+                }
+                _ if tokens > cli.max_item_tokens => format!(
+                    "is about {} tokens, more than --max-item-tokens {}",
+                    tokens, cli.max_item_tokens
+                ),
+                _ => {
+                    split_items.push(item);
+                    continue;
+                }
+            };
+            let methods = extract_class_methods(&item)?;
+            info!(
+                "Class in {:?} (line {}) {}, transforming its {} methods separately",
+                item.filename,
+                item.start_pos + 1,
+                reason,
+                methods.len()
+            );
+            split_items.extend(methods);
+        }
+        items = split_items;
+    }
+
+    match &cli.one_file {
+        Some(requested) => {
+            let (items, other_files) = select_one_file(items, requested.as_deref())?;
+            Ok((
+                filter_items_by_lines(items, cli.line_start, cli.line_end),
+                other_files,
+            ))
+        }
+        None => Ok((items, 0)),
+    }
+}
+
+/// Prints the git diff of a file
+fn show_file_diff(file: &Path) -> Result<()> {
+    let file = fs::canonicalize(file)?;
+    let status = std::process::Command::new("git")
+        .args(["--no-pager", "diff", "--"])
+        .arg(&file)
+        .current_dir(file.parent().unwrap_or(Path::new("/")))
+        .status()?;
+    if !status.success() {
+        bail!("git diff failed for {:?}", file);
+    }
+    Ok(())
+}
+
+/// Shows every change and asks whether to apply it. Quitting keeps the changes accepted so far.
+/// Without a terminal to ask, all changes are accepted
+fn review_changes(changes: Vec<ItemChange>) -> Result<Vec<ItemChange>> {
+    if !stdin().is_terminal() {
+        println!("stdin is not a terminal, accepting all changes");
+        return Ok(changes);
+    }
+
+    let total = changes.len();
+    let mut accepted = vec![];
+    for (index, change) in changes.into_iter().enumerate() {
+        println!(
+            "{}",
+            format!(
+                "[{}/{}] {:?} (lines {}-{})",
+                index + 1,
+                total,
+                change.before.filename,
+                change.before.start_pos + 1,
+                change.before.end_pos + 1
+            )
+            .bold()
+        );
+        print!("{}", colored_diff(&change.before.definition, &change.after));
+        loop {
+            let Some(answer) = utils::get_user_input("[a]ccept / [s]kip / [q]uit")? else {
+                return Ok(accepted);
+            };
+            match answer.trim().to_ascii_lowercase().as_str() {
+                "a" | "accept" => {
+                    accepted.push(change);
+                    break;
+                }
+                "s" | "skip" => break,
+                "q" | "quit" => return Ok(accepted),
+                _ => println!("Answer a, s or q"),
+            }
+        }
+    }
+    Ok(accepted)
+}
 
-    let folder: PathBuf = good_instructions
-        .folder
-        .clone()
-        .unwrap_or(".".to_string())
-        .into();
+/// Root of the git repository containing the (first) folder of the instructions
+fn repository_root(good_instructions: &GoodInstructions) -> Result<PathBuf> {
+    let folder = good_instructions.folders().remove(0);
+    let folder = fs::canonicalize(&folder)
+        .map_err(|err| anyhow!("Cannot resolve the folder {:?}: {}", folder, err))?;
+    utils::find_git_directory(folder)
+        .ok_or_else(|| anyhow!("The target directory is not inside a git repository"))
+}
+
+/// The code of an item as it is sent to the model
+fn item_code(good_instructions: &GoodInstructions, item: &ItemDef) -> String {
+    match good_instructions.item {
+        ProgItem::Rust(RustProgItem::DocTest) => strip_doc_comment(&item.definition),
+        _ => item.definition.clone(),
+    }
+}
 
-    if utils::find_git_directory(folder.clone()).is_none() {
-        bail!("The target directory or its parents should be inside a git repository (should contain a .git folder).");
+fn transform_prompt(good_instructions: &GoodInstructions, cli: &Cli, code: &str) -> String {
+    user_action_to_chatgpt_prompt(
+        &good_instructions.item,
+        &good_instructions.user_message,
+        cli.prompt_extra.as_deref(),
+    )
+    .replace("<CODE>", code)
+}
+
+/// Prints (or writes to `--prompt-dir`) the transform prompt of every extracted item
+/// without calling the API
+fn write_prompts(good_instructions: &GoodInstructions, cli: &Cli) -> Result<()> {
+    let (items, _) = collect_items(good_instructions, cli)?;
+    let prompt_dir = cli.prompt_dir.as_deref();
+
+    if let Some(dir) = prompt_dir {
+        fs::create_dir_all(dir)?;
     }
 
+    for (index, item) in items.iter().enumerate() {
+        let prompt = transform_prompt(good_instructions, cli, &item_code(good_instructions, item));
+        match prompt_dir {
+            Some(dir) => {
+                let stem = item
+                    .filename
+                    .file_stem()
+                    .and_then(|stem| stem.to_str())
+                    .unwrap_or("item");
+                let prompt_path =
+                    dir.join(format!("{:04}_{}_L{}.txt", index, stem, item.start_pos + 1));
+                fs::write(&prompt_path, prompt)?;
+                println!("Prompt written to {:?}", prompt_path);
+            }
+            None => {
+                println!(
+                    "{}",
+                    format!(
+                        "===== {:?} (lines {}-{}) =====",
+                        item.filename,
+                        item.start_pos + 1,
+                        item.end_pos + 1
+                    )
+                    .bold()
+                );
+                println!("{}", prompt);
+            }
+        }
+    }
+
+    println!("{} prompts generated", items.len());
+    Ok(())
+}
+
+/// Estimates the requests of the run from the transform prompts of the items, see `RunEstimate`
+fn estimate_run(good_instructions: &GoodInstructions, cli: &Cli) -> Result<RunEstimate> {
+    let (items, _) = collect_items(good_instructions, cli)?;
+    let mut estimate = RunEstimate::default();
+    for item in &items {
+        let code = item_code(good_instructions, item);
+        let prompt = transform_prompt(good_instructions, cli, &code);
+        estimate.add_item(&item.filename, &prompt, &code);
+    }
+    Ok(estimate)
+}
+
+async fn make_change(
+    good_instructions: GoodInstructions,
+    cli: &Cli,
+    llm: &LlmClient,
+) -> Result<RunReport> {
+    debug!("Instructions received: {:#?}", good_instructions);
+    info!(
+        "Scope: {:?}, Paths: {:?}",
+        good_instructions.item,
+        good_instructions.folders()
+    );
+
+    let started = Instant::now();
     let language: ProgLanguage = good_instructions.item.clone().into();
+    let mut report = RunReport::new(good_instructions.clone(), &cli.model);
+
+    let transforms = TransformRegistry::with_builtins().select(&cli.transform)?;
+    if cli.commit {
+        git::ensure_nothing_staged(&repository_root(&good_instructions)?)?;
+    }
+    if let Some(branch) = &cli.branch {
+        git::create_branch(
+            &repository_root(&good_instructions)?,
+            branch,
+            cli.branch_force,
+        )?;
+        info!("Switched to the new branch {}", branch);
+    }
+    let (functions, remaining_files) = collect_items(&good_instructions, cli)?;
+    report.summary.remaining_files = remaining_files;
+    let one_file = functions.first().map(|function| function.filename.clone());
 
-    let files = get_filenames(
-        &folder,
-        &language.file_extensions(),
-        &language.get_excluded_directories(),
-    )?;
-    let functions = extract_all_items_from_files(files, good_instructions.item.clone())?;
+    let cache = (!cli.no_cache).then(|| ResponseCache::new(DEFAULT_CACHE_DIR));
+    let detector = match (&cli.detector, &cli.save_detector) {
+        (Some(path), _) => Some(Detector::load(path)?),
+        (None, Some(path)) => {
+            let detector =
+                llm::generate_detector(&good_instructions.user_message, llm, cache.as_ref())
+                    .await?;
+            detector.save(path)?;
+            info!("Detector saved to {:?}", path);
+            Some(detector)
+        }
+        // by default a detector is generated for every task, so that only the relevant items are
+        // paid for
+        (None, None)
+            if !cli.no_prefilter
+                && cli.action.is_none()
+                && !good_instructions.user_message.is_empty() =>
+        {
+            match llm::generate_detector(&good_instructions.user_message, llm, cache.as_ref()).await
+            {
+                Ok(detector) => Some(detector),
+                Err(err) => {
+                    warn!(
+                        "Cannot prefilter the items for the task, sending all of them: {:#}",
+                        err
+                    );
+                    None
+                }
+            }
+        }
+        (None, None) => None,
+    };
 
     let mut changes = vec![];
+    let mut change_report = vec![];
+    let mut converted = vec![];
+    let mut pending = vec![];
     for function in functions {
-        println!("Changing item in file: {:?}", function.filename);
-        let mut new_code = function.definition.clone();
-        let mut retry_count = 0;
-        loop {
-            let prompt_text = if retry_count == 0 {
-                // First iteration: prompt to apply the suggested action
-                user_action_to_chatgpt_prompt(
-                    &good_instructions.item,
-                    &good_instructions.user_message,
-                )
-                .replace("<CODE>", &new_code)
-            } else {
-                // Subsequent iterations: prompt indicating that the previous change was incorrect
-                match chatgpt_wrong_code_proposal(
-                    &function.definition,
-                    &new_code,
-                    "Error message from parser",
-                ) {
-                    Ok(wrong_code_prompt) => wrong_code_prompt,
-                    Err(_) => {
-                        println!("Error generating prompt for wrong code proposal. Skipping...");
-                        break;
+        report.summary.record_item(&language);
+        if let Some(transform) = transforms.find(&function) {
+            let new_code = transform.apply(&function)?;
+            report.record_changed(&function, &language);
+            change_report.push(ChangeReportEntry {
+                filename: function.filename.clone(),
+                start_line: function.start_pos + 1,
+                end_line: function.end_pos + 1,
+                diff_lines: diff_line_count(&function.definition, &new_code),
+                risk: 0.0,
+            });
+            changes.push(ItemChange {
+                before: function.clone(),
+                after: new_code,
+            });
+            continue;
+        }
+        if good_instructions.user_message.is_empty() {
+            report.record_skipped(&function, &language, "no transform");
+            continue;
+        }
+        if let Some(detector) = &detector {
+            match detector.detect(&function.definition) {
+                Ok(true) => {}
+                Ok(false) => {
+                    report.record_skipped(&function, &language, "detector");
+                    continue;
+                }
+                Err(err) => warn!(
+                    "Detector failed for {:?} (line {}), keeping the item: {}",
+                    function.filename,
+                    function.start_pos + 1,
+                    err
+                ),
+            }
+        }
+        if let Some(action) = cli.action {
+            if !action.applies_to(&function.definition) {
+                report.record_skipped(&function, &language, "not applicable");
+                continue;
+            }
+        }
+        let tokens = estimate_tokens(&item_code(&good_instructions, &function));
+        if tokens > cli.max_item_tokens {
+            warn!(
+                "Skipping {:?} lines {}-{}: about {} tokens, more than --max-item-tokens {}",
+                function.filename,
+                function.start_pos + 1,
+                function.end_pos + 1,
+                tokens,
+                cli.max_item_tokens
+            );
+            report.record_skipped(&function, &language, "too large");
+            continue;
+        }
+        pending.push(function);
+    }
+
+    let report = Mutex::new(report);
+    let total = pending.len();
+    let good_instructions = &good_instructions;
+    let report_ref = &report;
+    let cache = cache.as_ref();
+    let mut results = stream::iter(pending.into_iter().map(|function| async move {
+        let result = change_item(good_instructions, cli, &function, report_ref, llm, cache).await;
+        (function, result)
+    }))
+    // streamed answers of concurrent items would be interleaved
+    .buffer_unordered(if cli.stream {
+        1
+    } else if cli.auto_concurrency {
+        // the LLM client holds the requests back to the limit it found
+        llm::MAX_AUTO_CONCURRENCY
+    } else {
+        cli.concurrency.get()
+    });
+    let mut done = 0;
+    while let Some((function, result)) = results.next().await {
+        done += 1;
+        match result {
+            Ok(Some((change, entry))) => {
+                if cli.action.is_some() {
+                    converted.push(change.before.clone());
+                }
+                change_report.push(entry);
+                changes.push(change);
+            }
+            Ok(None) => {}
+            Err(err) if err.is::<RequestTimeout>() => {
+                warn!(
+                    "{} for the item in {:?} (line {}), skipping it",
+                    err,
+                    function.filename,
+                    function.start_pos + 1
+                );
+                report
+                    .lock()
+                    .unwrap()
+                    .record_skipped(&function, &language, "timed out");
+            }
+            Err(err) => {
+                // over budget, the requests still in flight are dropped with the stream
+                llm.usage.lock().unwrap().check_budget()?;
+                error!(
+                    "Failed to change the item in {:?} (line {}): {:#}",
+                    function.filename,
+                    function.start_pos + 1,
+                    err
+                );
+                report
+                    .lock()
+                    .unwrap()
+                    .record_failed(&function, &language, &format!("{:#}", err));
+            }
+        }
+        info!(
+            "[{}/{}] {:?} (line {}) done",
+            done,
+            total,
+            function.filename,
+            function.start_pos + 1
+        );
+    }
+    drop(results);
+    let mut report = report.into_inner().unwrap();
+    let text_output = cli.output == RunOutput::Text;
+
+    if text_output && !change_report.is_empty() {
+        print_change_report(&mut change_report, cli.sort_report);
+    }
+    if cli.interactive {
+        changes = review_changes(changes)?;
+    }
+
+    match &cli.patch {
+        Some(path) => {
+            fs::write(
+                path,
+                unified_patch(&compute_file_changes(changes, cli.skip_overlaps)?),
+            )?;
+            if text_output {
+                println!("Patch written to {:?}, apply it with `git apply`", path);
+            }
+        }
+        None => {
+            let verify_build = cli.verify_build && matches!(language, ProgLanguage::Rust);
+            if verify_build && cli.dry_run {
+                let file_changes = compute_file_changes(changes.clone(), cli.skip_overlaps)?;
+                let root = repository_root(good_instructions)?;
+                match verify::cargo_check_with_changes(&root, &file_changes)? {
+                    None => println!("{}", "cargo check passes with the changes".green()),
+                    Some(errors) => println!(
+                        "{}\n{}",
+                        "cargo check fails with the changes:".red(),
+                        errors
+                    ),
+                }
+            }
+            let written = apply_changes(
+                changes,
+                &ApplyOptions {
+                    dry_run: cli.dry_run,
+                    // the backups are needed to revert a failed build
+                    backup: cli.backup || verify_build,
+                    skip_overlaps: cli.skip_overlaps,
+                },
+            )?;
+            let written_paths: Vec<PathBuf> =
+                written.iter().map(|file| file.path.clone()).collect();
+            if cli.format && !written.is_empty() {
+                let command = cli
+                    .formatter
+                    .as_deref()
+                    .unwrap_or(language.default_formatter());
+                for failure in Formatter::new(command)?.format_files(&written_paths) {
+                    warn!("Cannot format {:?}: {}", failure.file, failure.message);
+                }
+            }
+            if verify_build && !written.is_empty() {
+                let errors = verify::cargo_check(&repository_root(good_instructions)?)?;
+                if errors.is_some() {
+                    restore_backups(&written)?;
+                }
+                if !cli.backup {
+                    for backup in written.iter().filter_map(|file| file.backup.as_ref()) {
+                        fs::remove_file(backup)?;
                     }
                 }
-            };
+                match errors {
+                    None if text_output => println!("{}", "cargo check passes".green()),
+                    None => {}
+                    Some(errors) => {
+                        bail!("cargo check failed, the changes were reverted:\n{}", errors)
+                    }
+                }
+            }
+            if cli.backup && !written.is_empty() {
+                history::record_run(Path::new(DEFAULT_HISTORY_FILE), &written)?;
+            }
+            if cli.commit && !written.is_empty() {
+                let oid = git::commit_files(
+                    &repository_root(good_instructions)?,
+                    &written_paths,
+                    &good_instructions.user_message,
+                )?;
+                info!("Committed the changes as {}", oid);
+            }
+        }
+    }
 
-            let messages = vec![ChatCompletionMessage {
-                role: ChatCompletionMessageRole::User,
-                content: Some(prompt_text),
-                name: None,
-                function_call: None,
-            }];
-
-            let chat_completion = ChatCompletion::builder("gpt-3.5-turbo-16k-0613", messages)
-                .create()
-                .await?;
-            new_code = chat_completion
-                .choices
-                .first()
-                .unwrap()
-                .message
-                .content
-                .clone()
-                .unwrap();
+    report.summary.concurrency = llm.auto_concurrency();
+    if text_output {
+        print!("{}", report.summary_text().bold());
+        llm.usage.lock().unwrap().print_summary();
+    }
+    report.summary.wall_clock_secs = started.elapsed().as_secs_f64();
+    if let Some(path) = &cli.summary_json {
+        report.summary.write_json(path)?;
+    }
+    if !text_output {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    }
+
+    if text_output && cli.action == Some(BuiltinAction::ToAsync) && !converted.is_empty() {
+        println!(
+            "{}",
+            "Functions converted to async (update their callers to await them):".bold()
+        );
+        for function in converted {
+            let name = actions::python_function_signature(&function.definition)
+                .map(|signature| signature.name)
+                .unwrap_or_default();
+            println!(
+                "  {} ({:?}, line {})",
+                name,
+                function.filename,
+                function.start_pos + 1
+            );
+        }
+    }
+
+    if cli.one_file.is_some() {
+        if let Some(file) = one_file.as_ref().filter(|_| text_output) {
+            show_file_diff(file)?;
+        }
+        if remaining_files > 0 {
+            info!(
+                "{} more file(s) with matching items remain. Review the changes and run again.",
+                remaining_files
+            );
+        }
+    }
 
-            // Check if the reply from ChatGPT can be parsed
-            if parse_code(&new_code, &good_instructions.item).is_ok() {
+    Ok(report)
+}
+
+/// Attempts of an item before it is skipped, counted separately for the answers that can't be
+/// parsed and for the ones rejected by --retry-on-structural-regression
+const MAX_ITEM_ATTEMPTS: u32 = 3;
+
+/// Asks the model to change one item, retrying unparsable answers. Returns `None` when the item
+/// is skipped or the model keeps failing
+async fn change_item(
+    good_instructions: &GoodInstructions,
+    cli: &Cli,
+    function: &ItemDef,
+    report: &Mutex<RunReport>,
+    llm: &LlmClient,
+    cache: Option<&ResponseCache>,
+) -> Result<Option<(ItemChange, ChangeReportEntry)>> {
+    let language: ProgLanguage = good_instructions.item.clone().into();
+    info!(
+        "Changing item in file: {:?} (line {})",
+        function.filename,
+        function.start_pos + 1
+    );
+    let original_code = item_code(good_instructions, function);
+    let mut new_code = original_code.clone();
+    let mut parse_failures = 0;
+    let mut structural_regressions = 0;
+    let mut empty_responses = 0;
+    // the last answer and its divergence, when it was rejected for it
+    let mut structural_regression: Option<(String, f64)> = None;
+    let mut parse_error = String::new();
+    loop {
+        let prompt_text = if let Some((answer, divergence)) = &structural_regression {
+            structural_regression_prompt(
+                &transform_prompt(good_instructions, cli, &original_code),
+                answer,
+                *divergence,
+            )
+        } else if parse_failures == 0 {
+            // First iteration: prompt to apply the suggested action
+            transform_prompt(good_instructions, cli, &original_code)
+        } else {
+            // Subsequent iterations: prompt indicating that the previous change was incorrect
+            match chatgpt_wrong_code_proposal(&function.definition, &new_code, &parse_error) {
+                Ok(wrong_code_prompt) => wrong_code_prompt,
+                Err(_) => {
+                    warn!("Error generating prompt for wrong code proposal. Skipping...");
+                    report.lock().unwrap().record_failed(
+                        function,
+                        &language,
+                        "Cannot generate the retry prompt",
+                    );
+                    return Ok(None);
+                }
+            }
+        };
+
+        debug!("Prompt for {:?}:\n{}", function.filename, prompt_text);
+        let messages = vec![ChatCompletionMessage {
+            role: ChatCompletionMessageRole::User,
+            content: Some(prompt_text),
+            name: None,
+            function_call: None,
+        }];
+
+        let options = CompletionOptions {
+            temperature: cli.temperature,
+            max_tokens: cli.max_tokens,
+            stream: cli.stream,
+            ..Default::default()
+        };
+        let chat_completion = llm
+            .complete(messages, &options, cache, &function.definition)
+            .await?;
+        report.lock().unwrap().summary.record_usage(
+            chat_completion.usage.as_ref(),
+            llm.usage.lock().unwrap().prices(),
+        );
+        new_code = match llm::completion_content(&chat_completion) {
+            Ok(content) => {
+                debug!("Answer for {:?}:\n{}", function.filename, content);
+                content
+            }
+            Err(err) => {
+                empty_responses += 1;
+                if empty_responses >= llm::MAX_EMPTY_RESPONSES {
+                    warn!("{} Skipping...", err);
+                    report
+                        .lock()
+                        .unwrap()
+                        .record_failed(function, &language, &err.to_string());
+                    return Ok(None);
+                }
+                warn!("{}, retrying", err);
+                new_code = original_code.clone();
+                continue;
+            }
+        };
+
+        // the model may wrap the code in markdown code blocks despite being asked not to
+        if let Some(code) = extract_answer_code(&new_code, language.markdown_tag(), |code| {
+            parse_code_checked(code, &good_instructions.item, Some(&function.filename)).is_ok()
+        }) {
+            new_code = code;
+        }
+
+        // Check if the reply from ChatGPT can be parsed
+        match parse_code_checked(&new_code, &good_instructions.item, Some(&function.filename)) {
+            Ok(_) => {
+                if let Some(action) = cli.action {
+                    if let Err(err) = action.validate(&function.definition, &new_code) {
+                        warn!(
+                            "Rejected the change in {:?} (line {}): {}",
+                            function.filename,
+                            function.start_pos + 1,
+                            err
+                        );
+                        report.lock().unwrap().record_skipped(
+                            function,
+                            &language,
+                            "rejected by validation",
+                        );
+                        return Ok(None);
+                    }
+                }
+                if !cli.allow_rename {
+                    let old_name = item_name(&original_code, &good_instructions.item);
+                    let new_name = item_name(&new_code, &good_instructions.item);
+                    if let (Some(old_name), Some(new_name)) = (old_name, new_name) {
+                        if old_name != new_name {
+                            warn!(
+                                "Rejected the change in {:?} (line {}): {} was renamed to {}, use --allow-rename to accept renames",
+                                function.filename,
+                                function.start_pos + 1,
+                                old_name,
+                                new_name
+                            );
+                            report
+                                .lock()
+                                .unwrap()
+                                .record_skipped(function, &language, "renamed");
+                            return Ok(None);
+                        }
+                    }
+                }
+                if matches!(language, ProgLanguage::Python)
+                    && !type_comments(&original_code).is_empty()
+                {
+                    match preserve_type_comments(&original_code, &new_code) {
+                        Ok(code) => new_code = code,
+                        Err(err) => {
+                            warn!(
+                                "Rejected the change in {:?} (line {}): {}",
+                                function.filename,
+                                function.start_pos + 1,
+                                err
+                            );
+                            report.lock().unwrap().record_skipped(
+                                function,
+                                &language,
+                                "type comments dropped",
+                            );
+                            return Ok(None);
+                        }
+                    }
+                }
+                let divergence =
+                    structural_divergence(&original_code, &new_code, &good_instructions.item)?;
+                if divergence > cli.structure_threshold {
+                    warn!(
+                        "The structure of the new code in {:?} (line {}) diverges {:.0}% from the original",
+                        function.filename,
+                        function.start_pos + 1,
+                        divergence * 100.0
+                    );
+                    if cli.retry_on_structural_regression {
+                        structural_regressions += 1;
+                        if structural_regressions >= MAX_ITEM_ATTEMPTS {
+                            warn!(
+                                "Structural regression after {} attempts. Skipping...",
+                                MAX_ITEM_ATTEMPTS
+                            );
+                            report.lock().unwrap().record_skipped(
+                                function,
+                                &language,
+                                "structural regression",
+                            );
+                            return Ok(None);
+                        }
+                        structural_regression = Some((new_code.clone(), divergence));
+                        continue;
+                    }
+                }
                 // If the parsing is successful, save the change
-                changes.push(ItemChange {
+                report.lock().unwrap().record_changed(function, &language);
+                let entry = ChangeReportEntry {
+                    filename: function.filename.clone(),
+                    start_line: function.start_pos + 1,
+                    end_line: function.end_pos + 1,
+                    diff_lines: diff_line_count(&original_code, &new_code),
+                    risk: divergence,
+                };
+                let change = ItemChange {
                     before: function.clone(),
-                    after: new_code.clone(),
-                });
-                break;
-            } else {
-                // Retry up to 3 times before skipping
-                retry_count += 1;
-                if retry_count >= 3 {
-                    println!(
-                        "Failed to parse the code for function: {:?} after 3 attempts. Skipping...",
-                        function.filename
+                    after: match good_instructions.item {
+                        ProgItem::Rust(RustProgItem::DocTest) => {
+                            to_doc_comment(&new_code, &function.definition)
+                        }
+                        _ => new_code.clone(),
+                    },
+                };
+                return Ok(Some((change, entry)));
+            }
+            Err(err) => {
+                // Retry before skipping, telling the model what is wrong
+                parse_error = err.to_string();
+                debug!(
+                    "Cannot parse the answer for {:?}: {}",
+                    function.filename, parse_error
+                );
+                structural_regression = None;
+                parse_failures += 1;
+                if parse_failures >= MAX_ITEM_ATTEMPTS {
+                    warn!(
+                        "Failed to parse the code for function: {:?} after {} attempts ({}). Skipping...",
+                        function.filename, MAX_ITEM_ATTEMPTS, parse_error
                     );
-                    break;
+                    report
+                        .lock()
+                        .unwrap()
+                        .record_failed(function, &language, &parse_error);
+                    return Ok(None);
                 }
             }
         }
     }
-
-    apply_changes(changes)?;
-
-    Ok(())
 }
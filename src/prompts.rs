@@ -1,15 +1,43 @@
 use anyhow::Result;
 use schemars::{schema_for, JsonSchema};
 use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
 use strum_macros::{EnumString, EnumVariantNames};
 
 use crate::instructions::{all_instruction_examples, InitialInstruction};
 use crate::lang::{ProgItem, ProgLanguage};
 
-pub fn get_system_prompt() -> Result<String> {
+/// Language and item combinations the instruction parser may answer with, every item kind of
+/// every language, e.g. `{"Python": ["Function", "Class", ...], ...}`
+fn supported_items() -> String {
+    let languages: Vec<String> = ProgLanguage::iter()
+        .map(|language| {
+            let kinds: Vec<String> = language
+                .items()
+                .iter()
+                .filter_map(|item| Some(format!("{:?}", item.to_string().split_once('.')?.1)))
+                .collect();
+            format!("{:?}: [{}]", format!("{:?}", language), kinds.join(", "))
+        })
+        .collect();
+    format!("{{{}}}", languages.join(", "))
+}
+
+/// `--prompt-extra` instructions, appended on their own line to the requirements of a prompt
+fn extra_instructions(prompt_extra: Option<&str>) -> String {
+    match prompt_extra
+        .map(str::trim)
+        .filter(|extra| !extra.is_empty())
+    {
+        Some(extra) => format!("\n{}", extra),
+        None => String::new(),
+    }
+}
+
+pub fn get_system_prompt(prompt_extra: Option<&str>) -> Result<String> {
     Ok(format!(
         r#"
-Hi ChatGPT. I will paste a user prompt for a code assistant tool. The tool works by iterating through some folder,
+I will paste a user prompt for a code assistant tool. The tool works by iterating through some folder,
 find the items to be changed and applies the changes.
 
 Your answer should be a JSON using one of those variants
@@ -17,24 +45,28 @@ Your answer should be a JSON using one of those variants
 {}
 
 Requirements:
-- answer only with a proper JSON that can be parsed into one of those variants
+- answer only with a proper JSON that can be parsed into one of those variants, without a
+  code block or any text around it
 - please don't guess the programming language if it is not mentioned, ask for clarification
   using ClarificationNeeded variant
 - users cannot select spefific classes
 - don't guess the folder name, leave empty if it is not mentioned
+- when the user wants every kind of item of a language use the All item, e.g. {{"All": "Rust"}}
 
-SUPPORTED_ITEMS = {{"Rust": ["Struct", "Enum", "Function"], "Python": ["Function", "Class"]}}
+SUPPORTED_ITEMS = {}
 
 if the user uses a different combination mention the ones that can be used and tell that
-we are working on more."#,
-        all_instruction_examples()?
+we are working on more.{}"#,
+        all_instruction_examples()?,
+        supported_items(),
+        extra_instructions(prompt_extra)
     ))
 }
 
 pub fn wrap_user_message(user_message: &str) -> Result<String> {
     let prompt = format!(
         r#"
-Hi ChatGPT. I will paste a user prompt for a code assistant tool. The tool works by iterating through some folder,
+I will paste a user prompt for a code assistant tool. The tool works by iterating through some folder,
 find the items to be changed and applies the changes.
 
 Your answer should be one of these JSON structures
@@ -48,7 +80,7 @@ Requirements:
 - users cannot select spefific classes
 - Currently only some combinations of language and items are supported (others are coming soon).
 
-SUPPORTED_ITEMS = {{"rust": ["struct", "enum", "function"], "python": ["function", "class"]}}
+SUPPORTED_ITEMS = {}
 
 if the user uses a different combination mention the ones that can be used and tell that
 we are working on more.
@@ -58,6 +90,7 @@ USER_MESSAGE = "{}"
 Parse this message into one of: ClarificationNeeded, GoodInstructions, UserError.
 "#,
         all_instruction_examples()?,
+        supported_items(),
         user_message
     );
     Ok(prompt)
@@ -70,7 +103,7 @@ pub fn chatgpt_wrong_answer(
 ) -> Result<String> {
     Ok(format!(
         r#"
-Hi ChatGPT. The answer you provided:
+The answer you provided:
 
 {}
 
@@ -92,8 +125,9 @@ Requirements:
   using ClarificationNeeded variant
 - users cannot select spefific classes
 - don't guess the folder name, leave empty if it is not mentioned
+- when the user wants every kind of item of a language use the All item, e.g. {{"All": "Rust"}}
 
-SUPPORTED_ITEMS = {{"Rust": ["Struct", "Enum", "Function"], "Python": ["Function", "Class"]}}
+SUPPORTED_ITEMS = {}
 
 ~~~~~~~~~~
 
@@ -101,11 +135,16 @@ Please fix the issue and rewrite the answer so it matches the schema."#,
         chatgpt_answer,
         all_instruction_examples()?,
         original_question,
-        error_message
+        error_message,
+        supported_items()
     ))
 }
 
-pub fn user_action_to_chatgpt_prompt(prog_item: &ProgItem, user_message: &str) -> String {
+pub fn user_action_to_chatgpt_prompt(
+    prog_item: &ProgItem,
+    user_message: &str,
+    prompt_extra: Option<&str>,
+) -> String {
     format!(
         r#"
 Please {}:
@@ -116,10 +155,11 @@ Requirements:
 Ensure the code remains functionally equivalent.
 Return only the transformed code and do not include any explanations, comments, or additional text.
 The output should be only code, ready to be used as a replacement for the original code.
-Don't add special characters at the beginning or end.
+Don't add special characters at the beginning or end.{}
 
 Code:"#,
-        user_message
+        user_message,
+        extra_instructions(prompt_extra)
     )
 }
 
@@ -130,7 +170,7 @@ pub fn chatgpt_wrong_code_proposal(
 ) -> Result<String> {
     Ok(format!(
         r#"
-Hi ChatGPT. The code you provided:
+The code you provided:
 
 {}
 
@@ -149,6 +189,30 @@ Requirements:
     ))
 }
 
+/// Asks again for the change after `answer` diverged `divergence` (0.0-1.0) from the structure
+/// of the original code. With the rejected answer in it, a retry is not a request the response
+/// cache has already answered
+pub fn structural_regression_prompt(
+    transform_prompt: &str,
+    answer: &str,
+    divergence: f64,
+) -> String {
+    format!(
+        r#"{}
+
+Your previous answer changed the structure of the code much more than the task requires, {:.0}%
+of it differs from the original:
+
+{}
+
+Preserve the behavior of the original code: keep its control flow, calls and return values
+and only make the changes needed for the task."#,
+        transform_prompt,
+        divergence * 100.0,
+        answer
+    )
+}
+
 pub fn quickcheck_prompt(task: &str) -> String {
     format!(
         r#"
@@ -205,3 +269,57 @@ TASK: {}
         task
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::lang::PythonProgItem;
+
+    use super::*;
+
+    #[test]
+    fn test_prompt_extra() {
+        let item = ProgItem::Python(PythonProgItem::Function);
+        let extra = "Always keep the existing comments.";
+
+        let prompt = user_action_to_chatgpt_prompt(&item, "add type hints", Some(extra));
+        assert!(prompt.contains("end.\nAlways keep the existing comments.\n\nCode:"));
+        assert_eq!(
+            user_action_to_chatgpt_prompt(&item, "add type hints", Some("  ")),
+            user_action_to_chatgpt_prompt(&item, "add type hints", None)
+        );
+
+        let system_prompt = get_system_prompt(Some(extra)).unwrap();
+        assert!(system_prompt.ends_with("working on more.\nAlways keep the existing comments."));
+        assert!(!get_system_prompt(None).unwrap().contains(extra));
+    }
+
+    #[test]
+    fn test_structural_regression_prompt() {
+        let prompt = structural_regression_prompt("Add docstrings", "def b():\n    pass", 0.75);
+        assert!(prompt.starts_with("Add docstrings\n"));
+        assert!(prompt.contains("75%"));
+        assert!(prompt.contains("def b():\n    pass"));
+        assert_ne!(
+            prompt,
+            structural_regression_prompt("Add docstrings", "def c():\n    pass", 0.75)
+        );
+    }
+
+    #[test]
+    fn test_supported_items() {
+        let supported: HashMap<String, Vec<String>> =
+            serde_json::from_str(&supported_items()).unwrap();
+        assert_eq!(supported.len(), ProgLanguage::iter().count());
+        for language in ProgLanguage::iter() {
+            let items: Vec<String> = supported[&format!("{:?}", language)]
+                .iter()
+                .map(|kind| format!("{:?}.{}", language, kind))
+                .collect();
+            let expected: Vec<String> = language.items().iter().map(ToString::to_string).collect();
+            assert_eq!(items, expected);
+        }
+        assert!(supported["Java"].contains(&"Method".to_string()));
+    }
+}
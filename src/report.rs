@@ -0,0 +1,449 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use clap::ValueEnum;
+use colored::Colorize;
+use openai::Usage;
+use serde::{Deserialize, Serialize};
+use similar::{ChangeTag, TextDiff};
+
+use crate::instructions::GoodInstructions;
+use crate::lang::ProgLanguage;
+use crate::search::ItemDef;
+use crate::usage::PriceTable;
+
+/// Aggregated statistics of a single run, written by `--summary-json`
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RunSummary {
+    pub model: String,
+    pub total_items: usize,
+    pub changed: usize,
+    /// number of skipped items by reason
+    pub skipped: BTreeMap<String, usize>,
+    pub failed: usize,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub estimated_cost_usd: f64,
+    pub wall_clock_secs: f64,
+    /// files with matching items that `--one-file` left for the next runs
+    #[serde(default)]
+    pub remaining_files: usize,
+    /// the number of concurrent requests `--auto-concurrency` settled on
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub concurrency: Option<usize>,
+    pub languages: BTreeMap<String, LanguageSummary>,
+}
+
+/// Everything a run did, printed by `--output json`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RunReport {
+    pub instruction: GoodInstructions,
+    /// every item considered, in the order they were done
+    pub items: Vec<ItemOutcome>,
+    pub summary: RunSummary,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ItemOutcome {
+    pub filename: PathBuf,
+    pub start_line: usize,
+    pub end_line: usize,
+    #[serde(flatten)]
+    pub status: ItemStatus,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ItemStatus {
+    Changed,
+    Skipped { reason: String },
+    Failed { error: String },
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LanguageSummary {
+    pub total_items: usize,
+    pub changed: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+/// One line of the change report shown before the changes are applied
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChangeReportEntry {
+    pub filename: PathBuf,
+    pub start_line: usize,
+    pub end_line: usize,
+    /// number of removed plus added lines
+    pub diff_lines: usize,
+    /// structural divergence of the new code, see `search::structural_divergence`
+    pub risk: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ReportOrder {
+    /// biggest changes first
+    BySize,
+    /// grouped by file, in file order
+    ByFile,
+    /// structurally riskiest changes first
+    ByRisk,
+}
+
+/// Line diff of a change, removed lines in red and added lines in green
+pub fn colored_diff(old_code: &str, new_code: &str) -> String {
+    let mut diff = String::new();
+    for change in TextDiff::from_lines(old_code, new_code).iter_all_changes() {
+        let line = change.value().trim_end_matches('\n');
+        let line = match change.tag() {
+            ChangeTag::Delete => format!("-{}", line).red().to_string(),
+            ChangeTag::Insert => format!("+{}", line).green().to_string(),
+            ChangeTag::Equal => format!(" {}", line),
+        };
+        diff.push_str(&line);
+        diff.push('\n');
+    }
+    diff
+}
+
+/// Number of lines removed from `old_code` plus lines added in `new_code`, ignoring the order
+pub fn diff_line_count(old_code: &str, new_code: &str) -> usize {
+    let mut old_lines: HashMap<&str, usize> = HashMap::new();
+    for line in old_code.lines().map(str::trim_end) {
+        *old_lines.entry(line).or_default() += 1;
+    }
+    let mut added = 0;
+    for line in new_code.lines().map(str::trim_end) {
+        match old_lines.get_mut(line) {
+            Some(count) if *count > 0 => *count -= 1,
+            _ => added += 1,
+        }
+    }
+    let removed: usize = old_lines.values().sum();
+    added + removed
+}
+
+pub fn sort_change_report(entries: &mut [ChangeReportEntry], order: ReportOrder) {
+    match order {
+        ReportOrder::BySize => entries.sort_by(|a, b| b.diff_lines.cmp(&a.diff_lines)),
+        ReportOrder::ByFile => {
+            entries.sort_by(|a, b| (&a.filename, a.start_line).cmp(&(&b.filename, b.start_line)))
+        }
+        ReportOrder::ByRisk => entries.sort_by(|a, b| b.risk.total_cmp(&a.risk)),
+    }
+}
+
+pub fn print_change_report(entries: &mut [ChangeReportEntry], order: ReportOrder) {
+    sort_change_report(entries, order);
+    println!("{}", format!("{} changes:", entries.len()).bold());
+    let mut current_file = None;
+    for entry in entries.iter() {
+        if order == ReportOrder::ByFile && current_file != Some(&entry.filename) {
+            println!("{}", entry.filename.display().to_string().bold());
+            current_file = Some(&entry.filename);
+        }
+        let location = if order == ReportOrder::ByFile {
+            String::new()
+        } else {
+            format!("{} ", entry.filename.display())
+        };
+        println!(
+            "  {}lines {}-{}: {} lines changed, risk {:.0}%",
+            location,
+            entry.start_line,
+            entry.end_line,
+            entry.diff_lines,
+            entry.risk * 100.0
+        );
+    }
+}
+
+impl RunSummary {
+    pub fn new(model: &str) -> Self {
+        RunSummary {
+            model: model.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn language(&mut self, language: &ProgLanguage) -> &mut LanguageSummary {
+        self.languages.entry(format!("{:?}", language)).or_default()
+    }
+
+    pub fn record_item(&mut self, language: &ProgLanguage) {
+        self.total_items += 1;
+        self.language(language).total_items += 1;
+    }
+
+    pub fn record_changed(&mut self, language: &ProgLanguage) {
+        self.changed += 1;
+        self.language(language).changed += 1;
+    }
+
+    pub fn record_skipped(&mut self, language: &ProgLanguage, reason: &str) {
+        *self.skipped.entry(reason.to_string()).or_default() += 1;
+        self.language(language).skipped += 1;
+    }
+
+    pub fn record_failed(&mut self, language: &ProgLanguage) {
+        self.failed += 1;
+        self.language(language).failed += 1;
+    }
+
+    pub fn record_usage(&mut self, usage: Option<&Usage>, prices: &PriceTable) {
+        if let Some(usage) = usage {
+            self.prompt_tokens += usage.prompt_tokens as u64;
+            self.completion_tokens += usage.completion_tokens as u64;
+        }
+        self.estimated_cost_usd =
+            prices.cost(&self.model, self.prompt_tokens, self.completion_tokens);
+    }
+
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+}
+
+impl RunReport {
+    pub fn new(instruction: GoodInstructions, model: &str) -> Self {
+        RunReport {
+            instruction,
+            items: vec![],
+            summary: RunSummary::new(model),
+        }
+    }
+
+    fn record_outcome(&mut self, item: &ItemDef, status: ItemStatus) {
+        self.items.push(ItemOutcome {
+            filename: item.filename.clone(),
+            start_line: item.start_pos + 1,
+            end_line: item.end_pos + 1,
+            status,
+        });
+    }
+
+    pub fn record_changed(&mut self, item: &ItemDef, language: &ProgLanguage) {
+        self.summary.record_changed(language);
+        self.record_outcome(item, ItemStatus::Changed);
+    }
+
+    pub fn record_skipped(&mut self, item: &ItemDef, language: &ProgLanguage, reason: &str) {
+        self.summary.record_skipped(language, reason);
+        let reason = reason.to_string();
+        self.record_outcome(item, ItemStatus::Skipped { reason });
+    }
+
+    pub fn record_failed(&mut self, item: &ItemDef, language: &ProgLanguage, error: &str) {
+        self.summary.record_failed(language);
+        let error = error.to_string();
+        self.record_outcome(item, ItemStatus::Failed { error });
+    }
+
+    /// The files with at least one changed item, in the order they were changed
+    pub fn changed_files(&self) -> Vec<&Path> {
+        let mut files: Vec<&Path> = vec![];
+        for outcome in &self.items {
+            if outcome.status == ItemStatus::Changed && !files.contains(&outcome.filename.as_path())
+            {
+                files.push(&outcome.filename);
+            }
+        }
+        files
+    }
+
+    /// The tally printed at the end of a run
+    pub fn summary_text(&self) -> String {
+        let summary = &self.summary;
+        let changed_files = self.changed_files();
+        let mut text = format!(
+            "Changed {} of {} items across {} files, {} skipped, {} failed\n",
+            summary.changed,
+            summary.total_items,
+            changed_files.len(),
+            summary.skipped.values().sum::<usize>(),
+            summary.failed
+        );
+        for (reason, count) in &summary.skipped {
+            text.push_str(&format!("  skipped, {}: {}\n", reason, count));
+        }
+        if let Some(concurrency) = summary.concurrency {
+            text.push_str(&format!(
+                "Settled on {} concurrent requests, pass --concurrency {} to start there\n",
+                concurrency, concurrency
+            ));
+        }
+        if !changed_files.is_empty() {
+            text.push_str("Changed files:\n");
+            for file in changed_files {
+                text.push_str(&format!("  {}\n", file.display()));
+            }
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::lang::{ProgItem, PythonProgItem};
+
+    use super::*;
+
+    #[test]
+    fn test_sort_change_report() {
+        let entry = |filename: &str, start_line, diff_lines, risk| ChangeReportEntry {
+            filename: PathBuf::from(filename),
+            start_line,
+            end_line: start_line + 1,
+            diff_lines,
+            risk,
+        };
+        let mut entries = vec![
+            entry("b.py", 1, 5, 0.1),
+            entry("a.py", 10, 1, 0.7),
+            entry("a.py", 2, 9, 0.3),
+        ];
+
+        sort_change_report(&mut entries, ReportOrder::BySize);
+        assert_eq!(
+            entries.iter().map(|e| e.diff_lines).collect::<Vec<_>>(),
+            vec![9, 5, 1]
+        );
+        sort_change_report(&mut entries, ReportOrder::ByRisk);
+        assert_eq!(
+            entries.iter().map(|e| e.risk).collect::<Vec<_>>(),
+            vec![0.7, 0.3, 0.1]
+        );
+        sort_change_report(&mut entries, ReportOrder::ByFile);
+        assert_eq!(
+            entries
+                .iter()
+                .map(|e| (e.filename.to_str().unwrap(), e.start_line))
+                .collect::<Vec<_>>(),
+            vec![("a.py", 2), ("a.py", 10), ("b.py", 1)]
+        );
+    }
+
+    #[test]
+    fn test_diff_line_count() {
+        assert_eq!(diff_line_count("a\nb\nc", "a\nb\nc"), 0);
+        assert_eq!(diff_line_count("a\nb\nc", "a\nB\nc\nd"), 3);
+    }
+
+    #[test]
+    fn test_colored_diff() {
+        colored::control::set_override(false);
+        assert_eq!(
+            colored_diff("def a():\n    return 1\n", "def a():\n    return 2"),
+            " def a():\n-    return 1\n+    return 2\n"
+        );
+        colored::control::unset_override();
+    }
+
+    #[test]
+    fn test_run_summary_counters() {
+        let mut summary = RunSummary::new("gpt-4");
+        summary.record_item(&ProgLanguage::Python);
+        summary.record_item(&ProgLanguage::Python);
+        summary.record_item(&ProgLanguage::Rust);
+        summary.record_changed(&ProgLanguage::Python);
+        summary.record_skipped(&ProgLanguage::Python, "not applicable");
+        summary.record_failed(&ProgLanguage::Rust);
+        summary.record_usage(
+            Some(&Usage {
+                prompt_tokens: 1000,
+                completion_tokens: 500,
+                total_tokens: 1500,
+            }),
+            &PriceTable::default(),
+        );
+
+        assert_eq!(summary.total_items, 3);
+        assert_eq!(summary.skipped["not applicable"], 1);
+        assert_eq!(summary.languages["Python"].changed, 1);
+        assert_eq!(summary.languages["Rust"].failed, 1);
+        assert!((summary.estimated_cost_usd - 0.06).abs() < 1e-9);
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("summary.json");
+        summary.write_json(&path).unwrap();
+        let loaded: RunSummary = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(loaded, summary);
+    }
+
+    #[test]
+    fn test_run_report_round_trip() {
+        let instruction = GoodInstructions {
+            item: ProgItem::Python(PythonProgItem::Function),
+            answer: "Adding docstrings".to_string(),
+            user_message: "add docstrings".to_string(),
+            folder: Some("src".to_string()),
+            extra_folders: vec![],
+        };
+        let item = |start_pos| ItemDef {
+            filename: PathBuf::from("src/a.py"),
+            start_pos,
+            end_pos: start_pos + 2,
+            ..Default::default()
+        };
+        let mut report = RunReport::new(instruction, "gpt-4o");
+        let python = ProgLanguage::Python;
+        report.record_changed(&item(0), &python);
+        report.record_skipped(&item(4), &python, "detector");
+        report.record_failed(&item(8), &python, "Syntax error at line 2");
+
+        assert_eq!(report.summary.changed, 1);
+        assert_eq!(report.summary.skipped["detector"], 1);
+        assert_eq!(report.items[1].start_line, 5);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["items"][1]["status"], "skipped");
+        assert_eq!(json["items"][1]["reason"], "detector");
+        assert_eq!(json["items"][2]["error"], "Syntax error at line 2");
+        let loaded: RunReport = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(loaded.items, report.items);
+        assert_eq!(loaded.summary, report.summary);
+        assert_eq!(serde_json::to_value(&loaded).unwrap(), json);
+    }
+
+    #[test]
+    fn test_summary_text() {
+        let instruction = GoodInstructions::from_command_line(
+            ProgItem::Python(PythonProgItem::Function),
+            "add docstrings".to_string(),
+            &[],
+        );
+        let item = |filename: &str| ItemDef {
+            filename: PathBuf::from(filename),
+            ..Default::default()
+        };
+        let python = ProgLanguage::Python;
+        let mut report = RunReport::new(instruction, "gpt-4o");
+        for _ in 0..5 {
+            report.summary.record_item(&python);
+        }
+        report.record_changed(&item("b.py"), &python);
+        report.record_skipped(&item("a.py"), &python, "detector");
+        report.record_changed(&item("a.py"), &python);
+        report.record_changed(&item("b.py"), &python);
+        report.record_failed(&item("c.py"), &python, "Syntax error at line 2");
+
+        assert_eq!(
+            report.changed_files(),
+            vec![Path::new("b.py"), Path::new("a.py")]
+        );
+        assert_eq!(
+            report.summary_text(),
+            "Changed 3 of 5 items across 2 files, 1 skipped, 1 failed\n  skipped, detector: 1\nChanged files:\n  b.py\n  a.py\n"
+        );
+
+        report.summary.concurrency = Some(6);
+        assert!(report.summary_text().contains(
+            "1 failed\n  skipped, detector: 1\nSettled on 6 concurrent requests, pass --concurrency 6 to start there\n"
+        ));
+    }
+}
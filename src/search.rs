@@ -1,17 +1,24 @@
 // search
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
-use std::fs::File;
-use std::io::{Read, Write};
+use std::io::Write;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-use crate::code_cleaning::apply_indentation;
+use crate::code_cleaning::{apply_indentation, dedent, normalize_indentation_style};
 use anyhow::{self, bail, Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::WalkBuilder;
+use rayon::prelude::*;
+use regex::Regex;
+use similar::TextDiff;
+use tracing::{debug, info, warn};
 use tree_sitter::{Language, Node, Parser, Query, QueryCursor, Tree};
 
-use crate::lang::{ProgItem, ProgLanguage};
+use crate::lang::{ProgItem, ProgLanguage, PythonProgItem, RustProgItem, TypeScriptProgItem};
+use crate::utils::find_git_directory;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Default)]
 pub struct ItemDef {
     pub definition: String,
     pub start_pos: usize,
@@ -19,6 +26,13 @@ pub struct ItemDef {
     pub start_byte: usize,
     pub end_byte: usize,
     pub filename: PathBuf,
+    /// identifier of the item, e.g. the function name. None for unnamed items like `impl` blocks
+    pub name: Option<String>,
+    /// rough cyclomatic complexity, see `cyclomatic_complexity`
+    pub complexity: usize,
+    /// headers of the enclosing classes, impls, traits and modules, outermost first,
+    /// e.g. `impl Drop for Connection`
+    pub parent_scopes: Vec<String>,
 }
 
 #[derive(Clone, Debug)]
@@ -27,42 +41,137 @@ pub struct ItemChange {
     pub after: String, // assuming you want to replace with a new string
 }
 
+/// `--include`/`--exclude` globs, matched against paths relative to the scanned folder
+#[derive(Clone, Debug, Default)]
+pub struct PathGlobs {
+    include: GlobSet,
+    exclude: GlobSet,
+}
+
+impl PathGlobs {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self> {
+        let build = |patterns: &[String]| -> Result<GlobSet> {
+            let mut builder = GlobSetBuilder::new();
+            for pattern in patterns {
+                builder.add(
+                    Glob::new(pattern).with_context(|| format!("Invalid glob {:?}", pattern))?,
+                );
+            }
+            Ok(builder.build()?)
+        };
+        Ok(PathGlobs {
+            include: build(include)?,
+            exclude: build(exclude)?,
+        })
+    }
+
+    /// Excludes win over includes, and without includes every path is included
+    pub fn matches(&self, relative_path: &Path) -> bool {
+        (self.include.is_empty() || self.include.is_match(relative_path))
+            && !self.exclude.is_match(relative_path)
+    }
+}
+
+/// Files with one of the extensions under `path`, skipping dot-directories, `excluded_dirs`,
+/// paths not matching `globs` and, with `respect_gitignore`, whatever `.gitignore` rules exclude
 pub fn get_filenames(
     path: &Path,
     extensions: &[&str],
     excluded_dirs: &[&str],
+    respect_gitignore: bool,
+    follow_links: bool,
+    globs: &PathGlobs,
 ) -> Result<Vec<PathBuf>> {
     let mut files = Vec::new();
-    if path.is_dir() {
-        for entry in fs::read_dir(path)? {
-            let entry = entry?;
-            let entry_path = entry.path();
-            let dir_name = entry_path
-                .file_name()
-                .and_then(|name| name.to_str())
-                .unwrap_or("");
+    if !path.is_dir() {
+        return Ok(files);
+    }
 
+    let excluded_dirs: Vec<String> = excluded_dirs.iter().map(|dir| dir.to_string()).collect();
+    // A directory reached again through a symlink (a symlinked vendor directory, a cycle) is
+    // only scanned once
+    let visited = Mutex::new(HashSet::from([fs::canonicalize(path)?]));
+    let walker = WalkBuilder::new(path)
+        .standard_filters(false)
+        .git_ignore(respect_gitignore)
+        .git_exclude(respect_gitignore)
+        .parents(respect_gitignore)
+        .follow_links(follow_links)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .filter_entry(move |entry| {
+            let is_dir = entry
+                .file_type()
+                .map_or(false, |file_type| file_type.is_dir());
+            let dir_name = entry.file_name().to_str().unwrap_or("");
             // Exclude directories starting with dot or in excluded_dirs list
-            if entry_path.is_dir()
-                && (dir_name.starts_with('.') || excluded_dirs.iter().any(|excl| excl == &dir_name))
-            {
+            entry.depth() == 0
+                || !is_dir
+                || !(dir_name.starts_with('.')
+                    || excluded_dirs.iter().any(|excl| excl == dir_name)
+                    || !fs::canonicalize(entry.path())
+                        .map_or(false, |real| visited.lock().unwrap().insert(real)))
+        })
+        .build();
+    for entry in walker {
+        // e.g. a symlink loop or an unreadable directory
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(err) => {
+                warn!("Skipping {}", err);
                 continue;
             }
-
-            if entry_path.is_dir() {
-                files.extend(get_filenames(&entry_path, extensions, excluded_dirs)?);
-            } else if let Some(extension) = entry_path.extension() {
-                if let Some(extension_str) = extension.to_str() {
-                    if extensions.iter().any(|ext| extension_str == *ext) {
-                        files.push(entry_path);
-                    }
-                }
+        };
+        let entry_path = entry.path();
+        if entry_path.is_dir() {
+            continue;
+        }
+        if let Some(extension) = entry_path
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            let relative_path = entry_path.strip_prefix(path).unwrap_or(entry_path);
+            if extensions.iter().any(|ext| extension == *ext) && globs.matches(relative_path) {
+                files.push(entry_path.to_path_buf());
             }
         }
     }
     Ok(files)
 }
 
+/// The warning for a scan that found more files than `--max-files`, None up to the cap
+pub fn file_count_warning(count: usize, max_files: usize, scanned: &str) -> Option<String> {
+    (count > max_files).then(|| {
+        format!(
+            "{} matching files found in {}, more than --max-files {}. Narrow the scan with \
+             --include or --folder, or raise --max-files.",
+            count, scanned, max_files
+        )
+    })
+}
+
+/// Concatenates the files found in several folders, dropping the ones already listed (by their
+/// canonical path) so that overlapping folders don't process a file twice
+pub fn merge_file_lists(file_lists: Vec<Vec<PathBuf>>) -> Vec<PathBuf> {
+    let mut seen = HashSet::new();
+    file_lists
+        .into_iter()
+        .flatten()
+        .filter(|file| seen.insert(fs::canonicalize(file).unwrap_or_else(|_| file.clone())))
+        .collect()
+}
+
+/// Keeps the files whose text matches the regex. Files that cannot be read as text are dropped
+pub fn filter_files_by_content(files: Vec<PathBuf>, pattern: &Regex) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .filter(|file| {
+            fs::read_to_string(file)
+                .map(|contents| pattern.is_match(&contents))
+                .unwrap_or(false)
+        })
+        .collect()
+}
+
 pub fn extract_all_items_from_directory(
     directory_path: &Path,
     language_enum: ProgLanguage,
@@ -70,7 +179,14 @@ pub fn extract_all_items_from_directory(
 ) -> Result<Vec<ItemDef>> {
     let extensions = language_enum.file_extensions();
     let excluded = language_enum.get_excluded_directories();
-    let files = get_filenames(directory_path, &extensions, &excluded)?;
+    let files = get_filenames(
+        directory_path,
+        &extensions,
+        &excluded,
+        true,
+        true,
+        &PathGlobs::default(),
+    )?;
     extract_all_items_from_files(files, item)
 }
 
@@ -79,16 +195,83 @@ pub fn extract_sexpr_from_string(
     filename: &PathBuf,
     item: &ProgItem,
 ) -> Result<Vec<ItemDef>> {
-    let (language, tree) = parse_code(source_code, item)?;
+    match item {
+        ProgItem::Rust(RustProgItem::DocTest) => {
+            return Ok(extract_doc_tests(source_code, filename))
+        }
+        ProgItem::Python(PythonProgItem::TopLevel) => {
+            return extract_top_level_statements(source_code, filename)
+        }
+        // the grammar of `.ts` files has no JSX
+        ProgItem::TypeScript(TypeScriptProgItem::Component)
+            if filename
+                .extension()
+                .and_then(|extension| extension.to_str())
+                != Some("tsx") =>
+        {
+            return Ok(vec![])
+        }
+        ProgItem::All(_) => {
+            let items = extract_query_from_string(source_code, filename, item, &item.to_sexpr())?;
+            return Ok(keep_outermost(items));
+        }
+        _ => {}
+    }
+
+    extract_query_from_string(source_code, filename, item, &item.to_sexpr())
+}
+
+/// Splits `elements` by their `(start_byte, end_byte)` into the outermost ones, in source order,
+/// and the ones nested in (or overlapping) an earlier one, with the index of that outer element
+fn split_overlaps<T>(
+    mut elements: Vec<T>,
+    byte_range: impl Fn(&T) -> (usize, usize),
+) -> (Vec<T>, Vec<(usize, T)>) {
+    // outer elements first when two start at the same byte
+    elements.sort_by(|a, b| {
+        let ((a_start, a_end), (b_start, b_end)) = (byte_range(a), byte_range(b));
+        a_start.cmp(&b_start).then(b_end.cmp(&a_end))
+    });
+    let mut outermost: Vec<T> = Vec::with_capacity(elements.len());
+    let mut overlapping = vec![];
+    for element in elements {
+        match outermost.last() {
+            Some(previous) if byte_range(&element).0 < byte_range(previous).1 => {
+                overlapping.push((outermost.len() - 1, element));
+            }
+            _ => outermost.push(element),
+        }
+    }
+    (outermost, overlapping)
+}
+
+/// Drops the items nested in (or overlapping) an earlier one, e.g. the methods of an extracted
+/// `impl`, like `--skip-overlaps` does for the changes. Returns the items in source order
+fn keep_outermost(items: Vec<ItemDef>) -> Vec<ItemDef> {
+    split_overlaps(items, |item| (item.start_byte, item.end_byte)).0
+}
+
+/// Runs a tree-sitter query and returns one item per captured node, however many capture
+/// names match it
+fn extract_query_from_string(
+    source_code: &str,
+    filename: &PathBuf,
+    item: &ProgItem,
+    sexpr: &str,
+) -> Result<Vec<ItemDef>> {
+    let (language, tree) = parse_code_with_filename(source_code, item, Some(filename))?;
+    let language_enum: ProgLanguage = item.clone().into();
     let mut items = Vec::new();
+    let mut seen_nodes = HashSet::new();
 
-    let query = Query::new(language, item.to_sexpr().as_str())?;
+    let query = Query::new(language, sexpr)?;
     let mut cursor = QueryCursor::new();
     let matches = cursor.matches(&query, tree.root_node(), source_code.as_bytes());
     let capture_names = query.capture_names();
 
     for m in matches {
-        for name in capture_names {
+        // captures starting with `_` are only used by the predicates of the query
+        for name in capture_names.iter().filter(|name| !name.starts_with('_')) {
             let index = query.capture_index_for_name(name);
             let index = match index {
                 Some(i) => i,
@@ -103,6 +286,9 @@ pub fn extract_sexpr_from_string(
                 Some(v) => v,
                 None => continue,
             };
+            if !seen_nodes.insert((node.node.start_byte(), node.node.end_byte())) {
+                continue;
+            }
 
             let value = node
                 .node
@@ -114,16 +300,22 @@ pub fn extract_sexpr_from_string(
                     )
                 })?;
 
-            let start_byte = node.node.start_byte();
-            // Find the start of the line in the source code
-            let line_start_byte = source_code[..start_byte]
-                .rfind('\n')
-                .map(|pos| pos + 1)
-                .unwrap_or(0);
-            let byte_range = line_start_byte..node.node.end_byte();
+            // Include the decorators or template parameters, the indentation of the first line
+            // unless other code precedes the item on that line, and the doc comments and
+            // attributes above it
+            let wrapper = definition_wrapper(node.node);
+            let start_byte = wrapper.start_byte();
+            let line_start_byte = line_start(source_code, start_byte);
+            let (item_start_byte, start_pos) =
+                if source_code[line_start_byte..start_byte].trim().is_empty() {
+                    leading_decorations_start(wrapper, source_code)
+                        .unwrap_or((line_start_byte, wrapper.start_position().row))
+                } else {
+                    (start_byte, wrapper.start_position().row)
+                };
+            let byte_range = item_start_byte..node.node.end_byte();
             let definition = source_code[byte_range.clone()].to_string();
 
-            let start_pos = node.node.start_position().row;
             let end_pos = node.node.end_position().row;
             items.push(ItemDef {
                 definition,
@@ -132,6 +324,9 @@ pub fn extract_sexpr_from_string(
                 start_byte: byte_range.start,
                 end_byte: byte_range.end,
                 filename: filename.clone(),
+                name: definition_name(node.node, source_code),
+                complexity: cyclomatic_complexity(node.node, &language_enum),
+                parent_scopes: parent_scopes(node.node, source_code),
             });
         }
     }
@@ -139,76 +334,767 @@ pub fn extract_sexpr_from_string(
     Ok(items)
 }
 
+/// Byte offset of the start of the line containing `byte`
+fn line_start(source_code: &str, byte: usize) -> usize {
+    source_code[..byte]
+        .rfind('\n')
+        .map(|pos| pos + 1)
+        .unwrap_or(0)
+}
+
+/// The node wrapping a definition together with its Python decorators or C++ template
+/// parameters, the definition itself if there is none
+fn definition_wrapper(node: Node) -> Node {
+    let mut wrapper = node;
+    while let Some(parent) = wrapper.parent() {
+        let wraps = match parent.kind() {
+            "decorated_definition" => wrapper.kind() != "decorator",
+            "template_declaration" => wrapper.kind() != "template_parameter_list",
+            _ => false,
+        };
+        if !wraps {
+            break;
+        }
+        wrapper = parent;
+    }
+    wrapper
+}
+
+/// Rust doc comments and attributes, Python decorators
+fn is_decoration(node: Node, source_code: &str) -> bool {
+    match node.kind() {
+        "attribute_item" | "decorator" => true,
+        "line_comment" | "block_comment" => {
+            let text = &source_code[node.byte_range()];
+            ["///", "//!", "/**", "/*!"]
+                .iter()
+                .any(|prefix| text.starts_with(prefix))
+                && !text.starts_with("////")
+                && !text.starts_with("/***")
+        }
+        _ => false,
+    }
+}
+
+/// Start of the doc comments, attributes and decorators right above `node`, up to the first
+/// blank line, as the byte of the start of their first line and its row. None when there are
+/// none, so that replacing the item doesn't leave them behind
+fn leading_decorations_start(node: Node, source_code: &str) -> Option<(usize, usize)> {
+    if is_decoration(node, source_code) {
+        return None;
+    }
+    let mut start = None;
+    let mut next_line_start = line_start(source_code, node.start_byte());
+    let mut sibling = node.prev_named_sibling();
+    while let Some(prev) = sibling {
+        let prev_line_start = line_start(source_code, prev.start_byte());
+        let gap = source_code
+            .get(prev.end_byte()..next_line_start)
+            .unwrap_or("\n\n");
+        if !is_decoration(prev, source_code)
+            || !gap.trim().is_empty()
+            || gap.matches('\n').count() > 1
+            || !source_code[prev_line_start..prev.start_byte()]
+                .trim()
+                .is_empty()
+        {
+            break;
+        }
+        start = Some((prev_line_start, prev.start_position().row));
+        next_line_start = prev_line_start;
+        sibling = prev.prev_named_sibling();
+    }
+    start
+}
+
+/// The `name` field of a definition node, or of its first child that has one (the function of
+/// a Python `decorated_definition`, the `type_spec` of a Go `type_declaration`). C functions and
+/// typedefs are named by the innermost of their nested declarators, e.g. `*parse(...)`
+fn definition_name(node: Node, source_code: &str) -> Option<String> {
+    let mut cursor = node.walk();
+    let name = node
+        .child_by_field_name("name")
+        .or_else(|| innermost_declarator(node))
+        .or_else(|| create_statement_name(node))
+        .or_else(|| {
+            node.named_children(&mut cursor)
+                .find_map(|child| child.child_by_field_name("name"))
+        })?;
+    name.utf8_text(source_code.as_bytes())
+        .ok()
+        .map(str::to_string)
+}
+
+/// SQL `CREATE` statements have no `name` field, the identifier after the keywords names them
+fn create_statement_name(node: Node) -> Option<Node> {
+    if !node.kind().starts_with("create_") {
+        return None;
+    }
+    let mut cursor = node.walk();
+    let name = node
+        .named_children(&mut cursor)
+        .find(|child| child.kind() == "identifier");
+    name
+}
+
+fn innermost_declarator(node: Node) -> Option<Node> {
+    let mut declarator = node.child_by_field_name("declarator")?;
+    // the C++ `&` declarator has no field for the one it wraps
+    while let Some(inner) = declarator.child_by_field_name("declarator").or_else(|| {
+        (declarator.kind() == "reference_declarator")
+            .then(|| declarator.named_child(0))
+            .flatten()
+    }) {
+        declarator = inner;
+    }
+    Some(declarator)
+}
+
+/// Keeps the items whose name matches the regex, unnamed items are dropped
+pub fn filter_items_by_name(items: Vec<ItemDef>, pattern: &Regex) -> Vec<ItemDef> {
+    items
+        .into_iter()
+        .filter(|item| {
+            item.name
+                .as_deref()
+                .map_or(false, |name| pattern.is_match(name))
+        })
+        .collect()
+}
+
+/// Keeps the items overlapping the 1-based inclusive line range, an open end is unbounded
+pub fn filter_items_by_lines(
+    items: Vec<ItemDef>,
+    line_start: Option<usize>,
+    line_end: Option<usize>,
+) -> Vec<ItemDef> {
+    items
+        .into_iter()
+        .filter(|item| {
+            line_start.map_or(true, |start| item.end_pos + 1 >= start)
+                && line_end.map_or(true, |end| item.start_pos + 1 <= end)
+        })
+        .collect()
+}
+
+/// Headers (the code before the body) of the scopes enclosing a node, outermost first
+pub fn parent_scopes(node: Node, source_code: &str) -> Vec<String> {
+    let mut scopes = vec![];
+    let mut parent = node.parent();
+    while let Some(scope) = parent {
+        if matches!(
+            scope.kind(),
+            "class_definition"
+                | "impl_item"
+                | "trait_item"
+                | "mod_item"
+                | "namespace_definition"
+                | "class_specifier"
+                | "struct_specifier"
+                | "class"
+                | "module"
+                | "class_declaration"
+                | "interface_declaration"
+                | "enum_declaration"
+        ) {
+            if let Some(body) = scope.child_by_field_name("body") {
+                let header = &source_code[scope.start_byte()..body.start_byte()];
+                let header = header.split_whitespace().collect::<Vec<&str>>().join(" ");
+                scopes.push(header.trim_end_matches(':').trim_end().to_string());
+            }
+        }
+        parent = scope.parent();
+    }
+    scopes.reverse();
+    scopes
+}
+
+/// Groups the direct children of a Python module that are not definitions or imports into
+/// one item per contiguous run. Comments inside a run are kept, comments around it are left
+/// to the neighbouring definitions
+pub fn extract_top_level_statements(source_code: &str, filename: &Path) -> Result<Vec<ItemDef>> {
+    let item = ProgItem::Python(PythonProgItem::TopLevel);
+    let (_, tree) = parse_code(source_code, &item)?;
+    let root = tree.root_node();
+    let mut cursor = root.walk();
+
+    let mut groups: Vec<Vec<Node>> = vec![];
+    let mut current: Vec<Node> = vec![];
+    for node in root.named_children(&mut cursor) {
+        match node.kind() {
+            "comment" => {
+                if !current.is_empty() {
+                    current.push(node);
+                }
+            }
+            "function_definition"
+            | "class_definition"
+            | "decorated_definition"
+            | "import_statement"
+            | "import_from_statement"
+            | "future_import_statement" => {
+                if !current.is_empty() {
+                    groups.push(std::mem::take(&mut current));
+                }
+            }
+            _ => current.push(node),
+        }
+    }
+    if !current.is_empty() {
+        groups.push(current);
+    }
+
+    let language = ProgLanguage::Python;
+    let mut items = Vec::with_capacity(groups.len());
+    for mut group in groups {
+        while group.last().map_or(false, |node| node.kind() == "comment") {
+            group.pop();
+        }
+        let (Some(first), Some(last)) = (group.first(), group.last()) else {
+            continue;
+        };
+        items.push(ItemDef {
+            definition: source_code[first.start_byte()..last.end_byte()].to_string(),
+            start_pos: first.start_position().row,
+            end_pos: last.end_position().row,
+            start_byte: first.start_byte(),
+            end_byte: last.end_byte(),
+            filename: filename.to_path_buf(),
+            complexity: 1 + group
+                .iter()
+                .map(|node| cyclomatic_complexity(*node, &language) - 1)
+                .sum::<usize>(),
+            ..Default::default()
+        });
+    }
+    Ok(items)
+}
+
+/// Whether a code fence info string (the text after ```) marks a Rust doctest
+fn is_rust_code_fence(info: &str) -> bool {
+    info.split(',').map(str::trim).all(|attribute| {
+        matches!(
+            attribute,
+            "" | "rust" | "ignore" | "should_panic" | "no_run" | "compile_fail"
+        ) || attribute.starts_with("edition")
+    })
+}
+
+/// Extracts the code of fenced Rust blocks in `///` and `//!` doc comments. The item covers the
+/// comment lines between the fences, still with their doc comment markers
+pub fn extract_doc_tests(source_code: &str, filename: &Path) -> Vec<ItemDef> {
+    let mut items = Vec::new();
+    // first row and byte of the code in the currently open fence, and whether it is Rust code
+    let mut open_fence: Option<(usize, usize, bool)> = None;
+    let mut offset = 0;
+
+    for (row, line) in source_code.split_inclusive('\n').enumerate() {
+        let line_start = offset;
+        offset += line.len();
+
+        let trimmed = line.trim_start();
+        let doc_text = match trimmed
+            .strip_prefix("///")
+            .or_else(|| trimmed.strip_prefix("//!"))
+        {
+            Some(doc_text) if !doc_text.starts_with('/') => doc_text.trim(),
+            _ => {
+                // the doc comment ended, drop unterminated fences
+                open_fence = None;
+                continue;
+            }
+        };
+
+        let Some(info) = doc_text.strip_prefix("```") else {
+            continue;
+        };
+        match open_fence.take() {
+            None => open_fence = Some((row + 1, offset, is_rust_code_fence(info))),
+            Some((start_pos, start_byte, is_rust)) => {
+                if is_rust && row > start_pos {
+                    // the code ends before the line break preceding the closing fence
+                    let before_fence = &source_code[..line_start];
+                    let before_fence = before_fence.strip_suffix('\n').unwrap_or(before_fence);
+                    let end_byte = before_fence
+                        .strip_suffix('\r')
+                        .unwrap_or(before_fence)
+                        .len();
+                    items.push(ItemDef {
+                        definition: source_code[start_byte..end_byte].to_string(),
+                        start_pos,
+                        end_pos: row - 1,
+                        start_byte,
+                        end_byte,
+                        filename: filename.to_path_buf(),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+    }
+
+    items
+}
+
+/// Name of the first definition in the code (function, class, struct, ...), if it has one
+pub fn item_name(code: &str, item: &ProgItem) -> Option<String> {
+    let code = dedent(code);
+    let (_, tree) = parse_code(&code, item).ok()?;
+    let mut stack = vec![tree.root_node()];
+    while let Some(node) = stack.pop() {
+        let is_definition = !matches!(
+            node.kind(),
+            "decorated_definition" | "attribute_item" | "inner_attribute_item"
+        ) && (node.kind().ends_with("_definition")
+            || node.kind().ends_with("_item")
+            || node.kind().starts_with("create_"));
+        if is_definition {
+            // unnamed definitions like `impl` blocks are not searched any further
+            let name = node
+                .child_by_field_name("name")
+                .or_else(|| create_statement_name(node))?;
+            return name.utf8_text(code.as_bytes()).ok().map(str::to_string);
+        }
+        let mut cursor = node.walk();
+        let children: Vec<Node> = node.named_children(&mut cursor).collect();
+        stack.extend(children.into_iter().rev());
+    }
+    None
+}
+
+/// How much the syntax tree of `new_code` differs from the one of `old_code`, from 0.0 (same
+/// node kinds) to 1.0 (nothing in common). Computed as one minus the weighted Jaccard similarity
+/// of the node kind counts, so reformatting and renaming don't count as a divergence
+pub fn structural_divergence(old_code: &str, new_code: &str, item: &ProgItem) -> Result<f64> {
+    fn node_kinds(code: &str, item: &ProgItem) -> Result<HashMap<&'static str, usize>> {
+        let (_, tree) = parse_code(&dedent(code), item)?;
+        let mut counts = HashMap::new();
+        let mut stack = vec![tree.root_node()];
+        while let Some(node) = stack.pop() {
+            *counts.entry(node.kind()).or_default() += 1;
+            let mut cursor = node.walk();
+            stack.extend(node.named_children(&mut cursor));
+        }
+        Ok(counts)
+    }
+
+    let old_kinds = node_kinds(old_code, item)?;
+    let new_kinds = node_kinds(new_code, item)?;
+    let (mut shared, mut total) = (0, 0);
+    for kind in old_kinds
+        .keys()
+        .chain(new_kinds.keys().filter(|k| !old_kinds.contains_key(*k)))
+    {
+        let old_count = old_kinds.get(kind).copied().unwrap_or(0);
+        let new_count = new_kinds.get(kind).copied().unwrap_or(0);
+        shared += old_count.min(new_count);
+        total += old_count.max(new_count);
+    }
+    Ok(if total == 0 {
+        0.0
+    } else {
+        1.0 - shared as f64 / total as f64
+    })
+}
+
+/// Rough cyclomatic complexity of a node: one plus the number of branching and looping nodes in it
+pub fn cyclomatic_complexity(node: Node, language: &ProgLanguage) -> usize {
+    fn count_branches(node: Node, kinds: &[&str]) -> usize {
+        let mut cursor = node.walk();
+        let own = usize::from(kinds.contains(&node.kind()));
+        let children: usize = node
+            .children(&mut cursor)
+            .map(|child| count_branches(child, kinds))
+            .sum();
+        own + children
+    }
+
+    1 + count_branches(node, &language.branch_node_kinds())
+}
+
 pub fn parse_code(source_code: &str, item: &ProgItem) -> Result<(Language, Tree)> {
+    parse_code_with_filename(source_code, item, None)
+}
+
+/// Parses code with the grammar for the file when given, see
+/// `ProgLanguage::tree_sitter_language_for_file`
+pub fn parse_code_with_filename(
+    source_code: &str,
+    item: &ProgItem,
+    filename: Option<&Path>,
+) -> Result<(Language, Tree)> {
     let mut parser = Parser::new();
     let language_enum: ProgLanguage = (*item).clone().into();
-    let language = language_enum.tree_sitter_language();
-    parser.set_language(language).unwrap();
+    language_enum.check_grammar()?;
+    let language = match filename {
+        Some(filename) => language_enum.tree_sitter_language_for_file(filename),
+        None => language_enum.tree_sitter_language(),
+    };
+    parser.set_language(language).with_context(|| {
+        format!(
+            "Cannot set the tree-sitter language of {}",
+            language_enum.grammar_crate()
+        )
+    })?;
     let tree = parser
         .parse(source_code, None)
         .context("Cannot parse code")?;
     Ok((language, tree))
 }
 
+/// Like `parse_code_with_filename`, but fails on syntax errors, see `check_syntax`
+pub fn parse_code_checked(
+    source_code: &str,
+    item: &ProgItem,
+    filename: Option<&Path>,
+) -> Result<(Language, Tree)> {
+    let (language, tree) = parse_code_with_filename(source_code, item, filename)?;
+    check_syntax(&tree, source_code)?;
+    Ok((language, tree))
+}
+
+/// tree-sitter builds a tree for any input, marking what it couldn't parse with `ERROR` nodes and
+/// inserting `MISSING` nodes for absent tokens. Fails with the location of the first of them
+pub fn check_syntax(tree: &Tree, source_code: &str) -> Result<()> {
+    let mut stack = vec![tree.root_node()];
+    while let Some(node) = stack.pop() {
+        let position = node.start_position();
+        if node.is_missing() {
+            bail!(
+                "Syntax error at line {}, column {}: missing `{}`",
+                position.row + 1,
+                position.column + 1,
+                node.kind()
+            );
+        }
+        if node.is_error() {
+            let code = node
+                .utf8_text(source_code.as_bytes())
+                .unwrap_or_default()
+                .lines()
+                .next()
+                .unwrap_or_default()
+                .trim();
+            bail!(
+                "Syntax error at line {}, column {}: `{}`",
+                position.row + 1,
+                position.column + 1,
+                code
+            );
+        }
+        let mut cursor = node.walk();
+        let children: Vec<Node> = node.children(&mut cursor).collect();
+        stack.extend(children.into_iter().rev());
+    }
+    Ok(())
+}
+
+/// Extracts the items of every file, reading and parsing the files in parallel. The items are
+/// in the order of `files`, as if extracted one file after the other. Files that cannot be read
+/// as UTF-8 text, like binaries or latin-1 sources, are skipped with a warning
 pub fn extract_all_items_from_files(files: Vec<PathBuf>, item: ProgItem) -> Result<Vec<ItemDef>> {
-    let mut all_functions = Vec::new();
-    for file_path in files {
-        let mut file = File::open(&file_path)?;
-        let mut source_code = String::new();
-        file.read_to_string(&mut source_code)?;
+    let items_per_file = files
+        .par_iter()
+        .map(|file_path| match fs::read_to_string(file_path) {
+            // a parser is created for every file, they cannot be shared between threads
+            Ok(source_code) => extract_sexpr_from_string(&source_code, file_path, &item),
+            Err(err) => {
+                warn!("Skipping {}: {}", file_path.display(), err);
+                Ok(vec![])
+            }
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(items_per_file.into_iter().flatten().collect())
+}
+
+/// Extracts the methods of a Python class item, so a class too large for a single request can be
+/// transformed method by method. Functions nested in other functions are left out, they are part
+/// of their enclosing method
+pub fn extract_class_methods(class: &ItemDef) -> Result<Vec<ItemDef>> {
+    let source_code = fs::read_to_string(&class.filename)?;
+    let functions = extract_sexpr_from_string(
+        &source_code,
+        &class.filename,
+        &ProgItem::Python(PythonProgItem::Function),
+    )?;
+
+    let inside_class: Vec<ItemDef> = functions
+        .into_iter()
+        .filter(|function| {
+            function.start_byte >= class.start_byte && function.end_byte <= class.end_byte
+        })
+        .collect();
+
+    let is_nested = |function: &ItemDef| {
+        inside_class.iter().any(|other| {
+            (other.start_byte, other.end_byte) != (function.start_byte, function.end_byte)
+                && other.start_byte <= function.start_byte
+                && function.end_byte <= other.end_byte
+        })
+    };
+    Ok(inside_class
+        .iter()
+        .filter(|function| !is_nested(function))
+        .cloned()
+        .collect())
+}
+
+/// Keeps only the items of a single file: `requested` if given, otherwise the first file with
+/// any items. Returns the kept items and the number of other files that still have items
+pub fn select_one_file(
+    items: Vec<ItemDef>,
+    requested: Option<&Path>,
+) -> Result<(Vec<ItemDef>, usize)> {
+    let selected = match requested {
+        Some(path) => Some(
+            fs::canonicalize(path).with_context(|| format!("Cannot resolve path {:?}", path))?,
+        ),
+        None => match items.first() {
+            Some(item) => Some(fs::canonicalize(&item.filename)?),
+            None => None,
+        },
+    };
 
-        all_functions.extend(extract_sexpr_from_string(&source_code, &file_path, &item)?);
+    let mut other_files = Vec::new();
+    let mut selected_items = Vec::new();
+    for item in items {
+        let filename = fs::canonicalize(&item.filename)?;
+        if Some(&filename) == selected.as_ref() {
+            selected_items.push(item);
+        } else if !other_files.contains(&filename) {
+            other_files.push(filename);
+        }
     }
-    Ok(all_functions)
+    Ok((selected_items, other_files.len()))
+}
+
+/// Original and changed contents of a file touched by `ItemChange`s
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileChange {
+    /// canonical path of the file
+    pub path: PathBuf,
+    pub before: String,
+    pub after: String,
 }
 
-pub fn apply_changes(changes: Vec<ItemChange>) -> Result<()> {
-    // Group changes by file
+/// Computes the new contents of every file touched by the changes, without writing them
+pub fn compute_file_changes(
+    changes: Vec<ItemChange>,
+    skip_overlaps: bool,
+) -> Result<Vec<FileChange>> {
+    // Group changes by file, canonicalizing so that different representations of the
+    // same path (relative, absolute, with `..`) don't end up overwriting each other
     let mut changes_by_file: HashMap<PathBuf, Vec<ItemChange>> = HashMap::new();
     for change in changes {
-        changes_by_file
-            .entry(change.before.filename.clone())
-            .or_default()
-            .push(change);
+        let file_path = fs::canonicalize(&change.before.filename)
+            .with_context(|| format!("Cannot resolve path {:?}", change.before.filename))?;
+        changes_by_file.entry(file_path).or_default().push(change);
     }
 
-    // Apply changes to each file
+    let mut file_changes = Vec::with_capacity(changes_by_file.len());
     for (file_path, changes) in changes_by_file.iter() {
-        // Read the file contents line by line
         let contents = fs::read_to_string(file_path)?;
-        let mut lines: Vec<String> = contents.lines().map(|line| line.to_string()).collect();
+        let mut after = contents.clone();
+        let line_ending = dominant_line_ending(&contents);
 
-        // Sort changes in descending order by start_pos, so that changes later in the file do not affect the position of earlier changes
-        let mut changes = changes.clone();
-        changes.sort_by(|a, b| b.before.start_pos.cmp(&a.before.start_pos));
+        // Sort changes in descending order by start_byte, so that changes later in the file do
+        // not affect the position of earlier changes
+        let mut changes = remove_overlaps(file_path, changes.clone(), skip_overlaps)?;
+        changes.sort_by(|a, b| b.before.start_byte.cmp(&a.before.start_byte));
 
-        // Apply changes
         for change in changes {
-            let start_line = change.before.start_pos;
-            let end_line = change.before.end_pos;
-
-            if start_line <= end_line && end_line < lines.len() {
-                // Apply the same indentation to the new code
-                let indented_new_code = apply_indentation(&change.before.definition, &change.after);
-                // Concatenate the new lines and replace the corresponding lines in the original content
-                let replacement_lines: Vec<String> = indented_new_code
-                    .lines()
-                    .map(|line| line.to_string())
-                    .collect();
-                lines.splice(start_line..=end_line, replacement_lines.iter().cloned());
+            let range = change.before.start_byte..change.before.end_byte;
+            if contents.get(range.clone()) != Some(change.before.definition.as_str()) {
+                bail!(
+                    "{:?} changed since the items were extracted, lines {}-{} don't match anymore",
+                    file_path,
+                    change.before.start_pos + 1,
+                    change.before.end_pos + 1
+                );
+            }
+
+            // Apply the same indentation to the new code
+            let new_code = normalize_indentation_style(&change.before.definition, &change.after);
+            let mut indented_new_code = apply_indentation(&change.before.definition, &new_code);
+            if !change.before.definition.ends_with('\n') {
+                indented_new_code.pop();
             }
+            after.replace_range(range, &with_line_ending(&indented_new_code, line_ending));
         }
 
-        // Write the modified contents back to the file
-        let mut file = fs::File::create(file_path)?;
-        for line in lines {
-            writeln!(file, "{}", line)?;
+        file_changes.push(FileChange {
+            path: file_path.clone(),
+            before: contents,
+            after,
+        });
+    }
+    file_changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(file_changes)
+}
+
+/// `\r\n` if most lines of `contents` end with it, `\n` otherwise
+fn dominant_line_ending(contents: &str) -> &'static str {
+    let crlf = contents.matches("\r\n").count();
+    let lf = contents.matches('\n').count() - crlf;
+    if crlf > lf {
+        "\r\n"
+    } else {
+        "\n"
+    }
+}
+
+/// The model answers with `\n`, so the new code is converted to the line ending of the file
+/// to avoid a diff on every line of Windows-authored files
+fn with_line_ending(code: &str, line_ending: &str) -> String {
+    code.replace("\r\n", "\n").replace('\n', line_ending)
+}
+
+/// Splicing overlapping changes (e.g. an `impl` and a function inside it) corrupts the file.
+/// Fails naming both definitions, or with `skip` keeps the outer change and drops the inner one
+fn remove_overlaps(
+    file_path: &Path,
+    changes: Vec<ItemChange>,
+    skip: bool,
+) -> Result<Vec<ItemChange>> {
+    let (kept, overlapping) = split_overlaps(changes, |change| {
+        (change.before.start_byte, change.before.end_byte)
+    });
+    if let Some((outer, inner)) = overlapping.first().filter(|_| !skip) {
+        let describe = |item: &ItemDef| {
+            format!(
+                "`{}` (lines {}-{})",
+                item.definition.lines().next().unwrap_or_default().trim(),
+                item.start_pos + 1,
+                item.end_pos + 1
+            )
+        };
+        bail!(
+            "Overlapping changes in {:?}: {} and {}. Use --skip-overlaps to keep only the outer one",
+            file_path,
+            describe(&kept[*outer].before),
+            describe(&inner.before)
+        );
+    }
+    Ok(kept)
+}
+
+/// How `apply_changes` writes the changes
+#[derive(Clone, Debug, Default)]
+pub struct ApplyOptions {
+    /// only print the diff of the changes
+    pub dry_run: bool,
+    /// copy every file to `<filename>.mechatyper.bak` before changing it
+    pub backup: bool,
+    /// drop changes nested in (or overlapping) an earlier change instead of failing
+    pub skip_overlaps: bool,
+}
+
+/// Path for a backup of `path` that doesn't exist yet: `<filename>.mechatyper.bak`, then
+/// `<filename>.mechatyper.1.bak`, `<filename>.mechatyper.2.bak`, ...
+pub fn backup_path(path: &Path) -> PathBuf {
+    let filename = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let mut backup = path.with_file_name(format!("{}.mechatyper.bak", filename));
+    let mut counter = 1;
+    while backup.exists() {
+        backup = path.with_file_name(format!("{}.mechatyper.{}.bak", filename, counter));
+        counter += 1;
+    }
+    backup
+}
+
+/// A file changed by `apply_changes`
+#[derive(Clone, Debug, PartialEq)]
+pub struct WrittenFile {
+    pub path: PathBuf,
+    /// copy of the original file, with `ApplyOptions::backup`
+    pub backup: Option<PathBuf>,
+}
+
+/// Writes the changes to the files, see `ApplyOptions`. Returns the files written
+pub fn apply_changes(changes: Vec<ItemChange>, options: &ApplyOptions) -> Result<Vec<WrittenFile>> {
+    let file_changes = compute_file_changes(changes, options.skip_overlaps)?;
+    if options.dry_run {
+        print!("{}", unified_patch(&file_changes));
+        return Ok(vec![]);
+    }
+
+    let mut backups = Vec::with_capacity(file_changes.len());
+    for file_change in &file_changes {
+        if !options.backup {
+            backups.push(None);
+            continue;
         }
+        let backup = backup_path(&file_change.path);
+        fs::copy(&file_change.path, &backup)
+            .with_context(|| format!("Cannot back up {:?} to {:?}", file_change.path, backup))?;
+        backups.push(Some(backup));
     }
 
+    let mut written = Vec::with_capacity(file_changes.len());
+    for (file_change, backup) in file_changes.into_iter().zip(backups) {
+        if let Some(backup) = &backup {
+            debug!("Backed up {:?} to {:?}", file_change.path, backup);
+        }
+        if file_change.before == file_change.after {
+            debug!("{:?} is unchanged", file_change.path);
+        } else {
+            info!("Writing {:?}", file_change.path);
+        }
+        // Write the modified contents back to the file
+        let mut file = fs::File::create(&file_change.path)?;
+        file.write_all(file_change.after.as_bytes())?;
+        written.push(WrittenFile {
+            path: file_change.path,
+            backup,
+        });
+    }
+
+    Ok(written)
+}
+
+/// Puts the backups made by `apply_changes` back in place of the changed files
+pub fn restore_backups(written: &[WrittenFile]) -> Result<()> {
+    for file in written {
+        if let Some(backup) = &file.backup {
+            fs::copy(backup, &file.path)
+                .with_context(|| format!("Cannot restore {:?} from {:?}", file.path, backup))?;
+        }
+    }
     Ok(())
 }
 
+/// A unified diff of the changes that `git apply` and `patch -p1` accept. Paths are relative to
+/// the root of the git repository of each file
+pub fn unified_patch(file_changes: &[FileChange]) -> String {
+    let mut patch = String::new();
+    for file_change in file_changes {
+        if file_change.before == file_change.after {
+            continue;
+        }
+        let root = find_git_directory(file_change.path.clone()).unwrap_or_default();
+        let relative = file_change
+            .path
+            .strip_prefix(&root)
+            .unwrap_or(&file_change.path)
+            .display()
+            .to_string();
+
+        patch.push_str(&format!("diff --git a/{0} b/{0}\n", relative));
+        patch.push_str(
+            &TextDiff::from_lines(&file_change.before, &file_change.after)
+                .unified_diff()
+                .context_radius(3)
+                .header(&format!("a/{}", relative), &format!("b/{}", relative))
+                .to_string(),
+        );
+    }
+    patch
+}
+
 #[cfg(test)]
 mod tests {
     use std::fs::{self, File};
@@ -217,7 +1103,10 @@ mod tests {
 
     use tempfile::tempdir;
 
-    use crate::lang::PythonProgItem;
+    use crate::lang::{
+        CProgItem, CppProgItem, GoProgItem, JavaProgItem, JavaScriptProgItem, RubyProgItem,
+        SqlProgItem, TypeScriptProgItem,
+    };
 
     use super::*;
 
@@ -241,12 +1130,13 @@ mod tests {
                 start_byte: 0,
                 end_byte: initial_content.len(),
                 filename: file_path.clone(),
+                ..Default::default()
             },
             after: "fn modified_example() {\n    println!(\"Hello, ChatGPT!\");\n}\n".to_string(),
         }];
 
         // Apply changes
-        let apply_result = apply_changes(changes);
+        let apply_result = apply_changes(changes, &ApplyOptions::default());
 
         // Assert that there were no errors
         assert!(apply_result.is_ok());
@@ -259,6 +1149,1690 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_apply_changes_preserves_crlf() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("lib.rs");
+        let initial_content = "// header\r\nfn a() {\r\n    1;\r\n}\r\n\r\nfn b() {}\r\n";
+        fs::write(&file_path, initial_content).unwrap();
+
+        let items = extract_sexpr_from_string(
+            initial_content,
+            &file_path,
+            &ProgItem::Rust(RustProgItem::Function),
+        )
+        .unwrap();
+        let changes = vec![ItemChange {
+            before: items[0].clone(),
+            after: "fn a() {\n    2;\n    3;\n}\n".to_string(),
+        }];
+        apply_changes(changes, &ApplyOptions::default()).unwrap();
+
+        let modified_content = fs::read_to_string(&file_path).unwrap();
+        assert_eq!(
+            modified_content,
+            "// header\r\nfn a() {\r\n    2;\r\n    3;\r\n}\r\n\r\nfn b() {}\r\n"
+        );
+        assert_eq!(modified_content.matches('\n').count(), 7);
+        assert_eq!(modified_content.matches("\r\n").count(), 7);
+    }
+
+    #[test]
+    fn test_apply_changes_keeps_the_trailing_newline_as_is() {
+        let dir = tempdir().unwrap();
+        for (initial_content, expected) in [
+            (
+                "def a():\n    return 1\n\ndef b():\n    return 2",
+                "def a():\n    return 10\n\ndef b():\n    return 20",
+            ),
+            (
+                "def a():\n    return 1\n\ndef b():\n    return 2\n",
+                "def a():\n    return 10\n\ndef b():\n    return 20\n",
+            ),
+        ] {
+            let file_path = dir.path().join("a.py");
+            fs::write(&file_path, initial_content).unwrap();
+            let items = extract_sexpr_from_string(
+                initial_content,
+                &file_path,
+                &ProgItem::Python(PythonProgItem::Function),
+            )
+            .unwrap();
+            let changes = vec![
+                ItemChange {
+                    before: items[0].clone(),
+                    after: "def a():\n    return 10\n".to_string(),
+                },
+                ItemChange {
+                    before: items[1].clone(),
+                    after: "def b():\n    return 20".to_string(),
+                },
+            ];
+            apply_changes(changes, &ApplyOptions::default()).unwrap();
+
+            assert_eq!(fs::read(&file_path).unwrap(), expected.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_dominant_line_ending() {
+        assert_eq!(dominant_line_ending("a\nb\n"), "\n");
+        assert_eq!(dominant_line_ending("a\r\nb\r\nc\n"), "\r\n");
+        assert_eq!(dominant_line_ending("a"), "\n");
+        assert_eq!(with_line_ending("a\nb\r\n", "\r\n"), "a\r\nb\r\n");
+        assert_eq!(with_line_ending("a\r\nb\n", "\n"), "a\nb\n");
+    }
+
+    #[test]
+    fn test_apply_changes_to_items_on_the_same_line() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("lib.rs");
+        let initial_content = "fn a() -> i32 { 1 } fn b() -> i32 { 2 }\nfn c() {}\n";
+        fs::write(&file_path, initial_content).unwrap();
+
+        let items = extract_sexpr_from_string(
+            initial_content,
+            &file_path,
+            &ProgItem::Rust(RustProgItem::Function),
+        )
+        .unwrap();
+        // both functions are on line 0, a line based splice would replace it twice
+        assert_eq!((items[0].start_pos, items[1].start_pos), (0, 0));
+        let changes = vec![
+            ItemChange {
+                before: items[0].clone(),
+                after: "fn a() -> i32 { 10 }".to_string(),
+            },
+            ItemChange {
+                before: items[1].clone(),
+                after: "fn b() -> i32 { 20 }".to_string(),
+            },
+        ];
+        apply_changes(changes, &ApplyOptions::default()).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&file_path).unwrap(),
+            "fn a() -> i32 { 10 } fn b() -> i32 { 20 }\nfn c() {}\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_overlapping_changes() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("lib.rs");
+        let initial_content = "impl A {\n    fn a() {}\n}\n";
+        fs::write(&file_path, initial_content).unwrap();
+
+        let item = |definition: &str, start_pos, end_pos| {
+            let start_byte = initial_content.find(definition).unwrap();
+            ItemDef {
+                definition: definition.to_string(),
+                start_pos,
+                end_pos,
+                start_byte,
+                end_byte: start_byte + definition.len(),
+                filename: file_path.clone(),
+                ..Default::default()
+            }
+        };
+        let changes = vec![
+            ItemChange {
+                before: item("    fn a() {}\n", 1, 1),
+                after: "fn a() { inner() }\n".to_string(),
+            },
+            ItemChange {
+                before: item(initial_content, 0, 2),
+                after: "impl A {\n    fn a() { outer() }\n}\n".to_string(),
+            },
+        ];
+
+        let err = apply_changes(changes.clone(), &ApplyOptions::default()).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("`impl A {` (lines 1-3)"), "{}", message);
+        assert!(message.contains("`fn a() {}` (lines 2-2)"), "{}", message);
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), initial_content);
+
+        let options = ApplyOptions {
+            skip_overlaps: true,
+            ..Default::default()
+        };
+        apply_changes(changes, &options).unwrap();
+        assert_eq!(
+            fs::read_to_string(&file_path).unwrap(),
+            "impl A {\n    fn a() { outer() }\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_changes_dry_run() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("a.py");
+        fs::write(&file_path, "def a():\n    return 1\n").unwrap();
+
+        let changes = vec![ItemChange {
+            before: ItemDef {
+                definition: "def a():\n    return 1\n".to_string(),
+                start_pos: 0,
+                end_pos: 1,
+                start_byte: 0,
+                end_byte: 22,
+                filename: file_path.clone(),
+                ..Default::default()
+            },
+            after: "def a():\n    return 2\n".to_string(),
+        }];
+        let options = ApplyOptions {
+            dry_run: true,
+            ..Default::default()
+        };
+        apply_changes(changes, &options).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(&file_path).unwrap(),
+            "def a():\n    return 1\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_changes_backup() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("a.py");
+        // CRLF and no trailing newline, the backup must keep the exact bytes
+        let original = "def a():\r\n    return 1";
+        fs::write(&file_path, original).unwrap();
+        fs::write(dir.path().join("a.py.mechatyper.bak"), "older backup").unwrap();
+
+        let changes = vec![ItemChange {
+            before: ItemDef {
+                definition: "def a():\r\n    return 1".to_string(),
+                start_pos: 0,
+                end_pos: 1,
+                start_byte: 0,
+                end_byte: original.len(),
+                filename: file_path.clone(),
+                ..Default::default()
+            },
+            after: "def a():\n    return 2\n".to_string(),
+        }];
+        let options = ApplyOptions {
+            backup: true,
+            ..Default::default()
+        };
+        let written = apply_changes(changes, &options).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dir.path().join("a.py.mechatyper.bak")).unwrap(),
+            "older backup"
+        );
+        assert_eq!(
+            fs::read(dir.path().join("a.py.mechatyper.1.bak")).unwrap(),
+            original.as_bytes()
+        );
+        assert_eq!(
+            fs::read_to_string(&file_path).unwrap(),
+            "def a():\r\n    return 2"
+        );
+
+        restore_backups(&written).unwrap();
+        assert_eq!(fs::read(&file_path).unwrap(), original.as_bytes());
+    }
+
+    #[test]
+    fn test_unified_patch() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join(".git")).unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        let file_path = dir.path().join("src").join("a.py");
+        fs::write(&file_path, "import os\n\ndef a():\n    return 1\n").unwrap();
+
+        let file_changes = compute_file_changes(
+            vec![ItemChange {
+                before: ItemDef {
+                    definition: "def a():\n    return 1\n".to_string(),
+                    start_pos: 2,
+                    end_pos: 3,
+                    start_byte: 11,
+                    end_byte: 33,
+                    filename: file_path.clone(),
+                    ..Default::default()
+                },
+                after: "def a():\n    return 2\n".to_string(),
+            }],
+            false,
+        )
+        .unwrap();
+        // nothing is written
+        assert_eq!(
+            fs::read_to_string(&file_path).unwrap(),
+            "import os\n\ndef a():\n    return 1\n"
+        );
+
+        assert_eq!(
+            unified_patch(&file_changes),
+            "diff --git a/src/a.py b/src/a.py\n\
+             --- a/src/a.py\n\
+             +++ b/src/a.py\n\
+             @@ -1,4 +1,4 @@\n \
+             import os\n \n \
+             def a():\n\
+             -    return 1\n\
+             +    return 2\n"
+        );
+    }
+
+    #[test]
+    fn test_apply_changes_groups_different_paths_to_same_file() {
+        let dir = tempdir().unwrap();
+        fs::create_dir(dir.path().join("nested")).unwrap();
+        let file_path = dir.path().join("test_file.py");
+        let initial_content = "def a():\n    return 1\n\ndef b():\n    return 2\n";
+        fs::write(&file_path, initial_content).unwrap();
+
+        let item = |definition: &str, start_pos, end_pos, filename: PathBuf| {
+            let start_byte = initial_content.find(definition).unwrap();
+            ItemDef {
+                definition: definition.to_string(),
+                start_pos,
+                end_pos,
+                start_byte,
+                end_byte: start_byte + definition.len(),
+                filename,
+                ..Default::default()
+            }
+        };
+
+        // The same file referenced through two different path representations
+        let changes = vec![
+            ItemChange {
+                before: item("def a():\n    return 1\n", 0, 1, file_path.clone()),
+                after: "def a():\n    return 10\n".to_string(),
+            },
+            ItemChange {
+                before: item(
+                    "def b():\n    return 2\n",
+                    3,
+                    4,
+                    dir.path().join("nested/../test_file.py"),
+                ),
+                after: "def b():\n    return 20\n".to_string(),
+            },
+        ];
+
+        apply_changes(changes, &ApplyOptions::default()).unwrap();
+
+        let modified_content = fs::read_to_string(&file_path).unwrap();
+        assert_eq!(
+            modified_content,
+            "def a():\n    return 10\n\ndef b():\n    return 20\n"
+        );
+    }
+
+    #[test]
+    fn test_extract_javascript_items() {
+        let code = r#"function add(a, b) {
+  return a + b;
+}
+
+const double = (x) => x * 2;
+
+class Counter {
+  increment() {
+    this.count += 1;
+  }
+}
+"#;
+        let extract = |item| {
+            extract_sexpr_from_string(
+                code,
+                &PathBuf::from("index.js"),
+                &ProgItem::JavaScript(item),
+            )
+            .unwrap()
+            .into_iter()
+            .map(|item| item.definition)
+            .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            extract(JavaScriptProgItem::Function),
+            vec!["function add(a, b) {\n  return a + b;\n}"]
+        );
+        assert_eq!(
+            extract(JavaScriptProgItem::ArrowFunction),
+            vec!["(x) => x * 2"]
+        );
+        assert_eq!(extract(JavaScriptProgItem::Class).len(), 1);
+        assert_eq!(
+            extract(JavaScriptProgItem::Method),
+            vec!["  increment() {\n    this.count += 1;\n  }"]
+        );
+    }
+
+    #[test]
+    fn test_extract_typescript_items() {
+        let code = r#"export interface User {
+  id: number;
+  name: string;
+}
+
+type UserId = User["id"];
+
+function greet(user: User): string {
+  return `Hello ${user.name}`;
+}
+"#;
+        let extract = |item, filename: &str| {
+            extract_sexpr_from_string(code, &PathBuf::from(filename), &ProgItem::TypeScript(item))
+                .unwrap()
+                .into_iter()
+                .map(|item| item.definition)
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            extract(TypeScriptProgItem::Interface, "user.ts"),
+            vec!["interface User {\n  id: number;\n  name: string;\n}"]
+        );
+        assert_eq!(
+            extract(TypeScriptProgItem::TypeAlias, "user.ts"),
+            vec!["type UserId = User[\"id\"];"]
+        );
+        assert_eq!(extract(TypeScriptProgItem::Function, "user.ts").len(), 1);
+
+        // JSX only parses with the tsx grammar
+        let component =
+            "function App(): JSX.Element {\n  return <div className=\"app\">hi</div>;\n}\n";
+        let item = ProgItem::TypeScript(TypeScriptProgItem::Function);
+        let (_, tree) =
+            parse_code_with_filename(component, &item, Some(Path::new("App.tsx"))).unwrap();
+        assert!(!tree.root_node().has_error());
+        let (_, tree) =
+            parse_code_with_filename(component, &item, Some(Path::new("App.ts"))).unwrap();
+        assert!(tree.root_node().has_error());
+    }
+
+    #[test]
+    fn test_extract_components() {
+        let code = r#"export default function App() {
+  return <Layout title="Home" />;
+}
+
+function Card({ user }) {
+  if (!user) {
+    return <Empty />;
+  }
+  return null;
+}
+
+const Button = ({ label }) => (
+  <button>{label}</button>
+);
+
+function formatName(user) {
+  return <b>{user.name}</b>;
+}
+
+function Config() {
+  return { debug: true };
+}
+
+const Total = (items) => items.length;
+"#;
+        let names = |item: ProgItem, filename: &str| {
+            extract_sexpr_from_string(code, &PathBuf::from(filename), &item)
+                .unwrap()
+                .into_iter()
+                .map(|item| item.definition.lines().next().unwrap().to_string())
+                .collect::<Vec<String>>()
+        };
+        // PascalCase and returning JSX, helpers and other PascalCase functions are left out
+        let components = vec![
+            "function App() {",
+            "function Card({ user }) {",
+            "const Button = ({ label }) => (",
+        ];
+        assert_eq!(
+            names(
+                ProgItem::JavaScript(JavaScriptProgItem::Component),
+                "App.jsx"
+            ),
+            components
+        );
+        assert_eq!(
+            names(
+                ProgItem::TypeScript(TypeScriptProgItem::Component),
+                "App.tsx"
+            ),
+            components
+        );
+        let item = ProgItem::TypeScript(TypeScriptProgItem::Component);
+        assert!(names(item, "app.ts").is_empty());
+    }
+
+    #[test]
+    fn test_extract_go_items() {
+        let code = r#"package shapes
+
+type Circle struct {
+	Radius float64
+}
+
+type Shape interface {
+	Area() float64
+}
+
+func (c *Circle) Area() float64 {
+	return math.Pi * c.Radius * c.Radius
+}
+
+func NewCircle(radius float64) *Circle {
+	return &Circle{Radius: radius}
+}
+"#;
+        let extract = |item| {
+            extract_sexpr_from_string(code, &PathBuf::from("shapes.go"), &ProgItem::Go(item))
+                .unwrap()
+        };
+
+        let methods = extract(GoProgItem::Method);
+        assert_eq!(methods.len(), 1);
+        assert_eq!(
+            methods[0].definition,
+            "func (c *Circle) Area() float64 {\n\treturn math.Pi * c.Radius * c.Radius\n}"
+        );
+        assert_eq!((methods[0].start_pos, methods[0].end_pos), (10, 12));
+
+        let functions = extract(GoProgItem::Function);
+        assert_eq!(functions.len(), 1);
+        assert!(functions[0].definition.starts_with("func NewCircle"));
+        assert_eq!(
+            extract(GoProgItem::Struct)[0].definition,
+            "type Circle struct {\n\tRadius float64\n}"
+        );
+        assert!(extract(GoProgItem::Interface)[0]
+            .definition
+            .starts_with("type Shape interface"));
+    }
+
+    #[test]
+    fn test_extract_c_items() {
+        let code = r#"#include <stdio.h>
+
+typedef struct {
+    int x;
+} point;
+
+struct buffer {
+    char *data;
+    size_t len;
+};
+
+enum color { RED, GREEN };
+
+static const char *
+read_line(struct buffer *buffer,
+          size_t max_len,
+          int flags)
+{
+    struct buffer copy = *buffer;
+    return copy.data;
+}
+"#;
+        let extract = |item| {
+            extract_sexpr_from_string(code, &PathBuf::from("buffer.h"), &ProgItem::C(item)).unwrap()
+        };
+
+        let functions = extract(CProgItem::Function);
+        assert_eq!(functions.len(), 1);
+        // the multi-line signature starts at the return type
+        assert!(functions[0].definition.starts_with(
+            "static const char *\nread_line(struct buffer *buffer,\n          size_t max_len,"
+        ));
+        assert!(functions[0].definition.ends_with("return copy.data;\n}"));
+        assert_eq!((functions[0].start_pos, functions[0].end_pos), (13, 20));
+        assert_eq!(functions[0].name.as_deref(), Some("read_line"));
+
+        // `struct buffer copy` in the function is not a definition
+        let structs = extract(CProgItem::Struct);
+        assert_eq!(structs.len(), 2);
+        assert_eq!(
+            structs[1].definition,
+            "struct buffer {\n    char *data;\n    size_t len;\n}"
+        );
+        assert_eq!(structs[1].name.as_deref(), Some("buffer"));
+
+        assert_eq!(
+            extract(CProgItem::Enum)[0].definition,
+            "enum color { RED, GREEN }"
+        );
+        let typedefs = extract(CProgItem::Typedef);
+        assert!(typedefs[0].definition.starts_with("typedef struct {"));
+        assert_eq!(typedefs[0].name.as_deref(), Some("point"));
+    }
+
+    #[test]
+    fn test_extract_cpp_items() {
+        let code = r#"namespace geo {
+
+class Circle : public Shape {
+public:
+    double area() const {
+        return 3.14 * r * r;
+    }
+
+    template <typename T>
+    T scaled(T factor) const { return r * factor; }
+
+private:
+    double r;
+};
+
+double Circle::perimeter() const {
+    return 2 * 3.14 * r;
+}
+
+Circle &Circle::grow() {
+    r += 1;
+    return *this;
+}
+
+template <typename T>
+T max(T a, T b) {
+    return a > b ? a : b;
+}
+
+template <typename T> T min(T a, T b) { return a < b ? a : b; }
+
+}  // namespace geo
+"#;
+        let extract = |item| {
+            extract_sexpr_from_string(code, &PathBuf::from("circle.cpp"), &ProgItem::Cpp(item))
+                .unwrap()
+        };
+
+        let methods = extract(CppProgItem::Method);
+        let names: Vec<&str> = methods
+            .iter()
+            .filter_map(|method| method.name.as_deref())
+            .collect();
+        assert_eq!(
+            names,
+            vec!["area", "scaled", "Circle::perimeter", "Circle::grow"]
+        );
+        // inline, with the indentation of the class body
+        assert_eq!(
+            methods[0].definition,
+            "    double area() const {\n        return 3.14 * r * r;\n    }"
+        );
+        assert_eq!(
+            methods[0].parent_scopes,
+            vec![
+                "namespace geo".to_string(),
+                "class Circle : public Shape".to_string()
+            ]
+        );
+        assert!(methods[1]
+            .definition
+            .starts_with("    template <typename T>\n    T scaled(T factor)"));
+        // out of line
+        assert_eq!(
+            methods[2].definition,
+            "double Circle::perimeter() const {\n    return 2 * 3.14 * r;\n}"
+        );
+        assert!(methods[3]
+            .definition
+            .starts_with("Circle &Circle::grow() {"));
+
+        // templated functions start at the `template<...>` line, also when on the same line
+        let functions = extract(CppProgItem::Function);
+        let max = functions
+            .iter()
+            .find(|function| function.name.as_deref() == Some("max"))
+            .unwrap();
+        assert_eq!(
+            max.definition,
+            "template <typename T>\nT max(T a, T b) {\n    return a > b ? a : b;\n}"
+        );
+        assert_eq!(max.start_pos, 24);
+        assert_eq!(&code[max.start_byte..max.end_byte], max.definition);
+        let min = functions
+            .iter()
+            .find(|function| function.name.as_deref() == Some("min"))
+            .unwrap();
+        assert!(min.definition.starts_with("template <typename T> T min("));
+
+        let classes = extract(CppProgItem::Class);
+        assert_eq!(classes.len(), 1);
+        assert_eq!(classes[0].name.as_deref(), Some("Circle"));
+        assert!(extract(CppProgItem::Struct).is_empty());
+    }
+
+    #[test]
+    fn test_extract_ruby_items() {
+        let code = r#"module Billing
+  class Invoice < ApplicationRecord
+    def self.create_for(order)
+      new(order: order)
+    end
+
+    def total
+      lines.sum(&:amount)
+    end
+  end
+
+  def charge(amount)
+    raise ArgumentError if amount.negative?
+  end
+end
+"#;
+        let extract = |item| {
+            extract_sexpr_from_string(code, &PathBuf::from("invoice.rb"), &ProgItem::Ruby(item))
+                .unwrap()
+        };
+
+        let methods = extract(RubyProgItem::Method);
+        assert_eq!(methods.len(), 2);
+        // a method of the module, from `def` to `end`
+        assert_eq!(
+            methods[1].definition,
+            "  def charge(amount)\n    raise ArgumentError if amount.negative?\n  end"
+        );
+        assert_eq!(methods[1].name.as_deref(), Some("charge"));
+        assert_eq!(methods[1].parent_scopes, vec!["module Billing".to_string()]);
+        assert_eq!(
+            methods[0].parent_scopes,
+            vec![
+                "module Billing".to_string(),
+                "class Invoice < ApplicationRecord".to_string()
+            ]
+        );
+
+        let singleton_methods = extract(RubyProgItem::SingletonMethod);
+        assert_eq!(singleton_methods.len(), 1);
+        assert!(singleton_methods[0]
+            .definition
+            .starts_with("    def self.create_for(order)"));
+
+        assert_eq!(
+            extract(RubyProgItem::Class)[0].name.as_deref(),
+            Some("Invoice")
+        );
+        let modules = extract(RubyProgItem::Module);
+        assert_eq!(modules[0].definition, code.trim_end());
+    }
+
+    #[test]
+    fn test_extract_all_items() {
+        let code = r#"pub struct Point {
+    x: i32,
+}
+
+enum Shape {
+    Circle(Point),
+}
+
+impl Point {
+    fn norm(&self) -> i32 {
+        self.x.abs()
+    }
+}
+
+fn main() {}
+"#;
+        let items = extract_sexpr_from_string(
+            code,
+            &PathBuf::from("shapes.rs"),
+            &ProgItem::All(ProgLanguage::Rust),
+        )
+        .unwrap();
+        let first_lines: Vec<_> = items
+            .iter()
+            .map(|item| item.definition.lines().next().unwrap())
+            .collect();
+        // the struct and the enum in a single pass, the method only as part of its impl
+        assert_eq!(
+            first_lines,
+            vec![
+                "pub struct Point {",
+                "enum Shape {",
+                "impl Point {",
+                "fn main() {}"
+            ]
+        );
+
+        let python = "def numbers():\n    for i in range(3):\n        yield i\n\n\ndef squares():\n    return [i * i for i in range(3)]\n";
+        let extract = |item| {
+            extract_sexpr_from_string(python, &PathBuf::from("a.py"), &ProgItem::Python(item))
+                .unwrap()
+        };
+        let generators = extract(PythonProgItem::Generator);
+        assert_eq!(generators.len(), 1);
+        assert_eq!(generators[0].name.as_deref(), Some("numbers"));
+        assert_eq!(
+            extract(PythonProgItem::Comprehension)[0].definition,
+            "[i * i for i in range(3)]"
+        );
+
+        // the queries of every item kind are valid together
+        for language in [
+            ProgLanguage::Python,
+            ProgLanguage::Rust,
+            ProgLanguage::JavaScript,
+            ProgLanguage::TypeScript,
+            ProgLanguage::Go,
+            ProgLanguage::C,
+            ProgLanguage::Cpp,
+            ProgLanguage::Ruby,
+            ProgLanguage::Java,
+            ProgLanguage::Sql,
+        ] {
+            let file = PathBuf::from(format!("a.{}", language.file_extensions()[0]));
+            let item = ProgItem::All(language.clone());
+            assert!(
+                extract_sexpr_from_string("", &file, &item).is_ok(),
+                "{:?}",
+                language
+            );
+        }
+    }
+
+    #[test]
+    fn test_extract_java_items() {
+        let code = r#"package com.example.billing;
+
+public class Invoice {
+    private final List<Line> lines;
+
+    /** Sum of the lines. */
+    public int total() {
+        return lines.stream().mapToInt(Line::amount).sum();
+    }
+
+    static class Line {
+        @Override
+        public String toString() {
+            return "line";
+        }
+    }
+}
+
+interface Payable {
+    void pay(int amount);
+}
+
+enum Status { OPEN, PAID }
+"#;
+        let extract = |item| {
+            extract_sexpr_from_string(code, &PathBuf::from("Invoice.java"), &ProgItem::Java(item))
+                .unwrap()
+        };
+
+        let methods = extract(JavaProgItem::Method);
+        let names: Vec<_> = methods.iter().map(|m| m.name.as_deref().unwrap()).collect();
+        assert_eq!(names, vec!["total", "toString", "pay"]);
+        // the Javadoc comment comes along
+        assert!(methods[0]
+            .definition
+            .starts_with("    /** Sum of the lines. */\n    public int total() {"));
+        // a method of the nested class, with its annotation
+        assert_eq!(
+            methods[1].definition,
+            "        @Override\n        public String toString() {\n            return \"line\";\n        }"
+        );
+        assert_eq!(
+            methods[1].parent_scopes,
+            vec![
+                "public class Invoice".to_string(),
+                "static class Line".to_string()
+            ]
+        );
+
+        let classes = extract(JavaProgItem::Class);
+        assert_eq!(classes.len(), 2);
+        assert_eq!(classes[0].name.as_deref(), Some("Invoice"));
+        assert_eq!(
+            extract(JavaProgItem::Interface)[0].name.as_deref(),
+            Some("Payable")
+        );
+        assert_eq!(
+            extract(JavaProgItem::Enum)[0].definition,
+            "enum Status { OPEN, PAID }"
+        );
+    }
+
+    #[test]
+    fn test_extract_sql_items() {
+        let code = r#"-- Adds two numbers
+CREATE FUNCTION add(a integer, b integer) RETURNS integer
+    LANGUAGE sql IMMUTABLE
+    AS $$ SELECT a + b $$;
+
+CREATE TABLE users (
+    id integer PRIMARY KEY,
+    name text NOT NULL
+);
+"#;
+        let extract = |item| {
+            extract_sexpr_from_string(code, &PathBuf::from("schema.sql"), &ProgItem::Sql(item))
+                .unwrap()
+        };
+
+        let functions = extract(SqlProgItem::CreateFunction);
+        assert_eq!(functions.len(), 1);
+        assert_eq!(functions[0].name.as_deref(), Some("add"));
+        // the `;` stays in the file
+        assert_eq!(
+            functions[0].definition,
+            "CREATE FUNCTION add(a integer, b integer) RETURNS integer\n    LANGUAGE sql IMMUTABLE\n    AS $$ SELECT a + b $$"
+        );
+        assert_eq!(
+            item_name(
+                &functions[0].definition,
+                &ProgItem::Sql(SqlProgItem::CreateFunction)
+            )
+            .as_deref(),
+            Some("add")
+        );
+
+        let tables = extract(SqlProgItem::CreateTable);
+        assert_eq!(tables.len(), 1);
+        assert_eq!(tables[0].name.as_deref(), Some("users"));
+        assert!(tables[0].definition.starts_with("CREATE TABLE users (\n"));
+    }
+
+    #[test]
+    fn test_extract_query_deduplicates_nodes() {
+        let code = "def a():\n    pass\n\ndef b():\n    pass\n";
+        let items = extract_query_from_string(
+            code,
+            &PathBuf::from("a.py"),
+            &ProgItem::Python(PythonProgItem::Function),
+            "(function_definition) @item @definition",
+        )
+        .unwrap();
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].definition, "def a():\n    pass");
+        assert_eq!(items[1].definition, "def b():\n    pass");
+    }
+
+    #[test]
+    fn test_parent_scopes() {
+        let rust_code = "mod db {\n    impl Drop for Connection {\n        fn drop(&mut self) {}\n    }\n    impl Connection {\n        fn new() -> Self { Connection }\n    }\n}\nfn main() {}\n";
+        let items = extract_sexpr_from_string(
+            rust_code,
+            &PathBuf::from("lib.rs"),
+            &ProgItem::Rust(RustProgItem::Function),
+        )
+        .unwrap();
+        let scopes: Vec<Vec<String>> = items.into_iter().map(|item| item.parent_scopes).collect();
+        assert_eq!(
+            scopes,
+            vec![
+                vec!["mod db".to_string(), "impl Drop for Connection".to_string()],
+                vec!["mod db".to_string(), "impl Connection".to_string()],
+                vec![],
+            ]
+        );
+
+        let python_code =
+            "class TestLogin(unittest.TestCase):\n    def test_ok(self):\n        pass\n";
+        let items = extract_sexpr_from_string(
+            python_code,
+            &PathBuf::from("test_login.py"),
+            &ProgItem::Python(PythonProgItem::Function),
+        )
+        .unwrap();
+        assert_eq!(
+            items[0].parent_scopes,
+            vec!["class TestLogin(unittest.TestCase)".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_get_filenames_respects_gitignore() {
+        let dir = tempdir().unwrap();
+        git2::Repository::init(dir.path()).unwrap();
+        fs::write(dir.path().join(".gitignore"), "generated/\n").unwrap();
+        fs::create_dir(dir.path().join("generated")).unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        fs::write(dir.path().join("generated").join("schema.py"), "x = 1\n").unwrap();
+        fs::write(dir.path().join("src").join("main.py"), "x = 1\n").unwrap();
+
+        let files =
+            get_filenames(dir.path(), &["py"], &[], true, true, &PathGlobs::default()).unwrap();
+        assert_eq!(files, vec![dir.path().join("src").join("main.py")]);
+
+        let files =
+            get_filenames(dir.path(), &["py"], &[], false, true, &PathGlobs::default()).unwrap();
+        assert_eq!(
+            files,
+            vec![
+                dir.path().join("generated").join("schema.py"),
+                dir.path().join("src").join("main.py"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_filenames_excluded_dirs() {
+        let dir = tempdir().unwrap();
+        for folder in ["proto", "third_party", "node_modules", "src"] {
+            fs::create_dir(dir.path().join(folder)).unwrap();
+            fs::write(dir.path().join(folder).join("a.js"), "x = 1;\n").unwrap();
+        }
+        // --exclude-dir proto --exclude-dir third_party
+        let mut excluded = ProgLanguage::JavaScript.get_excluded_directories();
+        excluded.extend(["proto", "third_party"]);
+
+        let files = get_filenames(
+            dir.path(),
+            &["js"],
+            &excluded,
+            false,
+            true,
+            &PathGlobs::default(),
+        )
+        .unwrap();
+        assert_eq!(files, vec![dir.path().join("src").join("a.js")]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_get_filenames_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let dir = tempdir().unwrap();
+        let a = dir.path().join("a");
+        let b = dir.path().join("b");
+        fs::create_dir(&a).unwrap();
+        fs::create_dir(&b).unwrap();
+        fs::write(a.join("x.py"), "x = 1\n").unwrap();
+        fs::write(b.join("y.py"), "y = 1\n").unwrap();
+        // a/to_b -> b and b/to_a -> a, a cycle
+        symlink(&b, a.join("to_b")).unwrap();
+        symlink(&a, b.join("to_a")).unwrap();
+
+        let get = |follow_links| {
+            get_filenames(
+                dir.path(),
+                &["py"],
+                &[],
+                false,
+                follow_links,
+                &PathGlobs::default(),
+            )
+            .unwrap()
+        };
+        // terminates, and every file is found once
+        assert_eq!(get(true), vec![a.join("to_b").join("y.py"), a.join("x.py")]);
+        assert_eq!(get(false), vec![a.join("x.py"), b.join("y.py")]);
+    }
+
+    #[test]
+    fn test_file_count_warning() {
+        assert_eq!(file_count_warning(500, 500, "\"/home/me\""), None);
+        let warning = file_count_warning(501, 500, "\"/home/me\"").unwrap();
+        assert!(warning.starts_with("501 matching files found in \"/home/me\""));
+        assert!(warning.contains("--include or --folder"));
+        assert_eq!(file_count_warning(0, 0, "\".\""), None);
+    }
+
+    #[test]
+    fn test_merge_file_lists() {
+        let first = tempdir().unwrap();
+        let second = tempdir().unwrap();
+        fs::create_dir(first.path().join("api")).unwrap();
+        fs::write(first.path().join("a.py"), "x = 1\n").unwrap();
+        fs::write(first.path().join("api").join("b.py"), "x = 1\n").unwrap();
+        fs::write(second.path().join("c.py"), "x = 1\n").unwrap();
+
+        let get = |folder: &Path| {
+            get_filenames(folder, &["py"], &[], false, true, &PathGlobs::default()).unwrap()
+        };
+        // the api folder overlaps with the first one, also when named by another path
+        let files = merge_file_lists(vec![
+            get(first.path()),
+            get(&first.path().join("api").join("..").join("api")),
+            get(second.path()),
+        ]);
+        assert_eq!(
+            files,
+            vec![
+                first.path().join("a.py"),
+                first.path().join("api").join("b.py"),
+                second.path().join("c.py"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_get_filenames_globs() {
+        let dir = tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("src").join("api")).unwrap();
+        fs::create_dir_all(dir.path().join("src").join("cli")).unwrap();
+        for file in [
+            "src/api/users.py",
+            "src/api/users_test.py",
+            "src/cli/main.py",
+            "src/cli/main_test.py",
+        ] {
+            fs::write(dir.path().join(file), "x = 1\n").unwrap();
+        }
+        let files = |include: &[&str], exclude: &[&str]| -> Vec<String> {
+            let to_strings =
+                |globs: &[&str]| globs.iter().map(|g| g.to_string()).collect::<Vec<_>>();
+            let globs = PathGlobs::new(&to_strings(include), &to_strings(exclude)).unwrap();
+            get_filenames(dir.path(), &["py"], &[], false, true, &globs)
+                .unwrap()
+                .iter()
+                .map(|file| {
+                    file.strip_prefix(dir.path())
+                        .unwrap()
+                        .to_string_lossy()
+                        .replace('\\', "/")
+                })
+                .collect()
+        };
+
+        assert_eq!(
+            files(&["src/api/**"], &[]),
+            vec!["src/api/users.py", "src/api/users_test.py"]
+        );
+        assert_eq!(
+            files(&[], &["*_test.py"]),
+            vec!["src/api/users.py", "src/cli/main.py"]
+        );
+        assert_eq!(
+            files(&["src/api/**"], &["*_test.py"]),
+            vec!["src/api/users.py"]
+        );
+        // excludes win over includes
+        assert!(files(&["src/api/**"], &["src/api/**"]).is_empty());
+        assert!(PathGlobs::new(&["src/[".to_string()], &[]).is_err());
+    }
+
+    #[test]
+    fn test_filter_files_by_content() {
+        let dir = tempdir().unwrap();
+        let with_asyncio = dir.path().join("a.py");
+        let without_asyncio = dir.path().join("b.py");
+        fs::write(&with_asyncio, "import asyncio\n").unwrap();
+        fs::write(&without_asyncio, "import os\n").unwrap();
+
+        let files = filter_files_by_content(
+            vec![
+                with_asyncio.clone(),
+                without_asyncio,
+                dir.path().join("missing.py"),
+            ],
+            &Regex::new(r"^import asyncio").unwrap(),
+        );
+        assert_eq!(files, vec![with_asyncio]);
+    }
+
+    #[test]
+    fn test_item_name() {
+        let python = ProgItem::Python(PythonProgItem::Function);
+        assert_eq!(
+            item_name(
+                "    @cache\n    def load(self, key=1):\n        pass\n",
+                &python
+            ),
+            Some("load".to_string())
+        );
+        assert_eq!(item_name("x = f(a=1)\n", &python), None);
+
+        let rust = ProgItem::Rust(RustProgItem::Impl);
+        assert_eq!(
+            item_name("#[derive(Debug)]\npub struct Point { x: i32 }", &rust),
+            Some("Point".to_string())
+        );
+        assert_eq!(
+            item_name("impl Drop for A {\n    fn drop(&mut self) {}\n}", &rust),
+            None
+        );
+    }
+
+    #[test]
+    fn test_structural_divergence() {
+        let item = ProgItem::Python(PythonProgItem::Function);
+        let original = "def total(items):\n    result = 0\n    for item in items:\n        result += item\n    return result\n";
+        let renamed = "def total(values):\n    acc = 0\n    for value in values:\n        acc += value\n    return acc\n";
+        let rewritten = "def total(items):\n    if not items:\n        raise ValueError('empty')\n    with open('log') as f:\n        f.write(str(items))\n    return None\n";
+
+        assert_eq!(
+            structural_divergence(original, original, &item).unwrap(),
+            0.0
+        );
+        assert_eq!(
+            structural_divergence(original, renamed, &item).unwrap(),
+            0.0
+        );
+        assert!(structural_divergence(original, rewritten, &item).unwrap() > 0.5);
+    }
+
+    #[test]
+    fn test_extract_top_level_statements() {
+        let code = r#"import sys
+
+# configuration
+DEBUG = False
+# parse the arguments
+args = sys.argv[1:]
+
+# helper
+def main(args):
+    pass
+
+if __name__ == "__main__":
+    main(args)
+"#;
+        let items = extract_sexpr_from_string(
+            code,
+            &PathBuf::from("script.py"),
+            &ProgItem::Python(PythonProgItem::TopLevel),
+        )
+        .unwrap();
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[0].definition,
+            "DEBUG = False\n# parse the arguments\nargs = sys.argv[1:]"
+        );
+        assert_eq!((items[0].start_pos, items[0].end_pos), (3, 5));
+        assert_eq!(
+            items[1].definition,
+            "if __name__ == \"__main__\":\n    main(args)"
+        );
+        assert_eq!(items[1].complexity, 2);
+    }
+
+    #[test]
+    fn test_extract_doc_tests() {
+        let code = r#"/// Adds one
+///
+/// ```
+/// let x = add_one(1);
+/// assert_eq!(x, 2);
+/// ```
+///
+/// ```text
+/// not rust
+/// ```
+pub fn add_one(x: i32) -> i32 {
+    x + 1
+}
+
+impl Foo {
+    /// ```no_run
+    /// Foo::new().run();
+    /// ```
+    fn run(&self) {}
+}
+"#;
+        let items = extract_sexpr_from_string(
+            code,
+            &PathBuf::from("lib.rs"),
+            &ProgItem::Rust(RustProgItem::DocTest),
+        )
+        .unwrap();
+
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[0].definition,
+            "/// let x = add_one(1);\n/// assert_eq!(x, 2);"
+        );
+        assert_eq!((items[0].start_pos, items[0].end_pos), (3, 4));
+        assert_eq!(items[1].definition, "    /// Foo::new().run();");
+        assert_eq!(
+            &code[items[1].start_byte..items[1].end_byte],
+            items[1].definition
+        );
+    }
+
+    #[test]
+    fn test_extract_class_methods() {
+        let dir = tempdir().unwrap();
+        let file_path = dir.path().join("shapes.py");
+        let code = r#"def outside():
+    pass
+
+class Circle:
+    def __init__(self, radius):
+        self.radius = radius
+
+    def area(self):
+        def square(x):
+            return x * x
+        return 3.14 * square(self.radius)
+"#;
+        fs::write(&file_path, code).unwrap();
+
+        let class =
+            extract_sexpr_from_string(code, &file_path, &ProgItem::Python(PythonProgItem::Class))
+                .unwrap()
+                .remove(0);
+        let methods = extract_class_methods(&class).unwrap();
+
+        assert_eq!(methods.len(), 2);
+        assert!(methods[0].definition.starts_with("    def __init__"));
+        assert!(methods[1].definition.starts_with("    def area"));
+        assert!(methods[1].definition.contains("def square"));
+        assert_eq!(
+            &code[methods[1].start_byte..methods[1].end_byte],
+            methods[1].definition
+        );
+    }
+
+    #[test]
+    fn test_extract_python_methods() {
+        let file_path = PathBuf::from("shapes.py");
+        let code = r#"def outside():
+    pass
+
+class Circle:
+    RADIUS = 1
+
+    def __init__(self, radius):
+        self.radius = radius
+
+    def area(self):
+        def square(x):
+            return x * x
+        return 3.14 * square(self.radius)
+"#;
+        let methods =
+            extract_sexpr_from_string(code, &file_path, &ProgItem::Python(PythonProgItem::Method))
+                .unwrap();
+
+        let python = ProgItem::Python(PythonProgItem::Function);
+        let names: Vec<Option<String>> = methods
+            .iter()
+            .map(|method| item_name(&method.definition, &python))
+            .collect();
+        assert_eq!(
+            names,
+            vec![Some("__init__".to_string()), Some("area".to_string())]
+        );
+        // only the method, not the whole class
+        assert!(methods[0].definition.starts_with("    def __init__"));
+        assert!(!methods[1].definition.contains("class Circle"));
+        assert!(methods[1].definition.contains("def square"));
+        assert_eq!(methods[1].parent_scopes, vec!["class Circle".to_string()]);
+    }
+
+    #[test]
+    fn test_extract_decorated_python_functions() {
+        let file_path = PathBuf::from("jobs.py");
+        let code = r#"@retry(times=3, delay=timedelta(
+    seconds=1))
+# flaky on CI
+@log
+def fetch():
+    pass
+
+class Job:
+    @staticmethod
+    def create():
+        return Job()
+
+    def run(self):
+        pass
+"#;
+        let functions = extract_sexpr_from_string(
+            code,
+            &file_path,
+            &ProgItem::Python(PythonProgItem::Function),
+        )
+        .unwrap();
+        // from the first decorator to the end of the function
+        assert!(functions[0].definition.starts_with("@retry(times=3"));
+        assert!(functions[0].definition.ends_with("def fetch():\n    pass"));
+        assert_eq!(functions[0].start_pos, 0);
+        assert_eq!(functions[0].name.as_deref(), Some("fetch"));
+
+        let methods =
+            extract_sexpr_from_string(code, &file_path, &ProgItem::Python(PythonProgItem::Method))
+                .unwrap();
+        assert_eq!(methods.len(), 2);
+        assert_eq!(
+            methods[0].definition,
+            "    @staticmethod\n    def create():\n        return Job()"
+        );
+        assert_eq!(methods[0].start_pos, 8);
+        assert_eq!(
+            &code[methods[0].start_byte..methods[0].end_byte],
+            methods[0].definition
+        );
+        assert_eq!(methods[1].name.as_deref(), Some("run"));
+    }
+
+    #[test]
+    fn test_extract_all_items_skips_unreadable_files() {
+        let dir = tempdir().unwrap();
+        let valid = dir.path().join("valid.py");
+        let latin1 = dir.path().join("latin1.py");
+        fs::write(&valid, "def a():\n    pass\n").unwrap();
+        fs::write(&latin1, b"def caf\xe9():\n    pass\n").unwrap();
+
+        let items = extract_all_items_from_files(
+            vec![latin1, valid.clone()],
+            ProgItem::Python(PythonProgItem::Function),
+        )
+        .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].filename, valid);
+    }
+
+    #[test]
+    fn test_extract_all_items_matches_sequential_extraction() {
+        let dir = tempdir().unwrap();
+        let files: Vec<PathBuf> = (0..40)
+            .map(|i| {
+                let path = dir.path().join(format!("m{:02}.py", i));
+                let code: String = (0..i % 5)
+                    .map(|j| format!("def f{}_{}():\n    return {}\n\n", i, j, j))
+                    .collect();
+                fs::write(&path, code).unwrap();
+                path
+            })
+            .collect();
+        let item = ProgItem::Python(PythonProgItem::Function);
+        let key = |items: Vec<ItemDef>| -> Vec<(PathBuf, usize, usize, String)> {
+            items
+                .into_iter()
+                .map(|item| {
+                    (
+                        item.filename,
+                        item.start_byte,
+                        item.end_byte,
+                        item.definition,
+                    )
+                })
+                .collect()
+        };
+
+        let sequential: Vec<ItemDef> = files
+            .iter()
+            .flat_map(|file| {
+                let code = fs::read_to_string(file).unwrap();
+                extract_sexpr_from_string(&code, file, &item).unwrap()
+            })
+            .collect();
+        let parallel = extract_all_items_from_files(files, item).unwrap();
+        assert_eq!(parallel.len(), 80);
+        assert_eq!(key(parallel), key(sequential));
+    }
+
+    #[test]
+    fn test_extract_rust_traits_and_impls() {
+        let file_path = PathBuf::from("store.rs");
+        let code = r#"pub trait Store<K: Hash + Eq, V>: Send
+where
+    V: Clone,
+{
+    fn get(&self, key: &K) -> Option<V>;
+}
+
+mod memory {
+    impl<K, V> Store<K, V>
+        for MemoryStore<K, V>
+    where
+        K: Hash + Eq,
+        V: Clone + Send,
+    {
+        fn get(&self, key: &K) -> Option<V> {
+            self.map.get(key).cloned()
+        }
+    }
+}
+"#;
+        let traits =
+            extract_sexpr_from_string(code, &file_path, &ProgItem::Rust(RustProgItem::Trait))
+                .unwrap();
+        assert_eq!(traits.len(), 1);
+        assert_eq!(traits[0].name.as_deref(), Some("Store"));
+        assert_eq!((traits[0].start_pos, traits[0].end_pos), (0, 5));
+        assert!(traits[0]
+            .definition
+            .starts_with("pub trait Store<K: Hash + Eq, V>: Send\nwhere\n    V: Clone,\n{"));
+        assert!(traits[0].definition.ends_with("Option<V>;\n}"));
+
+        let impls =
+            extract_sexpr_from_string(code, &file_path, &ProgItem::Rust(RustProgItem::Impl))
+                .unwrap();
+        assert_eq!(impls.len(), 1);
+        let block = &impls[0];
+        // starts at the line of the `impl` keyword, with its indentation, and keeps the
+        // generics and the where-clause spread over the following lines
+        assert_eq!(block.start_pos, 8);
+        assert_eq!(&code[block.start_byte..block.end_byte], block.definition);
+        assert!(block
+            .definition
+            .starts_with("    impl<K, V> Store<K, V>\n        for MemoryStore<K, V>\n    where\n"));
+        assert!(block
+            .definition
+            .contains("        V: Clone + Send,\n    {\n"));
+        assert!(block.definition.ends_with("cloned()\n        }\n    }"));
+        assert_eq!(block.parent_scopes, vec!["mod memory".to_string()]);
+    }
+
+    #[test]
+    fn test_extract_doc_comments_and_attributes() {
+        let file_path = PathBuf::from("point.rs");
+        let code = r#"use std::fmt;
+
+// not documentation, separated by a blank line
+
+/// A point
+/// in the plane
+#[derive(Debug, Clone)]
+pub struct Point {
+    x: i32,
+}
+
+impl Point {
+    // a regular comment
+    /// The origin
+    #[inline]
+    #[must_use]
+    pub fn origin() -> Self {
+        Point { x: 0 }
+    }
+}
+"#;
+        let structs =
+            extract_sexpr_from_string(code, &file_path, &ProgItem::Rust(RustProgItem::Struct))
+                .unwrap();
+        assert_eq!(
+            structs[0].definition,
+            "/// A point\n/// in the plane\n#[derive(Debug, Clone)]\npub struct Point {\n    x: i32,\n}"
+        );
+        assert_eq!(structs[0].start_pos, 4);
+        assert_eq!(structs[0].name.as_deref(), Some("Point"));
+
+        let functions =
+            extract_sexpr_from_string(code, &file_path, &ProgItem::Rust(RustProgItem::Function))
+                .unwrap();
+        assert!(functions[0].definition.starts_with(
+            "    /// The origin\n    #[inline]\n    #[must_use]\n    pub fn origin()"
+        ));
+        assert_eq!(functions[0].start_pos, 13);
+        assert_eq!(
+            &code[functions[0].start_byte..functions[0].end_byte],
+            functions[0].definition
+        );
+
+        let code = "@app.route('/')\n@cache\ndef index():\n    pass\n";
+        let functions = extract_sexpr_from_string(
+            code,
+            &PathBuf::from("app.py"),
+            &ProgItem::Python(PythonProgItem::Function),
+        )
+        .unwrap();
+        assert_eq!(functions[0].definition, code.trim_end());
+        // the decorators themselves are not extended
+        let decorators = extract_sexpr_from_string(
+            code,
+            &PathBuf::from("app.py"),
+            &ProgItem::Python(PythonProgItem::Decorator),
+        )
+        .unwrap();
+        assert_eq!(decorators[1].definition, "@cache");
+    }
+
+    #[test]
+    fn test_filter_items_by_name() {
+        let python_code = "def test_a():\n    pass\n\ndef helper():\n    pass\n\n@fixture\ndef test_b():\n    pass\n";
+        let items = extract_sexpr_from_string(
+            python_code,
+            &PathBuf::from("test_a.py"),
+            &ProgItem::Python(PythonProgItem::Function),
+        )
+        .unwrap();
+        let names: Vec<Option<&str>> = items.iter().map(|item| item.name.as_deref()).collect();
+        assert_eq!(names, vec![Some("test_a"), Some("helper"), Some("test_b")]);
+
+        let pattern = Regex::new("^test_").unwrap();
+        let names: Vec<String> = filter_items_by_name(items, &pattern)
+            .into_iter()
+            .filter_map(|item| item.name)
+            .collect();
+        assert_eq!(names, vec!["test_a", "test_b"]);
+
+        let rust_code = "fn parse() {}\nimpl A {\n    fn parse_all() {}\n}\nfn print() {}\n";
+        let file_path = PathBuf::from("lib.rs");
+        let items = extract_sexpr_from_string(
+            rust_code,
+            &file_path,
+            &ProgItem::Rust(RustProgItem::Function),
+        )
+        .unwrap();
+        let pattern = Regex::new("^parse").unwrap();
+        let names: Vec<String> = filter_items_by_name(items, &pattern)
+            .into_iter()
+            .filter_map(|item| item.name)
+            .collect();
+        assert_eq!(names, vec!["parse", "parse_all"]);
+
+        // impl blocks have no name and never match
+        let impls =
+            extract_sexpr_from_string(rust_code, &file_path, &ProgItem::Rust(RustProgItem::Impl))
+                .unwrap();
+        assert_eq!(impls[0].name, None);
+        assert!(filter_items_by_name(impls, &Regex::new("").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn test_filter_items_by_lines() {
+        let code =
+            "def a():\n    pass\n\ndef b():\n    x = 1\n    return x\n\ndef c():\n    pass\n";
+        let items = extract_sexpr_from_string(
+            code,
+            &PathBuf::from("a.py"),
+            &ProgItem::Python(PythonProgItem::Function),
+        )
+        .unwrap();
+        let names = |items: Vec<ItemDef>| -> Vec<String> {
+            items.into_iter().filter_map(|item| item.name).collect()
+        };
+
+        // b (lines 4-6) fully inside, a (1-2) and c (8-9) fully outside
+        assert_eq!(
+            names(filter_items_by_lines(items.clone(), Some(3), Some(7))),
+            vec!["b"]
+        );
+        // partial overlaps with a and c
+        assert_eq!(
+            names(filter_items_by_lines(items.clone(), Some(2), Some(8))),
+            vec!["a", "b", "c"]
+        );
+        assert_eq!(
+            names(filter_items_by_lines(items.clone(), Some(6), None)),
+            vec!["b", "c"]
+        );
+        assert_eq!(
+            names(filter_items_by_lines(items.clone(), None, Some(1))),
+            vec!["a"]
+        );
+        assert!(filter_items_by_lines(items, Some(10), Some(20)).is_empty());
+    }
+
+    #[test]
+    fn test_parse_code_checked() {
+        let python = ProgItem::Python(PythonProgItem::Function);
+        assert!(parse_code_checked("def a(x):\n    return x\n", &python, None).is_ok());
+
+        let broken = "def a(x):\n    return x +* 2\n";
+        // the tree is built anyway
+        assert!(parse_code(broken, &python).is_ok());
+        let error = parse_code_checked(broken, &python, None)
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("Syntax error at line 2"), "{}", error);
+        assert!(error.contains('*'), "{}", error);
+
+        // a missing token is inserted without an ERROR node
+        let rust = ProgItem::Rust(RustProgItem::Function);
+        let broken = "fn a() {\n    let x = (1 + 2;\n}\n";
+        let (_, tree) = parse_code(broken, &rust).unwrap();
+        let error = check_syntax(&tree, broken).unwrap_err().to_string();
+        assert!(error.contains("missing `)`"), "{}", error);
+
+        let broken = "def a(x):\n    if x\n        return x\n";
+        let (_, tree) = parse_code(broken, &python).unwrap();
+        assert!(check_syntax(&tree, broken).is_err());
+    }
+
+    #[test]
+    fn test_select_one_file() {
+        let dir = tempdir().unwrap();
+        let item = |name: &str| {
+            let filename = dir.path().join(name);
+            fs::write(&filename, "").unwrap();
+            ItemDef {
+                filename,
+                ..Default::default()
+            }
+        };
+        let items = vec![item("a.py"), item("b.py"), item("a.py"), item("c.py")];
+
+        let (selected, remaining) = select_one_file(items.clone(), None).unwrap();
+        assert_eq!(selected.len(), 2);
+        assert_eq!(remaining, 2);
+
+        let (selected, remaining) = select_one_file(items, Some(&dir.path().join("c.py"))).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].filename, dir.path().join("c.py"));
+        assert_eq!(remaining, 2);
+    }
+
+    #[test]
+    fn test_item_complexity() {
+        let code = r#"
+def simple(a):
+    return a
+
+def branching(items):
+    for item in items:
+        if item > 10:
+            return 1
+        elif item > 5 and item < 7:
+            return 2
+    while True:
+        break
+"#;
+        let items = extract_sexpr_from_string(
+            code,
+            &PathBuf::new(),
+            &ProgItem::Python(PythonProgItem::Function),
+        )
+        .unwrap();
+        assert_eq!(items[0].complexity, 1);
+        // for, if, elif, and, while
+        assert_eq!(items[1].complexity, 6);
+    }
+
     #[test]
     fn test_extract_sexpr_from_string() {
         let code = r#"
@@ -0,0 +1,238 @@
+use anyhow::{bail, Result};
+
+use crate::search::ItemDef;
+
+/// A deterministic change applied to matching items instead of asking the model
+pub trait Transform {
+    /// Name used to enable the transform with `--transform`
+    fn name(&self) -> &str;
+    fn applies(&self, item: &ItemDef) -> bool;
+    fn apply(&self, item: &ItemDef) -> Result<String>;
+}
+
+/// Transforms available to `make_change`. Register your own with `register` when embedding
+/// mechatyper
+#[derive(Default)]
+pub struct TransformRegistry {
+    transforms: Vec<Box<dyn Transform>>,
+}
+
+impl TransformRegistry {
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::default();
+        registry.register(Box::new(TrimTrailingWhitespace));
+        registry.register(Box::new(NormalizeQuotes));
+        registry
+    }
+
+    pub fn register(&mut self, transform: Box<dyn Transform>) {
+        self.transforms.push(transform);
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.transforms
+            .iter()
+            .map(|transform| transform.name())
+            .collect()
+    }
+
+    /// Keeps only the transforms with the given names, in registration order
+    pub fn select(self, names: &[String]) -> Result<Self> {
+        for name in names {
+            if !self.names().contains(&name.as_str()) {
+                bail!(
+                    "Unknown transform {}, available: {}",
+                    name,
+                    self.names().join(", ")
+                );
+            }
+        }
+        Ok(TransformRegistry {
+            transforms: self
+                .transforms
+                .into_iter()
+                .filter(|transform| names.iter().any(|name| name == transform.name()))
+                .collect(),
+        })
+    }
+
+    /// The first registered transform applying to the item
+    pub fn find(&self, item: &ItemDef) -> Option<&dyn Transform> {
+        self.transforms
+            .iter()
+            .find(|transform| transform.applies(item))
+            .map(|transform| transform.as_ref())
+    }
+}
+
+/// Removes trailing spaces and tabs from every line
+pub struct TrimTrailingWhitespace;
+
+impl Transform for TrimTrailingWhitespace {
+    fn name(&self) -> &str {
+        "trim-trailing-whitespace"
+    }
+
+    fn applies(&self, item: &ItemDef) -> bool {
+        item.definition
+            .lines()
+            .any(|line| line.ends_with([' ', '\t']))
+    }
+
+    fn apply(&self, item: &ItemDef) -> Result<String> {
+        let mut code = item
+            .definition
+            .lines()
+            .map(str::trim_end)
+            .collect::<Vec<&str>>()
+            .join("\n");
+        if item.definition.ends_with('\n') {
+            code.push('\n');
+        }
+        Ok(code)
+    }
+}
+
+/// Rewrites single quoted Python strings to double quotes when that needs no escaping
+pub struct NormalizeQuotes;
+
+impl NormalizeQuotes {
+    fn normalize(code: &str) -> String {
+        let chars: Vec<char> = code.chars().collect();
+        let mut result = String::with_capacity(code.len());
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '#' => {
+                    let end = chars[i..]
+                        .iter()
+                        .position(|c| *c == '\n')
+                        .map_or(chars.len(), |offset| i + offset);
+                    result.extend(&chars[i..end]);
+                    i = end;
+                }
+                quote @ ('\'' | '"') => {
+                    // triple quoted strings are kept as they are
+                    if chars[i..].starts_with(&[quote, quote, quote]) {
+                        let mut end = i + 3;
+                        while end < chars.len() && !chars[end..].starts_with(&[quote, quote, quote])
+                        {
+                            end += if chars[end] == '\\' { 2 } else { 1 };
+                        }
+                        let end = (end + 3).min(chars.len());
+                        result.extend(&chars[i..end]);
+                        i = end;
+                        continue;
+                    }
+
+                    let mut end = i + 1;
+                    while end < chars.len() && chars[end] != quote && chars[end] != '\n' {
+                        end += if chars[end] == '\\' { 2 } else { 1 };
+                    }
+                    // an unterminated string, e.g. a quote at the end of the code, is kept
+                    let terminated = end < chars.len() && chars[end] == quote;
+                    let end = (end + 1).min(chars.len());
+                    if quote == '\''
+                        && terminated
+                        && !chars[i + 1..end - 1]
+                            .iter()
+                            .any(|c| *c == '"' || *c == '\\')
+                    {
+                        result.push('"');
+                        result.extend(&chars[i + 1..end - 1]);
+                        result.push('"');
+                    } else {
+                        result.extend(&chars[i..end]);
+                    }
+                    i = end;
+                }
+                c => {
+                    result.push(c);
+                    i += 1;
+                }
+            }
+        }
+        result
+    }
+}
+
+impl Transform for NormalizeQuotes {
+    fn name(&self) -> &str {
+        "normalize-quotes"
+    }
+
+    fn applies(&self, item: &ItemDef) -> bool {
+        item.filename.extension().map_or(false, |ext| ext == "py")
+            && Self::normalize(&item.definition) != item.definition
+    }
+
+    fn apply(&self, item: &ItemDef) -> Result<String> {
+        Ok(Self::normalize(&item.definition))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn item(definition: &str) -> ItemDef {
+        ItemDef {
+            definition: definition.to_string(),
+            filename: PathBuf::from("a.py"),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_trim_trailing_whitespace() {
+        let transform = TrimTrailingWhitespace;
+        let dirty = item("def a():  \n\treturn 1\t\n");
+        assert!(transform.applies(&dirty));
+        assert_eq!(transform.apply(&dirty).unwrap(), "def a():\n\treturn 1\n");
+        assert!(!transform.applies(&item("def a():\n    return 1\n")));
+    }
+
+    #[test]
+    fn test_normalize_quotes() {
+        let transform = NormalizeQuotes;
+        let code = item(
+            "def a():\n    '''Doc'''\n    x = 'a' + 'b\"c' + 'd\\'e'  # 'comment'\n    return f'{x}'\n",
+        );
+        assert!(transform.applies(&code));
+        assert_eq!(
+            transform.apply(&code).unwrap(),
+            "def a():\n    '''Doc'''\n    x = \"a\" + 'b\"c' + 'd\\'e'  # 'comment'\n    return f\"{x}\"\n"
+        );
+        assert!(!transform.applies(&item("x = \"a\"\n")));
+    }
+
+    #[test]
+    fn test_normalize_quotes_unterminated() {
+        // a quote at the end of the code
+        assert_eq!(NormalizeQuotes::normalize("x = '"), "x = '");
+        assert_eq!(NormalizeQuotes::normalize("x = 'abc"), "x = 'abc");
+        assert_eq!(
+            NormalizeQuotes::normalize("x = 'abc\ny = 'd'"),
+            "x = 'abc\ny = \"d\""
+        );
+        assert_eq!(NormalizeQuotes::normalize("x = '\\"), "x = '\\");
+    }
+
+    #[test]
+    fn test_registry_select() {
+        let registry = TransformRegistry::with_builtins()
+            .select(&["normalize-quotes".to_string()])
+            .unwrap();
+        assert_eq!(registry.names(), vec!["normalize-quotes"]);
+        assert_eq!(
+            registry.find(&item("x = 'a'\n")).map(|t| t.name()),
+            Some("normalize-quotes")
+        );
+        assert!(registry.find(&item("x = 1  \n")).is_none());
+        assert!(TransformRegistry::with_builtins()
+            .select(&["unknown".to_string()])
+            .is_err());
+    }
+}
@@ -0,0 +1,375 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use colored::Colorize;
+use openai::Usage;
+
+/// Price in USD per 1000 prompt and completion tokens, matched by model name prefix
+const DEFAULT_PRICES: [(&str, (f64, f64)); 10] = [
+    ("gpt-3.5-turbo-16k", (0.003, 0.004)),
+    ("gpt-3.5-turbo", (0.0015, 0.002)),
+    ("gpt-4o-mini", (0.00015, 0.0006)),
+    ("gpt-4o", (0.005, 0.015)),
+    ("gpt-4-32k", (0.06, 0.12)),
+    ("gpt-4", (0.03, 0.06)),
+    ("claude-3-5-sonnet", (0.003, 0.015)),
+    ("claude-3-5-haiku", (0.0008, 0.004)),
+    ("claude-3-opus", (0.015, 0.075)),
+    ("claude-3-haiku", (0.00025, 0.00125)),
+];
+
+/// Default for `--max-item-tokens`. The item is sent and comes back rewritten, so it has to fit
+/// in less than half of the default model's 16k context along with the prompt
+pub const DEFAULT_MAX_ITEM_TOKENS: usize = 6000;
+
+/// Rough number of tokens in `text`, about 4 characters per token for code and English. Good
+/// enough to tell whether an item fits the context window, not for billing
+pub fn estimate_tokens(text: &str) -> usize {
+    (text.chars().count() + 3) / 4
+}
+
+/// A `--price MODEL=PROMPT,COMPLETION` override, in USD per 1000 tokens
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelPrice {
+    pub model: String,
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+impl FromStr for ModelPrice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || {
+            format!(
+                "Expected MODEL=PROMPT,COMPLETION (USD per 1000 tokens), got {}",
+                s
+            )
+        };
+        let (model, prices) = s.split_once('=').ok_or_else(error)?;
+        let (prompt, completion) = prices.split_once(',').ok_or_else(error)?;
+        let parse = |price: &str| price.trim().parse::<f64>().map_err(|_| error());
+        Ok(ModelPrice {
+            model: model.trim().to_string(),
+            prompt: parse(prompt)?,
+            completion: parse(completion)?,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PriceTable {
+    prices: Vec<(String, (f64, f64))>,
+}
+
+impl Default for PriceTable {
+    fn default() -> Self {
+        PriceTable {
+            prices: DEFAULT_PRICES
+                .iter()
+                .map(|(prefix, price)| (prefix.to_string(), *price))
+                .collect(),
+        }
+    }
+}
+
+impl PriceTable {
+    /// The default prices, with `overrides` taking precedence
+    pub fn with_overrides(overrides: &[ModelPrice]) -> Self {
+        let mut table = PriceTable::default();
+        let overridden = overrides
+            .iter()
+            .map(|price| (price.model.clone(), (price.prompt, price.completion)));
+        table.prices.splice(0..0, overridden);
+        table
+    }
+
+    pub fn price(&self, model: &str) -> Option<(f64, f64)> {
+        self.prices
+            .iter()
+            .find(|(prefix, _)| model.starts_with(prefix.as_str()))
+            .map(|(_, price)| *price)
+    }
+
+    /// Estimated cost in USD, 0 for models without a known price
+    pub fn cost(&self, model: &str, prompt_tokens: u64, completion_tokens: u64) -> f64 {
+        self.price(model)
+            .map(|(prompt_price, completion_price)| {
+                (prompt_tokens as f64 * prompt_price + completion_tokens as f64 * completion_price)
+                    / 1000.0
+            })
+            .unwrap_or(0.0)
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ModelUsage {
+    pub requests: usize,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+/// Tokens used by all the requests of a session, per model, checked against `--budget`
+#[derive(Clone, Debug)]
+pub struct UsageTracker {
+    prices: PriceTable,
+    budget: Option<f64>,
+    models: BTreeMap<String, ModelUsage>,
+}
+
+impl UsageTracker {
+    pub fn new(prices: PriceTable, budget: Option<f64>) -> Self {
+        UsageTracker {
+            prices,
+            budget,
+            models: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the usage of one response (cached responses have none). Fails once the estimated
+    /// cost exceeds the budget
+    pub fn record(&mut self, model: &str, usage: Option<&Usage>) -> Result<()> {
+        if let Some(usage) = usage {
+            let model_usage = self.models.entry(model.to_string()).or_default();
+            model_usage.requests += 1;
+            model_usage.prompt_tokens += usage.prompt_tokens as u64;
+            model_usage.completion_tokens += usage.completion_tokens as u64;
+        }
+        self.check_budget()
+    }
+
+    pub fn check_budget(&self) -> Result<()> {
+        if let Some(budget) = self.budget {
+            let cost = self.estimated_cost();
+            if cost > budget {
+                bail!(
+                    "The estimated cost of ${:.4} exceeds the budget of ${:.2}, stopping. \
+                     The answers received so far are cached, rerun with a higher --budget to \
+                     continue",
+                    cost,
+                    budget
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn prices(&self) -> &PriceTable {
+        &self.prices
+    }
+
+    pub fn total_tokens(&self) -> u64 {
+        self.models
+            .values()
+            .map(|usage| usage.prompt_tokens + usage.completion_tokens)
+            .sum()
+    }
+
+    pub fn estimated_cost(&self) -> f64 {
+        self.models
+            .iter()
+            .map(|(model, usage)| {
+                self.prices
+                    .cost(model, usage.prompt_tokens, usage.completion_tokens)
+            })
+            .sum()
+    }
+
+    pub fn print_summary(&self) {
+        if self.models.is_empty() {
+            return;
+        }
+        println!("{}", "Token usage:".bold());
+        for (model, usage) in &self.models {
+            let cost = match self.prices.price(model) {
+                Some(_) => format!(
+                    "${:.4}",
+                    self.prices
+                        .cost(model, usage.prompt_tokens, usage.completion_tokens)
+                ),
+                None => "unknown price, set it with --price".to_string(),
+            };
+            println!(
+                "  {}: {} requests, {} prompt + {} completion tokens, {}",
+                model, usage.requests, usage.prompt_tokens, usage.completion_tokens, cost
+            );
+        }
+        println!(
+            "  Total: {} tokens, estimated cost ${:.4}",
+            self.total_tokens(),
+            self.estimated_cost()
+        );
+    }
+}
+
+/// Estimated tokens of the requests for the items of a file, see `RunEstimate`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TokenEstimate {
+    pub items: usize,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
+impl TokenEstimate {
+    fn add(&mut self, other: &TokenEstimate) {
+        self.items += other.items;
+        self.prompt_tokens += other.prompt_tokens;
+        self.completion_tokens += other.completion_tokens;
+    }
+}
+
+/// What a run would send, estimated from the prompts without calling the model, per file
+#[derive(Clone, Debug, Default)]
+pub struct RunEstimate {
+    pub files: BTreeMap<PathBuf, TokenEstimate>,
+}
+
+impl RunEstimate {
+    /// Adds an item sent with `prompt`. The answer is the rewritten `code`, about as long
+    pub fn add_item(&mut self, filename: &Path, prompt: &str, code: &str) {
+        let file = self.files.entry(filename.to_path_buf()).or_default();
+        file.items += 1;
+        file.prompt_tokens += estimate_tokens(prompt) as u64;
+        file.completion_tokens += estimate_tokens(code) as u64;
+    }
+
+    pub fn total(&self) -> TokenEstimate {
+        let mut total = TokenEstimate::default();
+        for file in self.files.values() {
+            total.add(file);
+        }
+        total
+    }
+
+    /// One line per file and the total, with the cost for `model`
+    pub fn to_table(&self, model: &str, prices: &PriceTable) -> String {
+        let known_price = prices.price(model).is_some();
+        let row = |name: &str, estimate: &TokenEstimate| {
+            let cost = if known_price {
+                format!(
+                    "${:.4}",
+                    prices.cost(model, estimate.prompt_tokens, estimate.completion_tokens)
+                )
+            } else {
+                "?".to_string()
+            };
+            format!(
+                "{:<40} {:>6} {:>10} {:>10} {:>10}\n",
+                name, estimate.items, estimate.prompt_tokens, estimate.completion_tokens, cost
+            )
+        };
+        let mut table = format!(
+            "{:<40} {:>6} {:>10} {:>10} {:>10}\n",
+            "File", "Items", "Prompt", "Completion", "Cost"
+        );
+        for (filename, estimate) in &self.files {
+            table.push_str(&row(&filename.display().to_string(), estimate));
+        }
+        table.push_str(&row("Total", &self.total()));
+        if !known_price {
+            table.push_str(&format!(
+                "No known price for {}, set it with --price\n",
+                model
+            ));
+        }
+        table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(prompt_tokens: u32, completion_tokens: u32) -> Usage {
+        Usage {
+            prompt_tokens,
+            completion_tokens,
+            total_tokens: prompt_tokens + completion_tokens,
+        }
+    }
+
+    #[test]
+    fn test_estimate_tokens() {
+        assert_eq!(estimate_tokens(""), 0);
+        assert_eq!(estimate_tokens("def"), 1);
+        assert_eq!(estimate_tokens("def "), 1);
+        assert_eq!(estimate_tokens("def a"), 2);
+        // at the default --max-item-tokens boundary
+        let limit = DEFAULT_MAX_ITEM_TOKENS;
+        assert_eq!(estimate_tokens(&"x".repeat(4 * limit)), limit);
+        assert_eq!(estimate_tokens(&"x".repeat(4 * limit + 1)), limit + 1);
+        // characters, not bytes
+        assert_eq!(estimate_tokens("ąęść"), 1);
+    }
+
+    #[test]
+    fn test_price_overrides() {
+        let default = PriceTable::default();
+        assert_eq!(
+            default.price("gpt-4o-mini-2024-07-18"),
+            Some((0.00015, 0.0006))
+        );
+        assert_eq!(default.price("gpt-4-0613"), Some((0.03, 0.06)));
+        assert_eq!(default.price("llama3"), None);
+
+        let price: ModelPrice = "gpt-4o=0.0025,0.01".parse().unwrap();
+        let table = PriceTable::with_overrides(&[price]);
+        assert_eq!(table.price("gpt-4o-2024-08-06"), Some((0.0025, 0.01)));
+        assert_eq!(table.price("gpt-4"), Some((0.03, 0.06)));
+
+        assert!("gpt-4o=0.0025".parse::<ModelPrice>().is_err());
+        assert!("gpt-4o".parse::<ModelPrice>().is_err());
+    }
+
+    #[test]
+    fn test_run_estimate() {
+        let mut estimate = RunEstimate::default();
+        let code = "x".repeat(400);
+        estimate.add_item(Path::new("b.py"), &"p".repeat(800), &code);
+        estimate.add_item(Path::new("a.py"), &"p".repeat(800), &code);
+        estimate.add_item(Path::new("b.py"), &"p".repeat(4000), &code);
+
+        assert_eq!(
+            estimate.files[Path::new("b.py")],
+            TokenEstimate {
+                items: 2,
+                prompt_tokens: 1200,
+                completion_tokens: 200
+            }
+        );
+        let total = estimate.total();
+        assert_eq!(
+            (total.items, total.prompt_tokens, total.completion_tokens),
+            (3, 1400, 300)
+        );
+
+        let table = estimate.to_table("gpt-4", &PriceTable::default());
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 4);
+        // sorted by file, 1400 * 0.03 + 300 * 0.06 per 1000 tokens in total
+        assert!(lines[1].starts_with("a.py"));
+        assert!(lines[3].starts_with("Total") && lines[3].ends_with("$0.0600"));
+
+        let table = estimate.to_table("llama3", &PriceTable::default());
+        assert!(table.contains("No known price for llama3"));
+    }
+
+    #[test]
+    fn test_usage_per_model_and_budget() {
+        let mut tracker = UsageTracker::new(PriceTable::default(), Some(0.1));
+        tracker.record("gpt-4", Some(&usage(1000, 500))).unwrap();
+        tracker.record("gpt-4o", Some(&usage(1000, 0))).unwrap();
+        // cache hits cost nothing
+        tracker.record("gpt-4o", None).unwrap();
+
+        assert_eq!(tracker.models["gpt-4o"].requests, 1);
+        assert_eq!(tracker.total_tokens(), 2500);
+        assert!((tracker.estimated_cost() - 0.065).abs() < 1e-9);
+
+        let err = tracker.record("gpt-4", Some(&usage(2000, 0))).unwrap_err();
+        assert!(err.to_string().contains("exceeds the budget of $0.10"));
+        assert!(tracker.check_budget().is_err());
+    }
+}
@@ -1,10 +1,12 @@
 use std::env;
 use std::io::{stdin, stdout, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use colored::Colorize;
 use openai::set_key;
 
+use crate::llm::Backend;
+
 pub fn find_git_directory(mut path: PathBuf) -> Option<PathBuf> {
     loop {
         if path.join(".git").is_dir() {
@@ -18,9 +20,40 @@ pub fn find_git_directory(mut path: PathBuf) -> Option<PathBuf> {
     }
 }
 
-pub fn load_env_variables() {
-    dotenv::dotenv().expect("Failed to read .env file");
-    set_key(env::var("OPENAI_KEY").expect("OPENAI_KEY not set"));
+/// Returns why scanning `path` is probably a mistake: it is the filesystem root or the home directory
+pub fn risky_scan_root(path: &Path) -> Option<&'static str> {
+    let path = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    if path.parent().is_none() {
+        return Some("the filesystem root");
+    }
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"))?;
+    match Path::new(&home).canonicalize() {
+        Ok(home) if home == path => Some("your home directory"),
+        _ => None,
+    }
+}
+
+/// Asks a yes/no question, anything but "y" or "yes" (including EOF) means no
+pub fn confirm(question: &str) -> anyhow::Result<bool> {
+    let answer = get_user_input(&format!("{} [y/N]", question))?.unwrap_or_default();
+    Ok(matches!(
+        answer.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+/// Loads `.env`. The OpenAI key is only required by the OpenAI backend without Azure, the other
+/// backends read their settings when they are created
+pub fn load_env_variables(backend: Backend, azure: bool) {
+    match backend {
+        Backend::OpenAi if !azure => {
+            dotenv::dotenv().expect("Failed to read .env file");
+            set_key(env::var("OPENAI_KEY").expect("OPENAI_KEY not set"));
+        }
+        _ => {
+            dotenv::dotenv().ok();
+        }
+    }
 }
 
 fn clear_screen() {
@@ -38,10 +71,14 @@ pub fn print_introduction() {
     );
 }
 
-pub fn get_user_input(prompt: &str) -> anyhow::Result<String> {
+/// Reads a line from stdin, returns `None` when the input is closed (Ctrl-D or end of a pipe)
+pub fn get_user_input(prompt: &str) -> anyhow::Result<Option<String>> {
     print!("{}: ", prompt);
     stdout().flush()?;
     let mut user_input = String::new();
-    stdin().read_line(&mut user_input)?;
-    Ok(user_input)
+    if stdin().read_line(&mut user_input)? == 0 {
+        println!();
+        return Ok(None);
+    }
+    Ok(Some(user_input))
 }
@@ -0,0 +1,90 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::{Context, Result};
+use ignore::WalkBuilder;
+
+use crate::search::FileChange;
+
+/// Runs `cargo check` in `root`. Returns the compiler errors when the build fails
+pub fn cargo_check(root: &Path) -> Result<Option<String>> {
+    let output = Command::new("cargo")
+        .args(["check", "--quiet", "--message-format", "short"])
+        .current_dir(root)
+        .output()
+        .context("Cannot run cargo check")?;
+    if output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(
+        String::from_utf8_lossy(&output.stderr).trim().to_string(),
+    ))
+}
+
+/// Runs `cargo check` on a temporary copy of `root` with the changes applied, leaving the files
+/// in `root` untouched. Ignored files such as `target/` are not copied
+pub fn cargo_check_with_changes(
+    root: &Path,
+    file_changes: &[FileChange],
+) -> Result<Option<String>> {
+    let root = fs::canonicalize(root)?;
+    let copy = tempfile::tempdir()?;
+    for entry in WalkBuilder::new(&root).hidden(false).build() {
+        let entry = entry?;
+        let relative = entry.path().strip_prefix(&root)?;
+        if relative.starts_with(".git") {
+            continue;
+        }
+        let target = copy.path().join(relative);
+        if entry.path().is_dir() {
+            fs::create_dir_all(&target)?;
+        } else {
+            fs::copy(entry.path(), &target)
+                .with_context(|| format!("Cannot copy {:?} for cargo check", entry.path()))?;
+        }
+    }
+    for file_change in file_changes {
+        let relative = file_change
+            .path
+            .strip_prefix(&root)
+            .with_context(|| format!("{:?} is outside of {:?}", file_change.path, root))?;
+        fs::write(copy.path().join(relative), &file_change.after)?;
+    }
+    cargo_check(copy.path())
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    #[test]
+    fn test_cargo_check() {
+        let dir = tempdir().unwrap();
+        fs::write(
+            dir.path().join("Cargo.toml"),
+            "[package]\nname = \"verify\"\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+        )
+        .unwrap();
+        fs::create_dir(dir.path().join("src")).unwrap();
+        let lib = dir.path().join("src").join("lib.rs");
+        fs::write(&lib, "pub fn a() -> u32 {\n    1\n}\n").unwrap();
+        assert_eq!(cargo_check(dir.path()).unwrap(), None);
+
+        let broken = FileChange {
+            path: fs::canonicalize(&lib).unwrap(),
+            before: "pub fn a() -> u32 {\n    1\n}\n".to_string(),
+            after: "pub fn a() -> u32 {\n    \"1\"\n}\n".to_string(),
+        };
+        let errors = cargo_check_with_changes(dir.path(), &[broken])
+            .unwrap()
+            .unwrap();
+        assert!(errors.contains("mismatched types"), "{}", errors);
+        assert_eq!(
+            fs::read_to_string(&lib).unwrap(),
+            "pub fn a() -> u32 {\n    1\n}\n"
+        );
+    }
+}
@@ -0,0 +1,91 @@
+#[path = "../src/test_server.rs"]
+mod test_server;
+
+use std::fs;
+use std::process::Command;
+use std::sync::{Arc, Mutex};
+use test_server::Request;
+
+/// An Azure deployment that rate limits the first request and answers the next two, the
+/// detector and the item, with `answer`
+fn serve_azure(answer: &str) -> (String, Arc<Mutex<Vec<Request>>>) {
+    let completion = serde_json::json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "gpt-35-turbo",
+        "choices": [{
+            "index": 0,
+            "finish_reason": "stop",
+            "message": {"role": "assistant", "content": answer},
+        }],
+        "usage": {"prompt_tokens": 10, "completion_tokens": 10, "total_tokens": 20},
+    });
+    test_server::serve_http(vec![
+        (
+            "429 Too Many Requests",
+            r#"{"error": {"code": "429", "message": "Rate limit reached"}}"#.to_string(),
+        ),
+        ("200 OK", completion.to_string()),
+        ("200 OK", completion.to_string()),
+    ])
+}
+
+#[test]
+fn test_json_output_with_retry() {
+    let dir = tempfile::tempdir().unwrap();
+    git2::Repository::init(dir.path()).unwrap();
+    fs::write(
+        dir.path().join("math.py"),
+        "def add(a, b):\n    return a + b\n",
+    )
+    .unwrap();
+    let (url, requests) = serve_azure(
+        "```python\ndef add(a, b):\n    \"\"\"Adds two numbers\"\"\"\n    return a + b\n```",
+    );
+
+    let output = Command::new(env!("CARGO_BIN_EXE_mechatyper"))
+        .current_dir(dir.path())
+        .env("AZURE_OPENAI_KEY", "key")
+        .args([
+            "--azure-endpoint",
+            &url,
+            "--azure-deployment",
+            "gpt-35-turbo",
+        ])
+        .args([
+            "--output",
+            "json",
+            "--no-cache",
+            "--yes",
+            "--retry-delay",
+            "0",
+        ])
+        .args([
+            "run",
+            "--task",
+            "add docstrings",
+            "--item",
+            "Python.Function",
+        ])
+        .args(["--folder", "."])
+        .output()
+        .unwrap();
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stderr.contains("retrying"), "{}", stderr);
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout)
+        .unwrap_or_else(|err| panic!("{}: {}", err, String::from_utf8_lossy(&output.stdout)));
+    assert_eq!(report["summary"]["changed"], 1);
+
+    // the rate limited request is sent again as it was
+    let requests = requests.lock().unwrap();
+    assert_eq!(requests.len(), 3);
+    assert_eq!(requests[0].body, requests[1].body);
+    assert!(requests[0].header("idempotency-key").is_some());
+    assert_eq!(
+        requests[0].header("idempotency-key"),
+        requests[1].header("idempotency-key")
+    );
+}